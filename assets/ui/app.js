@@ -0,0 +1,370 @@
+const REFRESH_INTERVAL = 2000;
+const FEED_LIMIT = 100;
+const USAGE_LIMIT = 1000;
+
+const state = {
+  tab: "feed",
+  traceId: null,
+  spanId: null,
+};
+
+function el(tag, attrs = {}, ...children) {
+  const node = document.createElement(tag);
+  for (const [key, value] of Object.entries(attrs)) {
+    if (key === "class") {
+      node.className = value;
+    } else if (key === "style") {
+      node.setAttribute("style", value);
+    } else if (key.startsWith("on")) {
+      node.addEventListener(key.slice(2), value);
+    } else {
+      node.setAttribute(key, value);
+    }
+  }
+  for (const child of children) {
+    if (child === null || child === undefined) continue;
+    node.append(child instanceof Node ? child : String(child));
+  }
+  return node;
+}
+
+async function getJson(path) {
+  const res = await fetch(path);
+  const data = await res.json();
+  if (!res.ok) {
+    throw new Error(data.error ? data.error.message : res.statusText);
+  }
+  return data;
+}
+
+function formatTime(ms) {
+  return new Date(ms).toLocaleTimeString();
+}
+
+function formatDuration(ms) {
+  return ms >= 1000 ? `${(ms / 1000).toFixed(2)}s` : `${Math.round(ms)}ms`;
+}
+
+function formatCost(cost) {
+  return cost ? `$${cost.toFixed(6)}` : "-";
+}
+
+function spanInfo(span) {
+  const attrs = span.attributes;
+  return {
+    agent: attrs["agent_panel.agent"] || "unknown",
+    model: `${attrs["agent_panel.client"] || ""}:${attrs["gen_ai.request.model"] || ""}`,
+    inputTokens: attrs["gen_ai.usage.input_tokens"] || 0,
+    outputTokens: attrs["gen_ai.usage.output_tokens"] || 0,
+    cost: attrs["agent_panel.usage.cost"] || 0,
+    estimated: !!attrs["agent_panel.usage.estimated"],
+  };
+}
+
+function spanContent(span, name, key) {
+  const event = span.events.find((v) => v.name === name);
+  if (!event || !event.attributes[key]) return null;
+  try {
+    return JSON.parse(event.attributes[key]);
+  } catch (_) {
+    return null;
+  }
+}
+
+// Live feed
+
+async function renderFeed() {
+  const { data } = await getJson(`/v1/spans?limit=${FEED_LIMIT}`);
+  const rows = document.getElementById("feed-rows");
+  rows.replaceChildren(
+    ...data.map((span) => {
+      const info = spanInfo(span);
+      const tokenSuffix = info.estimated ? "~" : "";
+      return el(
+        "tr",
+        { onclick: () => openTrace(span.trace_id, span.span_id) },
+        el("td", {}, formatTime(span.start_time)),
+        el("td", {}, info.agent),
+        el("td", {}, info.model),
+        el("td", { class: "num" }, `${tokenSuffix}${info.inputTokens}`),
+        el("td", { class: "num" }, `${tokenSuffix}${info.outputTokens}`),
+        el("td", { class: "num" }, formatCost(info.cost)),
+        el("td", { class: "num" }, formatDuration(span.duration_ms)),
+        span.error
+          ? el("td", { class: "status-error", title: span.error }, "error")
+          : el("td", { class: "status-ok" }, "ok"),
+      );
+    }),
+  );
+  document.getElementById("feed-empty").style.display = data.length ? "none" : "block";
+}
+
+// Traces
+
+async function renderTraceList() {
+  const { data } = await getJson("/v1/traces");
+  const list = document.getElementById("trace-list");
+  list.replaceChildren(
+    ...data.map((trace) =>
+      el(
+        "li",
+        {
+          class: trace.trace_id === state.traceId ? "selected" : "",
+          onclick: () => openTrace(trace.trace_id),
+        },
+        el("div", { class: trace.error ? "status-error" : "" }, trace.name),
+        el(
+          "div",
+          { class: "meta" },
+          `${formatTime(trace.start_time)} · ${trace.span_count} span(s) · ` +
+            `${formatDuration(trace.duration_ms)} · ${formatCost(trace.cost)}`,
+        ),
+        el("div", { class: "meta" }, trace.agents.join(", ")),
+      ),
+    ),
+  );
+  if (!data.length) {
+    list.replaceChildren(el("li", { class: "empty" }, "No traces yet."));
+  }
+}
+
+async function renderTraceDetail() {
+  const detail = document.getElementById("trace-detail");
+  if (!state.traceId) return;
+  let trace;
+  try {
+    trace = await getJson(`/v1/traces/${state.traceId}`);
+  } catch (err) {
+    detail.replaceChildren(el("p", { class: "empty" }, err.message));
+    return;
+  }
+  const spans = trace.spans;
+  const depths = {};
+  for (const span of spans) {
+    const parent = span.parent_span_id;
+    depths[span.span_id] = parent in depths ? depths[parent] + 1 : 0;
+  }
+  const total = Math.max(trace.duration_ms, 1);
+  const selected = spans.find((v) => v.span_id === state.spanId) || spans[0];
+  const waterfall = el(
+    "div",
+    { class: "waterfall" },
+    ...spans.map((span) => {
+      const offset = ((span.start_time - trace.start_time) / total) * 100;
+      const width = (span.duration_ms / total) * 100;
+      return el(
+        "div",
+        {
+          class: `row ${span === selected ? "selected" : ""}`,
+          onclick: () => {
+            state.spanId = span.span_id;
+            renderTraceDetail();
+          },
+        },
+        el(
+          "div",
+          { class: "label", style: `padding-left: ${depths[span.span_id] * 16}px` },
+          span.name,
+        ),
+        el(
+          "div",
+          { class: "track" },
+          el("div", {
+            class: `bar ${span.error ? "error" : ""}`,
+            style: `left: ${offset}%; width: ${width}%`,
+          }),
+        ),
+        el("div", { class: "duration" }, formatDuration(span.duration_ms)),
+      );
+    }),
+  );
+  detail.replaceChildren(
+    el(
+      "p",
+      {},
+      `Trace ${trace.trace_id} · ${formatDuration(trace.duration_ms)} · ` +
+        `${trace.input_tokens} in / ${trace.output_tokens} out · ${formatCost(trace.cost)}`,
+    ),
+    waterfall,
+    selected ? renderSpan(selected) : null,
+  );
+}
+
+function renderMessage(message) {
+  let content = message.content;
+  if (typeof content !== "string") {
+    content = JSON.stringify(content, null, 2);
+  }
+  const toolCalls = message.tool_calls && message.tool_calls.length
+    ? el("pre", {}, JSON.stringify(message.tool_calls, null, 2))
+    : null;
+  return el(
+    "div",
+    { class: "message" },
+    el("div", { class: "role" }, message.role),
+    content ? el("pre", {}, content) : null,
+    toolCalls,
+  );
+}
+
+function renderSpan(span) {
+  const prompt = spanContent(span, "gen_ai.content.prompt", "gen_ai.prompt") || [];
+  const completion =
+    spanContent(span, "gen_ai.content.completion", "gen_ai.completion") || [];
+  return el(
+    "div",
+    { class: "span-detail" },
+    el("strong", {}, span.name),
+    span.error ? el("pre", { class: "status-error" }, span.error) : null,
+    el("h3", {}, "Prompt"),
+    ...prompt.map(renderMessage),
+    el("h3", {}, "Response"),
+    ...completion.map(renderMessage),
+    el("h3", {}, "Attributes"),
+    el("pre", {}, JSON.stringify(span.attributes, null, 2)),
+    el("h3", {}, "Replay"),
+    renderReplayForm(span),
+  );
+}
+
+function renderReplayForm(span) {
+  const model = el("input", { placeholder: spanInfo(span).model, size: 32 });
+  const output = el("div");
+  const submit = async () => {
+    output.replaceChildren(el("p", { class: "empty" }, "Replaying..."));
+    const body = model.value ? { model: model.value } : {};
+    const res = await fetch(`/v1/traces/${span.span_id}/replay`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    const data = await res.json();
+    if (!res.ok) {
+      output.replaceChildren(el("pre", { class: "status-error" }, data.error.message));
+      return;
+    }
+    const { original, replay, diff } = data;
+    const sign = (v) => (v > 0 ? `+${v}` : String(v));
+    output.replaceChildren(
+      el(
+        "p",
+        {},
+        `${original.model} → ${replay.model} · tokens ${sign(diff.prompt_tokens)} in / ` +
+          `${sign(diff.completion_tokens)} out · latency ${sign(Math.round(diff.latency_ms))}ms`,
+      ),
+      el(
+        "pre",
+        {},
+        ...diff.content.map(({ op, text }) =>
+          el(
+            "div",
+            { class: op === "insert" ? "status-ok" : op === "delete" ? "status-error" : "" },
+            `${op === "insert" ? "+" : op === "delete" ? "-" : " "} ${text}`,
+          ),
+        ),
+      ),
+    );
+  };
+  return el("div", {}, model, " ", el("button", { onclick: submit }, "Replay"), output);
+}
+
+function openTrace(traceId, spanId = null) {
+  state.traceId = traceId;
+  state.spanId = spanId;
+  switchTab("traces");
+}
+
+// Usage
+
+function groupBy(spans, key) {
+  const groups = {};
+  for (const span of spans) {
+    const info = spanInfo(span);
+    const group = (groups[info[key]] ||= { cost: 0, inputTokens: 0, outputTokens: 0 });
+    group.cost += info.cost;
+    group.inputTokens += info.inputTokens;
+    group.outputTokens += info.outputTokens;
+  }
+  return Object.entries(groups);
+}
+
+function renderBars(id, entries, value, format) {
+  const max = Math.max(...entries.map(([, group]) => value(group).total), 0);
+  const container = document.getElementById(id);
+  if (!entries.length || !max) {
+    container.replaceChildren(el("p", { class: "empty" }, "No data."));
+    return;
+  }
+  entries.sort((a, b) => value(b[1]).total - value(a[1]).total);
+  container.replaceChildren(
+    ...entries.map(([name, group]) => {
+      const { total, parts } = value(group);
+      return el(
+        "div",
+        { class: "bar-row" },
+        el("div", { class: "bar-label", title: name }, name),
+        el(
+          "div",
+          { class: "bar-track", style: "display: flex" },
+          ...parts.map(([cls, part]) =>
+            el("div", { class: `bar-fill ${cls}`, style: `width: ${(part / max) * 100}%` }),
+          ),
+        ),
+        el("div", { class: "bar-value" }, format(total)),
+      );
+    }),
+  );
+}
+
+async function renderUsage() {
+  const { data } = await getJson(`/v1/spans?limit=${USAGE_LIMIT}`);
+  const cost = (group) => ({ total: group.cost, parts: [["", group.cost]] });
+  const tokens = (group) => ({
+    total: group.inputTokens + group.outputTokens,
+    parts: [["", group.inputTokens], ["output", group.outputTokens]],
+  });
+  renderBars("chart-cost-agent", groupBy(data, "agent"), cost, formatCost);
+  renderBars("chart-cost-model", groupBy(data, "model"), cost, formatCost);
+  renderBars("chart-tokens-agent", groupBy(data, "agent"), tokens, String);
+  renderBars("chart-tokens-model", groupBy(data, "model"), tokens, String);
+}
+
+// Navigation
+
+function switchTab(tab) {
+  state.tab = tab;
+  for (const button of document.querySelectorAll("nav button")) {
+    button.classList.toggle("active", button.dataset.tab === tab);
+  }
+  for (const section of document.querySelectorAll(".tab")) {
+    section.classList.toggle("active", section.id === `tab-${tab}`);
+  }
+  refresh();
+}
+
+async function refresh() {
+  try {
+    if (state.tab === "feed") {
+      await renderFeed();
+    } else if (state.tab === "traces") {
+      await renderTraceList();
+      await renderTraceDetail();
+    } else if (state.tab === "usage") {
+      await renderUsage();
+    }
+  } catch (err) {
+    console.error(err);
+  }
+}
+
+for (const button of document.querySelectorAll("nav button")) {
+  button.addEventListener("click", () => switchTab(button.dataset.tab));
+}
+
+setInterval(() => {
+  if (document.getElementById("auto-refresh").checked && state.tab !== "traces") {
+    refresh();
+  }
+}, REFRESH_INTERVAL);
+
+refresh();
@@ -0,0 +1,415 @@
+use crate::client::{ModelData, ALL_MODELS};
+use crate::utils::{base64_decode, get_env_name};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::env;
+
+const SECRET_FIELDS: [&str; 4] = ["key", "secret", "token", "password"];
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// The bearer token of the admin API, the dashboard and the trace API, which are
+    /// disabled without one
+    pub token: Option<String>,
+    /// Write the changes made through the admin API back to the config file
+    pub persist: bool,
+}
+
+impl AdminConfig {
+    /// Check the admin token, given as a bearer token or as the password of a basic
+    /// authorization so that browsers can open the dashboard.
+    pub fn check_token(&self, authorization: Option<&str>) -> Result<()> {
+        let token = self
+            .token
+            .clone()
+            .or_else(|| env::var(get_env_name("admin_token")).ok())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("The admin API is disabled, no admin token is configured."))?;
+        let authorization = authorization.unwrap_or_default();
+        let provided = match authorization.strip_prefix("Basic ") {
+            Some(credentials) => base64_decode(credentials)
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .and_then(|v| v.split_once(':').map(|(_, password)| password.to_string()))
+                .unwrap_or_default(),
+            None => authorization
+                .strip_prefix("Bearer ")
+                .unwrap_or_default()
+                .to_string(),
+        };
+        if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+            bail!("Invalid admin token");
+        }
+        Ok(())
+    }
+}
+
+/// The name a client is referred to in model ids, defaulting to its type.
+pub fn client_name(client: &Value) -> &str {
+    client["name"]
+        .as_str()
+        .or_else(|| client["type"].as_str())
+        .unwrap_or_default()
+}
+
+pub fn find_client(document: &Value, name: &str) -> Option<usize> {
+    clients(document)
+        .iter()
+        .position(|client| client_name(client) == name)
+}
+
+pub fn list_clients(document: &Value) -> Vec<Value> {
+    clients(document).iter().map(client_summary).collect()
+}
+
+pub fn client_summary(client: &Value) -> Value {
+    let mut config = client.clone();
+    mask_secrets(&mut config);
+    json!({
+        "name": client_name(client),
+        "type": client["type"],
+        "disabled": client["disabled"].as_bool().unwrap_or_default(),
+        "config": config,
+    })
+}
+
+pub fn add_client(document: &mut Value, client: Value) -> Result<()> {
+    if !client.is_object() || client["type"].as_str().is_none() {
+        bail!("A client needs a 'type'");
+    }
+    let name = client_name(&client);
+    if find_client(document, name).is_some() {
+        bail!("Client '{name}' already exists");
+    }
+    clients_mut(document)?.push(client);
+    Ok(())
+}
+
+/// Merge the patch into the client, `null` fields being removed and masked secrets, e.g.
+/// sent back unchanged from the listing, being ignored.
+pub fn update_client(document: &mut Value, index: usize, patch: &Value) -> Result<()> {
+    let mut patch = patch.clone();
+    let Some(map) = patch.as_object_mut() else {
+        bail!("The patch must be an object");
+    };
+    let client = &mut clients_mut(document)?[index];
+    drop_masked_secrets(map, client);
+    let old_name = client_name(client).to_string();
+    json_patch::merge(client, &patch);
+    let name = client_name(client).to_string();
+    if name != old_name && find_client(document, &name).is_some_and(|v| v != index) {
+        bail!("Client '{name}' already exists");
+    }
+    Ok(())
+}
+
+pub fn remove_client(document: &mut Value, index: usize) -> Result<()> {
+    clients_mut(document)?.remove(index);
+    Ok(())
+}
+
+/// The models of every client, either configured or built in.
+pub fn list_models(document: &Value) -> Vec<Value> {
+    let mut output = vec![];
+    for client in clients(document) {
+        let name = client_name(client);
+        let (source, models) = match client["models"].as_array() {
+            Some(models) if !models.is_empty() => ("config", models.clone()),
+            _ => ("builtin", builtin_models(client)),
+        };
+        for model in models {
+            let mut model = model.clone();
+            let model_name = model["name"].as_str().unwrap_or_default();
+            model["id"] = format!("{name}:{model_name}").into();
+            model["client"] = name.into();
+            model["source"] = source.into();
+            model["disabled"] = model["disabled"].as_bool().unwrap_or_default().into();
+            output.push(model);
+        }
+    }
+    output
+}
+
+/// Locate a model by its id, returning the indexes of its client and of the model when the
+/// client lists it in the config.
+pub fn find_model(document: &Value, id: &str) -> Option<(usize, Option<usize>)> {
+    let (name, model_name) = id.split_once(':')?;
+    let index = find_client(document, name)?;
+    let client = &clients(document)[index];
+    match client["models"].as_array() {
+        Some(models) if !models.is_empty() => models
+            .iter()
+            .position(|v| v["name"].as_str() == Some(model_name))
+            .map(|i| (index, Some(i))),
+        _ => builtin_models(client)
+            .iter()
+            .any(|v| v["name"].as_str() == Some(model_name))
+            .then_some((index, None)),
+    }
+}
+
+pub fn add_model(document: &mut Value, index: usize, mut model: Value) -> Result<()> {
+    if let Some(model) = model.as_object_mut() {
+        model.remove("client");
+    }
+    validate_model(&model)?;
+    let models = client_models_mut(document, index)?;
+    let name = model["name"].as_str().unwrap_or_default();
+    if models.iter().any(|v| v["name"].as_str() == Some(name)) {
+        bail!("Model '{name}' already exists");
+    }
+    models.push(model);
+    Ok(())
+}
+
+/// Merge the patch into the model, built-in models being copied to the config first.
+pub fn update_model(
+    document: &mut Value,
+    index: usize,
+    model_name: &str,
+    patch: &Value,
+) -> Result<()> {
+    if !patch.is_object() {
+        bail!("The patch must be an object");
+    }
+    let models = client_models_mut(document, index)?;
+    let model = models
+        .iter_mut()
+        .find(|v| v["name"].as_str() == Some(model_name))
+        .ok_or_else(|| anyhow!("Model '{model_name}' not found"))?;
+    json_patch::merge(model, patch);
+    validate_model(model)
+}
+
+pub fn remove_model(document: &mut Value, index: usize, model_name: &str) -> Result<()> {
+    let models = client_models_mut(document, index)?;
+    models.retain(|v| v["name"].as_str() != Some(model_name));
+    if models.is_empty() {
+        bail!("Cannot remove the last model of a client, disable the client instead");
+    }
+    Ok(())
+}
+
+fn clients(document: &Value) -> &[Value] {
+    document["clients"]
+        .as_array()
+        .map(|v| v.as_slice())
+        .unwrap_or_default()
+}
+
+fn clients_mut(document: &mut Value) -> Result<&mut Vec<Value>> {
+    if document["clients"].is_null() {
+        document["clients"] = json!([]);
+    }
+    document["clients"]
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("Invalid config, 'clients' must be a list"))
+}
+
+/// The models listed by the client, seeded with its built-in models so that they keep
+/// being served once the client lists models of its own.
+fn client_models_mut(document: &mut Value, index: usize) -> Result<&mut Vec<Value>> {
+    let client = &mut clients_mut(document)?[index];
+    if client["models"].as_array().map(|v| v.is_empty()) != Some(false) {
+        client["models"] = builtin_models(client).into();
+    }
+    client["models"]
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("Invalid config, 'models' must be a list"))
+}
+
+fn builtin_models(client: &Value) -> Vec<Value> {
+    let kind = client["type"].as_str().unwrap_or_default();
+    let name = client["name"].as_str();
+    ALL_MODELS
+        .iter()
+        .find(|v| v.platform == kind || (kind == "openai-compatible" && name == Some(&v.platform)))
+        .map(|v| v.models.iter().map(compact_model).collect())
+        .unwrap_or_default()
+}
+
+/// Serialize the model without the fields left to their defaults.
+fn compact_model(model: &ModelData) -> Value {
+    let mut value = json!(model);
+    if let Some(map) = value.as_object_mut() {
+        map.retain(|_, v| !v.is_null() && *v != Value::Bool(false));
+    }
+    value
+}
+
+fn validate_model(model: &Value) -> Result<()> {
+    let data: ModelData =
+        serde_json::from_value(model.clone()).map_err(|err| anyhow!("Invalid model, {err}"))?;
+    if data.name.is_empty() {
+        bail!("A model needs a 'name'");
+    }
+    Ok(())
+}
+
+fn mask_secrets(value: &mut Value) {
+    if let Some(map) = value.as_object_mut() {
+        mask_map(map);
+    }
+}
+
+fn mask_map(map: &mut Map<String, Value>) {
+    for (key, value) in map.iter_mut() {
+        match value {
+            Value::String(secret) if is_secret_field(key) => *secret = mask_secret(secret),
+            Value::Object(map) => mask_map(map),
+            _ => {}
+        }
+    }
+}
+
+/// Drop the secrets of the patch sent back masked as listed, so that the stored ones stay.
+fn drop_masked_secrets(patch: &mut Map<String, Value>, stored: &Value) {
+    patch.retain(|key, value| match (&*value, &stored[key.as_str()]) {
+        (Value::String(value), Value::String(secret)) if is_secret_field(key) => {
+            *value != mask_secret(secret)
+        }
+        _ => true,
+    });
+    for (key, value) in patch.iter_mut() {
+        if let Value::Object(map) = value {
+            drop_masked_secrets(map, &stored[key.as_str()]);
+        }
+    }
+}
+
+fn is_secret_field(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_FIELDS.iter().any(|v| key.contains(v))
+}
+
+fn mask_secret(secret: &str) -> String {
+    let visible = if secret.chars().count() > 12 {
+        let tail: String = secret.chars().rev().take(4).collect();
+        tail.chars().rev().collect()
+    } else {
+        String::new()
+    };
+    format!("****{visible}")
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_token() {
+        let config = AdminConfig {
+            token: Some("s3cret".into()),
+            persist: false,
+        };
+        assert!(config.check_token(Some("Bearer s3cret")).is_ok());
+        // admin:s3cret
+        assert!(config.check_token(Some("Basic YWRtaW46czNjcmV0")).is_ok());
+        assert!(config.check_token(Some("Bearer wrong")).is_err());
+        assert!(config.check_token(Some("Basic czNjcmV0")).is_err());
+        assert!(config.check_token(None).is_err());
+    }
+
+    #[test]
+    fn test_clients() {
+        let mut document = json!({
+            "clients": [
+                { "type": "openai", "api_key": "sk-1234567890abcdef" },
+                { "type": "mock", "name": "fake", "models": [{ "name": "echo" }] },
+            ]
+        });
+        assert_eq!(find_client(&document, "fake"), Some(1));
+        assert_eq!(
+            list_clients(&document)[0]["config"]["api_key"],
+            json!("****cdef")
+        );
+        assert!(add_client(&mut document, json!({ "type": "openai" })).is_err());
+        update_client(
+            &mut document,
+            0,
+            &json!({ "api_key": "sk-new", "disabled": true }),
+        )
+        .unwrap();
+        assert_eq!(document["clients"][0]["api_key"], json!("sk-new"));
+        assert_eq!(list_clients(&document)[0]["disabled"], json!(true));
+    }
+
+    #[test]
+    fn test_update_masked_secrets() {
+        let mut document = json!({
+            "clients": [{
+                "type": "openai",
+                "api_key": "sk-1234567890abcdef",
+                "extra": { "proxy_token": "short" },
+            }]
+        });
+        // The listed config sent back as is keeps the stored secrets
+        let mut config = list_clients(&document)[0]["config"].clone();
+        assert_eq!(config["extra"]["proxy_token"], json!("****"));
+        config["disabled"] = json!(true);
+        update_client(&mut document, 0, &config).unwrap();
+        assert_eq!(
+            document["clients"][0]["api_key"],
+            json!("sk-1234567890abcdef")
+        );
+        assert_eq!(
+            document["clients"][0]["extra"]["proxy_token"],
+            json!("short")
+        );
+        assert_eq!(document["clients"][0]["disabled"], json!(true));
+
+        update_client(&mut document, 0, &json!({ "api_key": "sk-new" })).unwrap();
+        assert_eq!(document["clients"][0]["api_key"], json!("sk-new"));
+    }
+
+    #[test]
+    fn test_models() {
+        let mut document = json!({
+            "clients": [{ "type": "mock", "models": [{ "name": "echo" }] }]
+        });
+        assert_eq!(find_model(&document, "mock:echo"), Some((0, Some(0))));
+        assert_eq!(find_model(&document, "mock:other"), None);
+        add_model(
+            &mut document,
+            0,
+            json!({ "client": "mock", "name": "priced", "input_price": 1.5 }),
+        )
+        .unwrap();
+        assert!(add_model(
+            &mut document,
+            0,
+            json!({ "name": "bad", "input_price": "x" })
+        )
+        .is_err());
+        update_model(&mut document, 0, "echo", &json!({ "disabled": true })).unwrap();
+        let models = list_models(&document);
+        assert_eq!(models[0]["disabled"], json!(true));
+        assert_eq!(models[1]["id"], json!("mock:priced"));
+        assert!(models[1].get("client").is_some());
+        assert!(document["clients"][0]["models"][1].get("client").is_none());
+    }
+
+    #[test]
+    fn test_builtin_models() {
+        let mut document = json!({ "clients": [{ "type": "openai", "api_key": "sk" }] });
+        let builtin = list_models(&document);
+        assert!(!builtin.is_empty());
+        assert_eq!(builtin[0]["source"], json!("builtin"));
+        let id = builtin[0]["id"].as_str().unwrap().to_string();
+        let (index, position) = find_model(&document, &id).unwrap();
+        assert_eq!(position, None);
+        let name = id.split_once(':').unwrap().1;
+        update_model(&mut document, index, name, &json!({ "input_price": 9 })).unwrap();
+        let models = list_models(&document);
+        assert_eq!(models.len(), builtin.len());
+        assert_eq!(models[0]["source"], json!("config"));
+        assert_eq!(models[0]["input_price"], json!(9));
+    }
+}
@@ -128,7 +128,7 @@ async fn chat_completions(
     builder: RequestBuilder,
     model_category: &ModelCategory,
 ) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
 
@@ -149,7 +149,7 @@ async fn chat_completions_streaming(
     handler: &mut SseHandler,
     model_category: &ModelCategory,
 ) -> Result<()> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     if !status.is_success() {
         let data: Value = res.json().await?;
@@ -172,6 +172,12 @@ async fn chat_completions_streaming(
                         anyhow!("Invalid chunk data: {}", hex_encode(message.payload()))
                     })?;
                     debug!("stream-data: {data}");
+                    // The last chunk of every model carries the metrics of the invocation
+                    let metrics = &data["amazon-bedrock-invocationMetrics"];
+                    handler.usage(
+                        metrics["inputTokenCount"].as_u64(),
+                        metrics["outputTokenCount"].as_u64(),
+                    );
                     match model_category {
                         ModelCategory::Anthropic => {
                             if let Some(typ) = data["type"].as_str() {
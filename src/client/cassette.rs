@@ -0,0 +1,357 @@
+use crate::utils::{base64_decode, base64_encode, hex_encode};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use reqwest::{Body, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    fs::{read_to_string, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+/// The query parameters carrying credentials, left out of the cassette keys
+const AUTH_QUERY_PARAMS: [&str; 6] = ["key", "api_key", "api-key", "access_token", "token", "sig"];
+
+lazy_static! {
+    static ref CASSETTE: RwLock<Option<Cassette>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Forward requests upstream and append every exchange to the cassette
+    Record,
+    /// Serve responses from the cassette without touching the network
+    Replay,
+}
+
+#[derive(Debug)]
+enum Cassette {
+    Record {
+        file: Arc<Mutex<std::fs::File>>,
+    },
+    Replay {
+        path: PathBuf,
+        exchanges: Mutex<IndexMap<String, VecDeque<Exchange>>>,
+    },
+}
+
+/// A recorded upstream HTTP exchange, stored as one JSON line of the cassette.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Exchange {
+    key: String,
+    method: String,
+    path: String,
+    status: u16,
+    headers: IndexMap<String, String>,
+    /// The response body, base64 encoded when it is not valid UTF-8
+    body: String,
+    #[serde(default)]
+    base64: bool,
+}
+
+impl Exchange {
+    fn body_bytes(&self) -> Result<Vec<u8>> {
+        if self.base64 {
+            base64_decode(&self.body).map_err(|err| anyhow!("Invalid cassette body, {err}"))
+        } else {
+            Ok(self.body.as_bytes().to_vec())
+        }
+    }
+}
+
+pub fn init_cassette(mode: CassetteMode, path: &Path) -> Result<()> {
+    let cassette = match mode {
+        CassetteMode::Record => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open cassette at '{}'", path.display()))?;
+            info!("Recording upstream requests to {}", path.display());
+            Cassette::Record {
+                file: Arc::new(Mutex::new(file)),
+            }
+        }
+        CassetteMode::Replay => {
+            let content = read_to_string(path)
+                .with_context(|| format!("Failed to read cassette at '{}'", path.display()))?;
+            let mut exchanges: IndexMap<String, VecDeque<Exchange>> = IndexMap::new();
+            for (i, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let exchange: Exchange = serde_json::from_str(line).with_context(|| {
+                    format!("Invalid exchange at line {} of the cassette", i + 1)
+                })?;
+                exchanges
+                    .entry(exchange.key.clone())
+                    .or_default()
+                    .push_back(exchange);
+            }
+            info!("Replaying upstream requests from {}", path.display());
+            Cassette::Replay {
+                path: path.to_path_buf(),
+                exchanges: Mutex::new(exchanges),
+            }
+        }
+    };
+    *CASSETTE.write() = Some(cassette);
+    Ok(())
+}
+
+/// Send an upstream request, going through the cassette when one is configured.
+///
+/// Requests are matched by the hash of their method, URL without credentials and body.
+/// Identical requests are served in the recorded order, the last exchange being repeated
+/// once exhausted. Requests streaming their body are sent upstream without being recorded,
+/// and fail to replay.
+pub async fn send_request(builder: RequestBuilder) -> Result<Response> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+    if CASSETTE.read().is_none() {
+        return Ok(client.execute(request).await?);
+    }
+    let method = request.method().to_string();
+    let path = request.url().path().to_string();
+    let body = match request.body().map(|v| v.as_bytes()) {
+        Some(Some(body)) => body,
+        // A streamed body, e.g. a multipart upload, cannot be hashed without consuming it
+        Some(None) => {
+            if let Some(Cassette::Replay { .. }) = CASSETTE.read().as_ref() {
+                bail!("Cannot replay {method} {path} from the cassette, it streams its body");
+            }
+            warn!("Not recording {method} {path} in the cassette, it streams its body");
+            return Ok(client.execute(request).await?);
+        }
+        None => &[],
+    };
+    let key = cassette_key(&method, request.url(), body);
+
+    let record_file = match CASSETTE.read().as_ref() {
+        None => None,
+        Some(Cassette::Replay {
+            path: cassette_path,
+            exchanges,
+        }) => {
+            let mut exchanges = exchanges.lock();
+            let exchange = match exchanges.get_mut(&key) {
+                Some(queue) if queue.len() > 1 => queue.pop_front(),
+                Some(queue) => queue.front().cloned(),
+                None => None,
+            };
+            let Some(exchange) = exchange else {
+                error!("Cassette miss: {method} {path} ({key})");
+                bail!(
+                    "No recorded response for {method} {path} in the cassette '{}' (key: {key})",
+                    cassette_path.display()
+                );
+            };
+            let mut res = http::Response::builder().status(exchange.status);
+            for (name, value) in &exchange.headers {
+                res = res.header(name, value);
+            }
+            let res = res.body(Body::from(exchange.body_bytes()?))?;
+            return Ok(Response::from(res));
+        }
+        Some(Cassette::Record { file }) => Some(file.clone()),
+    };
+    let Some(record_file) = record_file else {
+        return Ok(client.execute(request).await?);
+    };
+
+    let res = client.execute(request).await?;
+    let status = res.status().as_u16();
+    let headers: IndexMap<String, String> = res
+        .headers()
+        .iter()
+        .filter(|(name, _)| *name != http::header::SET_COOKIE)
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in &headers {
+        builder = builder.header(name, value);
+    }
+    let recorder = RecordingStream {
+        inner: Box::pin(res.bytes_stream()),
+        buffer: vec![],
+        exchange: Some(Exchange {
+            key,
+            method,
+            path,
+            status,
+            headers,
+            body: String::new(),
+            base64: false,
+        }),
+        file: record_file,
+    };
+    let res = builder.body(Body::wrap_stream(recorder))?;
+    Ok(Response::from(res))
+}
+
+fn cassette_key(method: &str, url: &reqwest::Url, body: &[u8]) -> String {
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !AUTH_QUERY_PARAMS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(url.host_str().unwrap_or_default().as_bytes());
+    if let Some(port) = url.port() {
+        hasher.update(format!(":{port}").as_bytes());
+    }
+    hasher.update(url.path().as_bytes());
+    for (name, value) in query {
+        hasher.update(format!("\n{name}={value}").as_bytes());
+    }
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex_encode(&hasher.finalize())
+}
+
+/// Passes the response body through while keeping a copy of it, the exchange is
+/// written to the cassette once the body has been consumed or dropped.
+struct RecordingStream {
+    inner: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send + Sync>>,
+    buffer: Vec<u8>,
+    exchange: Option<Exchange>,
+    file: Arc<Mutex<std::fs::File>>,
+}
+
+impl RecordingStream {
+    fn save(&mut self) {
+        let Some(mut exchange) = self.exchange.take() else {
+            return;
+        };
+        let buffer = std::mem::take(&mut self.buffer);
+        match String::from_utf8(buffer) {
+            Ok(body) => exchange.body = body,
+            Err(err) => {
+                exchange.body = base64_encode(err.as_bytes());
+                exchange.base64 = true;
+            }
+        }
+        let ret = serde_json::to_string(&exchange)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.file.lock(), "{line}")?));
+        if let Err(err) = ret {
+            warn!(
+                "Failed to record {} {}, {err}",
+                exchange.method, exchange.path
+            );
+        }
+    }
+}
+
+impl Stream for RecordingStream {
+    type Item = reqwest::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.poll_next_unpin(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => self.buffer.extend_from_slice(chunk),
+            Poll::Ready(None) => self.save(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for RecordingStream {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cassette_key() {
+        let url = |v: &str| reqwest::Url::parse(v).unwrap();
+        let chat = url("https://api.openai.com/v1/chat/completions");
+        let key = cassette_key("POST", &chat, br#"{"model":"gpt-4o"}"#);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cassette_key("POST", &chat, br#"{"model":"gpt-4o"}"#));
+        assert_ne!(key, cassette_key("POST", &chat, br#"{"model":"gpt-4"}"#));
+        assert_ne!(
+            key,
+            cassette_key(
+                "POST",
+                &url("https://api.groq.com/v1/chat/completions"),
+                br#"{"model":"gpt-4o"}"#
+            )
+        );
+
+        let gemini = "https://generativelanguage.googleapis.com/v1beta/models/gemini-pro:streamGenerateContent";
+        assert_eq!(
+            cassette_key("POST", &url(&format!("{gemini}?alt=sse&key=a")), b""),
+            cassette_key("POST", &url(&format!("{gemini}?alt=sse&key=b")), b"")
+        );
+        assert_ne!(
+            cassette_key("POST", &url(&format!("{gemini}?alt=sse")), b""),
+            cassette_key("POST", &url(gemini), b"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/echo", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let res = "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello";
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        let path =
+            std::env::temp_dir().join(format!("agent-panel-{}.cassette", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let client = reqwest::Client::new();
+        let request = || client.post(format!("{url}?key=secret")).body("hi");
+
+        init_cassette(CassetteMode::Record, &path).unwrap();
+        let res = send_request(request()).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "hello");
+        // A streamed body goes upstream without being recorded
+        let streamed = || {
+            let stream = futures_util::stream::iter([Ok::<_, std::io::Error>("hi")]);
+            client.post(&url).body(Body::wrap_stream(stream))
+        };
+        let res = send_request(streamed()).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "hello");
+        server.await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        // The server is gone, the response can only come from the cassette
+        init_cassette(CassetteMode::Replay, &path).unwrap();
+        let res = send_request(client.post(format!("{url}?key=other")).body("hi"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "text/plain");
+        assert_eq!(res.text().await.unwrap(), "hello");
+        assert!(send_request(client.post(&url).body("bye")).await.is_err());
+        assert!(send_request(streamed()).await.is_err());
+
+        *CASSETTE.write() = None;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
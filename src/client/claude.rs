@@ -6,6 +6,7 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 
 const API_BASE: &str = "https://api.anthropic.com/v1/messages";
+const MODELS_API_URL: &str = "https://api.anthropic.com/v1/models";
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClaudeConfig {
@@ -47,16 +48,26 @@ impl ClaudeClient {
 
         Ok(builder)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        Ok(client
+            .get(MODELS_API_URL)
+            .header("anthropic-version", "2023-06-01")
+            .header("x-api-key", api_key))
+    }
 }
 
 impl_client_trait!(
     ClaudeClient,
     claude_chat_completions,
-    claude_chat_completions_streaming
+    claude_chat_completions_streaming;
+    models_builder
 );
 
 pub async fn claude_chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -78,6 +89,16 @@ pub async fn claude_chat_completions_streaming(
         debug!("stream-data: {data}");
         if let Some(typ) = data["type"].as_str() {
             match typ {
+                "message_start" => {
+                    let usage = &data["message"]["usage"];
+                    handler.usage(
+                        usage["input_tokens"].as_u64(),
+                        usage["output_tokens"].as_u64(),
+                    );
+                }
+                "message_delta" => {
+                    handler.usage(None, data["usage"]["output_tokens"].as_u64());
+                }
                 "content_block_start" => {
                     if let (Some("tool_use"), Some(name), Some(id)) = (
                         data["content_block"]["type"].as_str(),
@@ -164,7 +185,7 @@ pub fn claude_build_chat_completions_body(
                                 json!({"type": "text", "text": text})
                             }
                             MessageContentPart::ImageUrl {
-                                image_url: ImageUrl { url },
+                                image_url: ImageUrl { url, .. },
                             } => {
                                 if let Some((mime_type, data)) = url
                                     .strip_prefix("data:")
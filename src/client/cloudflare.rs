@@ -58,7 +58,7 @@ impl_client_trait!(
 );
 
 async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -7,6 +7,8 @@ use serde_json::{json, Value};
 
 const CHAT_COMPLETIONS_API_URL: &str = "https://api.cohere.ai/v1/chat";
 const EMBEDDINGS_API_URL: &str = "https://api.cohere.ai/v1/embed";
+const RERANK_API_URL: &str = "https://api.cohere.ai/v1/rerank";
+const MODELS_API_URL: &str = "https://api.cohere.ai/v1/models";
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct CohereConfig {
@@ -69,17 +71,39 @@ impl CohereClient {
 
         Ok(builder)
     }
+
+    fn rerank_builder(&self, client: &ReqwestClient, data: RerankData) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let body = cohere_build_rerank_body(data, &self.model);
+
+        let url = RERANK_API_URL;
+
+        debug!("Cohere Rerank Request: {url} {body}");
+
+        let builder = client.post(url).bearer_auth(api_key).json(&body);
+
+        Ok(builder)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        Ok(client.get(MODELS_API_URL).bearer_auth(api_key))
+    }
 }
 
 impl_client_trait!(
     CohereClient,
     chat_completions,
     chat_completions_streaming,
-    embeddings
+    embeddings,
+    cohere_rerank;
+    models_builder
 );
 
 async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -94,7 +118,7 @@ async fn chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     if !status.is_success() {
         let data: Value = res.json().await?;
@@ -107,6 +131,12 @@ async fn chat_completions_streaming(
                 if let Some(text) = data["text"].as_str() {
                     handler.text(text)?;
                 }
+            } else if let Some("stream-end") = data["event_type"].as_str() {
+                let billed_units = &data["response"]["meta"]["billed_units"];
+                handler.usage(
+                    billed_units["input_tokens"].as_u64(),
+                    billed_units["output_tokens"].as_u64(),
+                );
             } else if let Some("tool-calls-generation") = data["event_type"].as_str() {
                 if let Some(tool_calls) = data["tool_calls"].as_array() {
                     for call in tool_calls {
@@ -130,7 +160,7 @@ async fn chat_completions_streaming(
 }
 
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -146,6 +176,41 @@ struct EmbeddingsResBody {
     embeddings: Vec<Vec<f32>>,
 }
 
+/// Send a rerank request in the format of Cohere's API, which self-hosted rerank servers
+/// also speak.
+pub async fn cohere_rerank(builder: RequestBuilder) -> Result<RerankOutput> {
+    let res = send_request(builder).await?;
+    let status = res.status();
+    let data: Value = res.json().await?;
+    if !status.is_success() {
+        catch_error(&data, status.as_u16())?;
+    }
+    let res_body: RerankResBody = serde_json::from_value(data).context("Invalid request data")?;
+    Ok(res_body.results)
+}
+
+pub fn cohere_build_rerank_body(data: RerankData, model: &Model) -> Value {
+    let RerankData {
+        query,
+        documents,
+        top_n,
+    } = data;
+    let mut body = json!({
+        "model": model.name(),
+        "query": query,
+        "documents": documents,
+    });
+    if let Some(top_n) = top_n {
+        body["top_n"] = top_n.into();
+    }
+    body
+}
+
+#[derive(Deserialize)]
+struct RerankResBody {
+    results: Vec<RerankResult>,
+}
+
 fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Result<Value> {
     let ChatCompletionsData {
         mut messages,
@@ -179,7 +244,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
                         .filter_map(|item| match item {
                             MessageContentPart::Text { text } => Some(text),
                             MessageContentPart::ImageUrl {
-                                image_url: ImageUrl { url },
+                                image_url: ImageUrl { url, .. },
                             } => {
                                 image_urls.push(url.clone());
                                 None
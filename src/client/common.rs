@@ -15,7 +15,7 @@ use fancy_regex::Regex;
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{env, future::Future, time::Duration};
 
@@ -61,6 +61,22 @@ macro_rules! register_client {
         }
 
 
+        impl ClientConfig {
+            pub fn name(&self) -> &str {
+                match self {
+                    $(ClientConfig::$config(c) => $client::name(c),)+
+                    ClientConfig::Unknown => "unknown",
+                }
+            }
+
+            pub fn kind(&self) -> &str {
+                match self {
+                    $(ClientConfig::$config(_) => $client::NAME,)+
+                    ClientConfig::Unknown => "unknown",
+                }
+            }
+        }
+
         $(
             #[derive(Debug)]
             pub struct $client {
@@ -137,26 +153,28 @@ macro_rules! register_client {
             anyhow::bail!("Unknown client '{}'", client)
         }
 
-        static mut ALL_CLIENT_MODELS: Option<Vec<$crate::client::Model>> = None;
-
-        pub fn list_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
-            if unsafe { ALL_CLIENT_MODELS.is_none() } {
-                let models: Vec<_> = config
-                    .clients
-                    .iter()
-                    .flat_map(|v| match v {
-                        $(ClientConfig::$config(c) => $client::list_models(c),)+
-                        ClientConfig::Unknown => vec![],
-                    })
-                    .collect();
-                unsafe { ALL_CLIENT_MODELS = Some(models) };
-            }
-            unsafe { ALL_CLIENT_MODELS.as_ref().unwrap().iter().collect() }
+        pub fn list_models(config: &$crate::config::Config) -> Vec<$crate::client::Model> {
+            config
+                .clients
+                .iter()
+                .flat_map(|v| match v {
+                    $(ClientConfig::$config(c) => $client::list_models(c),)+
+                    ClientConfig::Unknown => vec![],
+                })
+                .collect()
         }
 
-        pub fn list_chat_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
+        pub fn list_chat_models(config: &$crate::config::Config) -> Vec<$crate::client::Model> {
             list_models(config).into_iter().filter(|v| v.mode() == "chat").collect()
         }
+
+        pub fn list_embedding_models(config: &$crate::config::Config) -> Vec<$crate::client::Model> {
+            list_models(config).into_iter().filter(|v| v.mode() == "embedding").collect()
+        }
+
+        pub fn list_rerank_models(config: &$crate::config::Config) -> Vec<$crate::client::Model> {
+            list_models(config).into_iter().filter(|v| v.mode() == "rerank").collect()
+        }
     };
 }
 
@@ -179,6 +197,10 @@ macro_rules! client_common_fns {
             Self::name(&self.config)
         }
 
+        fn kind(&self) -> &str {
+            Self::NAME
+        }
+
         fn model(&self) -> &Model {
             &self.model
         }
@@ -191,11 +213,54 @@ macro_rules! client_common_fns {
 
 #[macro_export]
 macro_rules! impl_client_trait {
-    ($client:ident, $chat_completions:path, $chat_completions_streaming:path) => {
+    ($client:ident, $chat_completions:path, $chat_completions_streaming:path $(; $list_models:ident)?) => {
+        #[async_trait::async_trait]
+        impl $crate::client::Client for $crate::client::$client {
+            client_common_fns!();
+
+            $(
+                fn list_models_builder(
+                    &self,
+                    client: &reqwest::Client,
+                ) -> Option<anyhow::Result<reqwest::RequestBuilder>> {
+                    Some(self.$list_models(client))
+                }
+            )?
+
+            async fn chat_completions_inner(
+                &self,
+                client: &reqwest::Client,
+                data: $crate::client::ChatCompletionsData,
+            ) -> anyhow::Result<$crate::client::ChatCompletionsOutput> {
+                let builder = self.chat_completions_builder(client, data)?;
+                $chat_completions(builder).await
+            }
+
+            async fn chat_completions_streaming_inner(
+                &self,
+                client: &reqwest::Client,
+                handler: &mut $crate::client::SseHandler,
+                data: $crate::client::ChatCompletionsData,
+            ) -> Result<()> {
+                let builder = self.chat_completions_builder(client, data)?;
+                $chat_completions_streaming(builder, handler).await
+            }
+        }
+    };
+    ($client:ident, $chat_completions:path, $chat_completions_streaming:path, $embeddings:path $(; $list_models:ident)?) => {
         #[async_trait::async_trait]
         impl $crate::client::Client for $crate::client::$client {
             client_common_fns!();
 
+            $(
+                fn list_models_builder(
+                    &self,
+                    client: &reqwest::Client,
+                ) -> Option<anyhow::Result<reqwest::RequestBuilder>> {
+                    Some(self.$list_models(client))
+                }
+            )?
+
             async fn chat_completions_inner(
                 &self,
                 client: &reqwest::Client,
@@ -214,13 +279,31 @@ macro_rules! impl_client_trait {
                 let builder = self.chat_completions_builder(client, data)?;
                 $chat_completions_streaming(builder, handler).await
             }
+
+            async fn embeddings_inner(
+                &self,
+                client: &ReqwestClient,
+                data: EmbeddingsData,
+            ) -> Result<Vec<Vec<f32>>> {
+                let builder = self.embeddings_builder(client, data)?;
+                $embeddings(builder).await
+            }
         }
     };
-    ($client:ident, $chat_completions:path, $chat_completions_streaming:path, $embeddings:path) => {
+    ($client:ident, $chat_completions:path, $chat_completions_streaming:path, $embeddings:path, $rerank:path $(; $list_models:ident)?) => {
         #[async_trait::async_trait]
         impl $crate::client::Client for $crate::client::$client {
             client_common_fns!();
 
+            $(
+                fn list_models_builder(
+                    &self,
+                    client: &reqwest::Client,
+                ) -> Option<anyhow::Result<reqwest::RequestBuilder>> {
+                    Some(self.$list_models(client))
+                }
+            )?
+
             async fn chat_completions_inner(
                 &self,
                 client: &reqwest::Client,
@@ -248,6 +331,15 @@ macro_rules! impl_client_trait {
                 let builder = self.embeddings_builder(client, data)?;
                 $embeddings(builder).await
             }
+
+            async fn rerank_inner(
+                &self,
+                client: &ReqwestClient,
+                data: RerankData,
+            ) -> Result<RerankOutput> {
+                let builder = self.rerank_builder(client, data)?;
+                $rerank(builder).await
+            }
         }
     };
 }
@@ -288,6 +380,8 @@ pub trait Client: Sync + Send {
 
     fn name(&self) -> &str;
 
+    fn kind(&self) -> &str;
+
     fn model(&self) -> &Model;
 
     fn model_mut(&mut self) -> &mut Model;
@@ -344,6 +438,26 @@ pub trait Client: Sync + Send {
             .with_context(|| "Failed to get embeddings")
     }
 
+    async fn rerank(&self, data: RerankData) -> Result<RerankOutput> {
+        let client = self.build_client()?;
+        let top_n = data.top_n;
+        let mut output = self
+            .rerank_inner(&client, data)
+            .await
+            .with_context(|| "Failed to rerank documents")?;
+        output.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+        if let Some(top_n) = top_n {
+            output.truncate(top_n);
+        }
+        Ok(output)
+    }
+
+    /// The request listing the models of the provider, probing it without spending tokens,
+    /// `None` for the providers without one.
+    fn list_models_builder(&self, _client: &ReqwestClient) -> Option<Result<RequestBuilder>> {
+        None
+    }
+
     fn patch_chat_completions_body(&self, body: &mut Value) {
         let model_name = self.model().name();
         if let Some(patch_data) = select_model_patch(self.patches_config(), model_name) {
@@ -373,6 +487,14 @@ pub trait Client: Sync + Send {
     ) -> Result<Vec<Vec<f32>>> {
         bail!("No embeddings api")
     }
+
+    async fn rerank_inner(
+        &self,
+        _client: &ReqwestClient,
+        _data: RerankData,
+    ) -> Result<RerankOutput> {
+        bail!("No rerank api")
+    }
 }
 
 impl Default for ClientConfig {
@@ -411,7 +533,7 @@ pub fn select_model_patch<'a>(
     None
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChatCompletionsData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
@@ -452,6 +574,32 @@ impl EmbeddingsData {
 
 pub type EmbeddingsOutput = Vec<Vec<f32>>;
 
+#[derive(Debug)]
+pub struct RerankData {
+    pub query: String,
+    pub documents: Vec<String>,
+    pub top_n: Option<usize>,
+}
+
+impl RerankData {
+    pub fn new(query: String, documents: Vec<String>, top_n: Option<usize>) -> Self {
+        Self {
+            query,
+            documents,
+            top_n,
+        }
+    }
+}
+
+/// The relevance of a document to the query, by its position in the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResult {
+    pub index: usize,
+    pub relevance_score: f64,
+}
+
+pub type RerankOutput = Vec<RerankResult>;
+
 pub type PromptAction<'a> = (&'a str, &'a str, bool, PromptKind);
 
 pub fn create_config(prompts: &[PromptAction], client: &str) -> Result<(String, Value)> {
@@ -101,7 +101,7 @@ impl Client for ErnieClient {
 }
 
 async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let data: Value = builder.send().await?.json().await?;
+    let data: Value = send_request(builder).await?.json().await?;
     maybe_catch_error(&data)?;
     debug!("non-stream-data: {data}");
     extract_chat_completions_text(&data)
@@ -117,6 +117,10 @@ async fn chat_completions_streaming(
         if let Some(text) = data["result"].as_str() {
             handler.text(text)?;
         }
+        handler.usage(
+            data["usage"]["prompt_tokens"].as_u64(),
+            data["usage"]["completion_tokens"].as_u64(),
+        );
         Ok(false)
     };
 
@@ -175,7 +179,7 @@ async fn fetch_access_token(
     secret_key: &str,
 ) -> Result<String> {
     let url = format!("{ACCESS_TOKEN_URL}?grant_type=client_credentials&client_id={api_key}&client_secret={secret_key}");
-    let value: Value = client.get(&url).send().await?.json().await?;
+    let value: Value = send_request(client.get(&url)).await?.json().await?;
     let result = value["access_token"].as_str().ok_or_else(|| {
         if let Some(err_msg) = value["error_description"].as_str() {
             anyhow!("{err_msg}")
@@ -77,17 +77,26 @@ impl GeminiClient {
 
         Ok(builder)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let url = format!("{}?key={}", API_BASE.trim_end_matches('/'), api_key);
+
+        Ok(client.get(url))
+    }
 }
 
 impl_client_trait!(
     GeminiClient,
     gemini_chat_completions,
     gemini_chat_completions_streaming,
-    gemini_embeddings
+    gemini_embeddings;
+    models_builder
 );
 
 async fn gemini_embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
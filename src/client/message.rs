@@ -119,6 +119,9 @@ pub enum MessageContentPart {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageUrl {
     pub url: String,
+    /// `low`, `high` or `auto`, the resolution OpenAI models see the image at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
 
 pub fn patch_system_message(messages: &mut Vec<Message>) {
@@ -0,0 +1,299 @@
+use super::*;
+
+use crate::utils::tokenize;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use indexmap::IndexMap;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const EMBEDDING_DIMENSIONS: usize = 256;
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct MockConfig {
+    pub name: Option<String>,
+    /// Delay between streamed tokens, in milliseconds
+    pub token_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub models: Vec<ModelData>,
+    /// Scripted responses keyed by model name, models without one echo the last user message
+    #[serde(default)]
+    pub responses: IndexMap<String, MockResponse>,
+    pub patches: Option<ModelPatches>,
+    pub extra: Option<ExtraConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MockResponse {
+    #[serde(flatten)]
+    pub reply: MockReply,
+    /// Overrides the client's `token_delay_ms`
+    pub token_delay_ms: Option<u64>,
+    /// The token usage reported with the reply, which is estimated otherwise
+    pub usage: Option<MockUsage>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MockUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MockReply {
+    Text {
+        text: String,
+    },
+    Echo,
+    ToolCalls {
+        #[serde(default)]
+        text: String,
+        tool_calls: Vec<ToolCall>,
+    },
+    Error {
+        message: String,
+        /// Text streamed before the error is raised
+        #[serde(default)]
+        partial: String,
+    },
+}
+
+impl MockClient {
+    pub const PROMPTS: [PromptAction<'static>; 0] = [];
+
+    fn response(&self) -> Option<&MockResponse> {
+        self.config.responses.get(self.model.name())
+    }
+
+    fn token_delay(&self) -> Option<Duration> {
+        self.response()
+            .and_then(|v| v.token_delay_ms)
+            .or(self.config.token_delay_ms)
+            .filter(|v| *v > 0)
+            .map(Duration::from_millis)
+    }
+
+    fn usage(&self) -> (Option<u64>, Option<u64>) {
+        match self.response().and_then(|v| v.usage) {
+            Some(usage) => (Some(usage.input_tokens), Some(usage.output_tokens)),
+            None => (None, None),
+        }
+    }
+
+    fn reply(&self, data: &ChatCompletionsData) -> MockReply {
+        match self.response() {
+            Some(response) => response.reply.clone(),
+            None => MockReply::Echo,
+        }
+        .resolve(data)
+    }
+}
+
+impl MockReply {
+    fn resolve(self, data: &ChatCompletionsData) -> Self {
+        match self {
+            MockReply::Echo => {
+                let text = data
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|v| v.role.is_user())
+                    .map(|v| v.content.to_text())
+                    .unwrap_or_default();
+                MockReply::Text { text }
+            }
+            MockReply::ToolCalls { text, tool_calls } => {
+                // Answer with the results sent back, so that tool loops come to an end.
+                if let Some(MessageContent::ToolResults((results, _))) =
+                    data.messages.last().map(|v| &v.content)
+                {
+                    let text = results
+                        .iter()
+                        .map(|v| v.output.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    return MockReply::Text { text };
+                }
+                let tool_calls = tool_calls
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, mut call)| {
+                        call.id.get_or_insert_with(|| format!("call_mock_{i}"));
+                        call
+                    })
+                    .collect();
+                MockReply::ToolCalls { text, tool_calls }
+            }
+            reply => reply,
+        }
+    }
+}
+
+#[async_trait]
+impl Client for MockClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        debug!("Mock Request: {} {:?}", self.model.id(), data);
+        let mut output = match self.reply(&data) {
+            MockReply::Text { text } => ChatCompletionsOutput::new(&text),
+            MockReply::ToolCalls { text, tool_calls } => ChatCompletionsOutput {
+                text,
+                tool_calls,
+                ..Default::default()
+            },
+            MockReply::Error { message, .. } => bail!("{message}"),
+            MockReply::Echo => unreachable!(),
+        };
+        if let Some(delay) = self.token_delay() {
+            let tokens = tokenize(&output.text).len() as u32;
+            tokio::time::sleep(delay * tokens).await;
+        }
+        (output.input_tokens, output.output_tokens) = self.usage();
+        Ok(output)
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        _client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        debug!("Mock Request: {} {:?}", self.model.id(), data);
+        let delay = self.token_delay();
+        let (text, tool_calls, error) = match self.reply(&data) {
+            MockReply::Text { text } => (text, vec![], None),
+            MockReply::ToolCalls { text, tool_calls } => (text, tool_calls, None),
+            MockReply::Error { message, partial } => (partial, vec![], Some(message)),
+            MockReply::Echo => unreachable!(),
+        };
+        for token in tokenize(&text) {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            handler.text(token)?;
+        }
+        if let Some(message) = error {
+            bail!("{message}");
+        }
+        for call in tool_calls {
+            handler.tool_call(call)?;
+        }
+        let (input_tokens, output_tokens) = self.usage();
+        handler.usage(input_tokens, output_tokens);
+        Ok(())
+    }
+
+    async fn embeddings_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<Vec<Vec<f32>>> {
+        if let Some(MockReply::Error { message, .. }) = self.response().map(|v| &v.reply) {
+            bail!("{message}");
+        }
+        Ok(data.texts.iter().map(|v| embed(v)).collect())
+    }
+
+    async fn rerank_inner(
+        &self,
+        _client: &ReqwestClient,
+        data: RerankData,
+    ) -> Result<RerankOutput> {
+        if let Some(MockReply::Error { message, .. }) = self.response().map(|v| &v.reply) {
+            bail!("{message}");
+        }
+        let query = embed(&data.query);
+        Ok(data
+            .documents
+            .iter()
+            .enumerate()
+            .map(|(index, document)| RerankResult {
+                index,
+                relevance_score: embed(document)
+                    .iter()
+                    .zip(&query)
+                    .map(|(a, b)| (a * b) as f64)
+                    .sum(),
+            })
+            .collect())
+    }
+}
+
+/// Hashes the words of the text into a normalized bag-of-words vector, so that texts
+/// sharing words get similar embeddings.
+pub(crate) fn embed(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; EMBEDDING_DIMENSIONS];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+    {
+        let hash = Sha256::digest(word.to_lowercase().as_bytes());
+        let index = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) as usize;
+        vector[index % EMBEDDING_DIMENSIONS] += 1.0;
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_config() {
+        let config: MockConfig = serde_yaml::from_str(
+            r#"
+token_delay_ms: 20
+models:
+  - name: weather
+responses:
+  weather:
+    type: tool_calls
+    tool_calls:
+      - name: get_weather
+        arguments: {"city": "Paris"}
+  broken:
+    type: error
+    message: Rate limit exceeded
+    token_delay_ms: 0
+"#,
+        )
+        .unwrap();
+        let data = ChatCompletionsData {
+            messages: vec![],
+            temperature: None,
+            top_p: None,
+            functions: None,
+            stream: false,
+        };
+        match config.responses["weather"].reply.clone().resolve(&data) {
+            MockReply::ToolCalls { tool_calls, .. } => {
+                assert_eq!(tool_calls[0].id.as_deref(), Some("call_mock_0"))
+            }
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+        assert_eq!(config.responses["broken"].token_delay_ms, Some(0));
+    }
+
+    #[test]
+    fn test_embed() {
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        let a = embed("The cat sat on the mat");
+        let b = embed("the cat sat");
+        let c = embed("Quarterly revenue grew");
+        assert!((dot(&a, &a) - 1.0).abs() < 1e-5);
+        assert!(dot(&a, &b) > dot(&a, &c));
+    }
+}
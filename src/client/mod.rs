@@ -1,13 +1,16 @@
 #[macro_use]
 mod common;
 mod access_token;
+mod cassette;
 mod message;
 mod model;
 mod prompt_format;
 mod stream;
+pub mod tokenizer;
 
 pub use crate::function::{ToolCall, ToolResults};
 pub use crate::utils::PromptKind;
+pub use cassette::*;
 pub use common::*;
 pub use message::*;
 pub use model::*;
@@ -43,8 +46,12 @@ register_client!(
     (replicate, "replicate", ReplicateConfig, ReplicateClient),
     (ernie, "ernie", ErnieConfig, ErnieClient),
     (qianwen, "qianwen", QianwenConfig, QianwenClient),
+    (mock, "mock", MockConfig, MockClient),
 );
 
+#[cfg(test)]
+pub(crate) use self::mock::embed as mock_embed;
+
 pub const OPENAI_COMPATIBLE_PLATFORMS: [(&str, &str); 12] = [
     ("anyscale", "https://api.endpoints.anyscale.com/v1"),
     ("deepinfra", "https://api.deepinfra.com/v1/openai"),
@@ -1,20 +1,26 @@
 use super::{
-    message::{Message, MessageContent},
-    EmbeddingsData,
+    message::{Message, MessageContent, MessageContentPart},
+    tokenizer, ChatCompletionsOutput, EmbeddingsData,
 };
 
-use crate::utils::{estimate_token_length, format_option_value};
+use crate::overflow::OverflowStrategy;
+use crate::utils::format_option_value;
 
 use anyhow::{bail, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-const PER_MESSAGES_TOKENS: usize = 5;
-const BASIS_TOKENS: usize = 2;
+/// Tokens framing each message sent, but the last one when it is not a user message
+pub const PER_MESSAGES_TOKENS: usize = 5;
+/// Tokens priming the reply
+pub const BASIS_TOKENS: usize = 2;
 
 #[derive(Debug, Clone)]
 pub struct Model {
     client_name: String,
     data: ModelData,
+    /// The tokens of the tool declarations sent along the messages
+    tools_tokens: usize,
 }
 
 impl Default for Model {
@@ -28,6 +34,7 @@ impl Model {
         Self {
             client_name: client_name.into(),
             data: ModelData::new(name),
+            tools_tokens: 0,
         }
     }
 
@@ -37,11 +44,12 @@ impl Model {
             .map(|v| Model {
                 client_name: client_name.to_string(),
                 data: v.clone(),
+                tools_tokens: 0,
             })
             .collect()
     }
 
-    pub fn find(models: &[&Self], value: &str) -> Option<Self> {
+    pub fn find(models: &[Self], value: &str) -> Option<Self> {
         let mut model = None;
         log::debug!("Finding model: {}", value);
         let (client_name, model_name) = match value.split_once(':') {
@@ -61,16 +69,24 @@ impl Model {
                     log::debug!("v.id(): {}, value: {}", v.id(), value);
                     v.id() == value
                 }) {
-                    model = Some((*found).clone());
-                } else if let Some(found) = models.iter().find(|v| v.client_name == client_name) {
-                    let mut found = (*found).clone();
+                    if !found.disabled() {
+                        model = Some(found.clone());
+                    }
+                } else if let Some(found) = models
+                    .iter()
+                    .find(|v| v.client_name == client_name && !v.disabled())
+                {
+                    let mut found = found.clone();
                     found.data.name = model_name.to_string();
                     model = Some(found)
                 }
             }
             None => {
-                if let Some(found) = models.iter().find(|v| v.client_name == client_name) {
-                    model = Some((*found).clone());
+                if let Some(found) = models
+                    .iter()
+                    .find(|v| v.client_name == client_name && !v.disabled())
+                {
+                    model = Some(found.clone());
                 }
             }
         }
@@ -94,6 +110,10 @@ impl Model {
         &self.data.mode
     }
 
+    pub fn disabled(&self) -> bool {
+        self.data.disabled
+    }
+
     pub fn data(&self) -> &ModelData {
         &self.data
     }
@@ -150,6 +170,10 @@ impl Model {
         self.data.supports_function_calling
     }
 
+    pub fn overflow_strategy(&self) -> Option<OverflowStrategy> {
+        self.data.overflow_strategy
+    }
+
     pub fn default_chunk_size(&self) -> usize {
         self.data.default_chunk_size.unwrap_or(1000)
     }
@@ -158,6 +182,21 @@ impl Model {
         self.data.max_concurrent_chunks.unwrap_or(1)
     }
 
+    /// Calculate the cost in dollars, prices are per million tokens.
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+        let ModelData {
+            input_price,
+            output_price,
+            ..
+        } = &self.data;
+        if input_price.is_none() && output_price.is_none() {
+            return None;
+        }
+        let cost = input_tokens as f64 * input_price.unwrap_or_default()
+            + output_tokens as f64 * output_price.unwrap_or_default();
+        Some(cost / 1_000_000.0)
+    }
+
     pub fn max_tokens_param(&self) -> Option<isize> {
         if self.data.require_max_tokens {
             self.data.max_output_tokens
@@ -179,36 +218,88 @@ impl Model {
         self
     }
 
-    pub fn messages_tokens(&self, messages: &[Message]) -> usize {
-        messages
+    pub fn count_tokens(&self, text: &str) -> usize {
+        tokenizer::count_tokens(self, text)
+    }
+
+    pub fn message_tokens(&self, message: &Message) -> usize {
+        match &message.content {
+            MessageContent::Text(text) => self.count_tokens(text),
+            MessageContent::Array(list) => list
+                .iter()
+                .map(|v| match v {
+                    MessageContentPart::Text { text } => self.count_tokens(text),
+                    MessageContentPart::ImageUrl { image_url } => {
+                        tokenizer::count_image_tokens(self, image_url)
+                    }
+                })
+                .sum(),
+            MessageContent::ToolResults((results, text)) => {
+                let results: usize = results
+                    .iter()
+                    .map(|v| {
+                        let call = json!({ "name": v.call.name, "arguments": v.call.arguments });
+                        self.count_tokens(&call.to_string())
+                            + self.count_tokens(&v.output.to_string())
+                    })
+                    .sum();
+                self.count_tokens(text) + results
+            }
+        }
+    }
+
+    pub fn tools_tokens(&self) -> usize {
+        self.tools_tokens
+    }
+
+    /// Count the tool declarations sent along the messages against `max_input_tokens`.
+    pub fn set_tools(&mut self, tools: &[Value]) {
+        self.tools_tokens = tools
             .iter()
-            .map(|v| match &v.content {
-                MessageContent::Text(text) => estimate_token_length(text),
-                MessageContent::Array(_) => 0,
-                MessageContent::ToolResults(_) => 0,
+            .map(|v| self.count_tokens(&v.to_string()))
+            .sum();
+    }
+
+    pub fn messages_tokens(&self, messages: &[Message]) -> usize {
+        messages.iter().map(|v| self.message_tokens(v)).sum()
+    }
+
+    /// The framing tokens added to each message.
+    pub fn messages_overheads(&self, messages: &[Message]) -> Vec<usize> {
+        let last_is_user = messages.last().is_some_and(|v| v.role.is_user());
+        (0..messages.len())
+            .map(|i| {
+                if i + 1 < messages.len() || last_is_user {
+                    PER_MESSAGES_TOKENS
+                } else {
+                    0
+                }
             })
-            .sum()
+            .collect()
     }
 
     pub fn total_tokens(&self, messages: &[Message]) -> usize {
-        if messages.is_empty() {
-            return 0;
-        }
-        let num_messages = messages.len();
-        let message_tokens = self.messages_tokens(messages);
-        if messages[num_messages - 1].role.is_user() {
-            num_messages * PER_MESSAGES_TOKENS + message_tokens
-        } else {
-            (num_messages - 1) * PER_MESSAGES_TOKENS + message_tokens
+        self.messages_tokens(messages) + self.messages_overheads(messages).iter().sum::<usize>()
+    }
+
+    /// The tokens of the messages and tools counted against `max_input_tokens`.
+    pub fn input_tokens(&self, messages: &[Message]) -> usize {
+        self.total_tokens(messages) + self.tools_tokens + BASIS_TOKENS
+    }
+
+    /// The estimated tokens to remove from the messages for them to fit in the context
+    /// window, 0 when they fit or the window size is unknown.
+    pub fn excess_input_tokens(&self, messages: &[Message]) -> usize {
+        let input_tokens = self.input_tokens(messages);
+        match self.data.max_input_tokens {
+            Some(max_input_tokens) => (input_tokens + 1).saturating_sub(max_input_tokens),
+            None => 0,
         }
     }
 
     pub fn guard_max_input_tokens(&self, messages: &[Message]) -> Result<()> {
-        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
-        if let Some(max_input_tokens) = self.data.max_input_tokens {
-            if total_tokens >= max_input_tokens {
-                bail!("Exceed max_input_tokens limit")
-            }
+        if self.excess_input_tokens(messages) > 0 {
+            bail!("Exceed max_input_tokens limit")
         }
         Ok(())
     }
@@ -221,9 +312,69 @@ impl Model {
     }
 }
 
-#[derive(Debug, Clone, Default, Deserialize)]
+/// The token usage and cost of a single LLM call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: Option<f64>,
+    /// Whether the token counts were estimated rather than reported by the provider
+    pub estimated: bool,
+}
+
+impl CallUsage {
+    /// The usage reported by the provider, the missing counts being estimated with the
+    /// model's tokenizer.
+    pub fn new(model: &Model, estimated_input_tokens: u64, output: &ChatCompletionsOutput) -> Self {
+        let mut estimated = false;
+        let input_tokens = output.input_tokens.unwrap_or_else(|| {
+            estimated = true;
+            estimated_input_tokens
+        });
+        let output_tokens = output.output_tokens.unwrap_or_else(|| {
+            estimated = true;
+            let mut tokens = model.count_tokens(&output.text);
+            if !output.tool_calls.is_empty() {
+                tokens += model.count_tokens(&json!(output.tool_calls).to_string());
+            }
+            tokens as u64
+        });
+        Self {
+            input_tokens,
+            output_tokens,
+            cost: model.cost(input_tokens, output_tokens),
+            estimated,
+        }
+    }
+
+    /// The usage of two calls made for the same request.
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            input_tokens: self.input_tokens + other.input_tokens,
+            output_tokens: self.output_tokens + other.output_tokens,
+            cost: match (self.cost, other.cost) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+            },
+            estimated: self.estimated || other.estimated,
+        }
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({
+            "prompt_tokens": self.input_tokens,
+            "completion_tokens": self.output_tokens,
+            "total_tokens": self.input_tokens + self.output_tokens,
+            "cost": self.cost,
+            "estimated": self.estimated,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ModelData {
     pub name: String,
+    /// `chat`, `embedding` or `rerank`
     #[serde(default = "default_model_mode")]
     pub mode: String,
     pub max_input_tokens: Option<usize>,
@@ -238,10 +389,19 @@ pub struct ModelData {
     pub supports_vision: bool,
     #[serde(default)]
     pub supports_function_calling: bool,
+    /// Overrides the `overflow_strategy` of the config
+    pub overflow_strategy: Option<OverflowStrategy>,
+    /// `o200k_base`, `cl100k_base`, `p50k_base`, `p50k_edit`, `r50k_base` or the path of a
+    /// tiktoken BPE file, defaults to the tokenizer of the model family
+    pub tokenizer: Option<String>,
 
     // embedding-only properties
     pub default_chunk_size: Option<usize>,
     pub max_concurrent_chunks: Option<usize>,
+
+    /// Keep the model out of the served models, e.g. when it misbehaves
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 impl ModelData {
@@ -79,17 +79,30 @@ impl OllamaClient {
 
         Ok(builder)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_base = self.get_api_base()?;
+        let api_auth = self.get_api_auth().ok();
+
+        let mut builder = client.get(format!("{api_base}/api/tags"));
+        if let Some(api_auth) = api_auth {
+            builder = builder.header("Authorization", api_auth)
+        }
+
+        Ok(builder)
+    }
 }
 
 impl_client_trait!(
     OllamaClient,
     chat_completions,
     chat_completions_streaming,
-    embeddings
+    embeddings;
+    models_builder
 );
 
 async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data = res.json().await?;
     if !status.is_success() {
@@ -106,7 +119,7 @@ async fn chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     if !status.is_success() {
         let data = res.json().await?;
@@ -120,6 +133,10 @@ async fn chat_completions_streaming(
                 if let Some(text) = data["message"]["content"].as_str() {
                     handler.text(text)?;
                 }
+                handler.usage(
+                    data["prompt_eval_count"].as_u64(),
+                    data["eval_count"].as_u64(),
+                );
             } else {
                 bail!("Invalid response data: {data}")
             }
@@ -134,7 +151,7 @@ async fn chat_completions_streaming(
 }
 
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data = res.json().await?;
     if !status.is_success() {
@@ -181,7 +198,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
                                 content.push(text);
                             }
                             MessageContentPart::ImageUrl {
-                                image_url: ImageUrl { url },
+                                image_url: ImageUrl { url, .. },
                             } => {
                                 if let Some((_, data)) = url
                                     .strip_prefix("data:")
@@ -35,6 +35,9 @@ impl OpenAIClient {
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
 
         let mut body = openai_build_chat_completions_body(data, &self.model);
+        if body["stream"] == true {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
         self.patch_chat_completions_body(&mut body);
 
         let url = format!("{api_base}/chat/completions");
@@ -68,10 +71,27 @@ impl OpenAIClient {
 
         Ok(builder)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+
+        let url = format!("{api_base}/models");
+
+        debug!("OpenAI Models Request: {url}");
+
+        let mut builder = client.get(url).bearer_auth(api_key);
+
+        if let Some(organization_id) = &self.config.organization_id {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+
+        Ok(builder)
+    }
 }
 
 pub async fn openai_chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -103,6 +123,13 @@ pub async fn openai_chat_completions_streaming(
         }
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
+        // Sent in a last chunk when asked with `stream_options`
+        if data["usage"].is_object() {
+            handler.usage(
+                data["usage"]["prompt_tokens"].as_u64(),
+                data["usage"]["completion_tokens"].as_u64(),
+            );
+        }
         if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
             handler.text(text)?;
         } else if let (Some(function), index, id) = (
@@ -141,7 +168,7 @@ pub async fn openai_chat_completions_streaming(
 }
 
 pub async fn openai_embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -291,5 +318,6 @@ impl_client_trait!(
     OpenAIClient,
     openai_chat_completions,
     openai_chat_completions_streaming,
-    openai_embeddings
+    openai_embeddings;
+    models_builder
 );
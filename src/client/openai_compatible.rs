@@ -1,9 +1,11 @@
+use super::cohere::{cohere_build_rerank_body, cohere_rerank};
 use super::openai::*;
 use super::*;
 
 use anyhow::Result;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
+use serde_json::json;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct OpenAICompatibleConfig {
@@ -11,6 +13,10 @@ pub struct OpenAICompatibleConfig {
     pub api_base: Option<String>,
     pub api_key: Option<String>,
     pub chat_endpoint: Option<String>,
+    pub rerank_endpoint: Option<String>,
+    /// Ask the server for the token usage of streamed replies, which not all servers support
+    #[serde(default)]
+    pub stream_usage: bool,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patches: Option<ModelPatches>,
@@ -43,6 +49,9 @@ impl OpenAICompatibleClient {
         let api_base = self.get_api_base_ext()?;
 
         let mut body = openai_build_chat_completions_body(data, &self.model);
+        if self.config.stream_usage && body["stream"] == true {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
         self.patch_chat_completions_body(&mut body);
 
         let chat_endpoint = self
@@ -82,6 +91,26 @@ impl OpenAICompatibleClient {
         Ok(builder)
     }
 
+    fn rerank_builder(&self, client: &ReqwestClient, data: RerankData) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key().ok();
+        let api_base = self.get_api_base_ext()?;
+
+        let body = cohere_build_rerank_body(data, &self.model);
+
+        let rerank_endpoint = self.config.rerank_endpoint.as_deref().unwrap_or("/rerank");
+
+        let url = format!("{api_base}{rerank_endpoint}");
+
+        debug!("OpenAICompatible Rerank Request: {url} {body}");
+
+        let mut builder = client.post(url).json(&body);
+        if let Some(api_key) = api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        Ok(builder)
+    }
+
     fn get_api_base_ext(&self) -> Result<String> {
         let api_base = match self.get_api_base() {
             Ok(v) => v,
@@ -102,11 +131,25 @@ impl OpenAICompatibleClient {
         };
         Ok(api_base)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key().ok();
+        let api_base = self.get_api_base_ext()?;
+
+        let mut builder = client.get(format!("{api_base}/models"));
+        if let Some(api_key) = api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        Ok(builder)
+    }
 }
 
 impl_client_trait!(
     OpenAICompatibleClient,
     openai_chat_completions,
     openai_chat_completions_streaming,
-    openai_embeddings
+    openai_embeddings,
+    cohere_rerank;
+    models_builder
 );
@@ -100,7 +100,7 @@ pub fn generate_prompt(messages: &[Message], format: PromptFormat) -> anyhow::Re
                     match item {
                         MessageContentPart::Text { text } => parts.push(text.clone()),
                         MessageContentPart::ImageUrl {
-                            image_url: ImageUrl { url },
+                            image_url: ImageUrl { url, .. },
                         } => {
                             image_urls.push(url.clone());
                         }
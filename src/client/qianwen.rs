@@ -136,7 +136,7 @@ impl Client for QianwenClient {
 }
 
 async fn chat_completions(builder: RequestBuilder, model: &Model) -> Result<ChatCompletionsOutput> {
-    let data: Value = builder.send().await?.json().await?;
+    let data: Value = send_request(builder).await?.json().await?;
     maybe_catch_error(&data)?;
 
     debug!("non-stream-data: {data}");
@@ -166,6 +166,10 @@ async fn chat_completions_streaming(
         } else if let Some(text) = data["output"]["text"].as_str() {
             handler.text(text)?;
         }
+        handler.usage(
+            data["usage"]["input_tokens"].as_u64(),
+            data["usage"]["output_tokens"].as_u64(),
+        );
         Ok(false)
     };
 
@@ -195,7 +199,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
                         .map(|item| match item {
                             MessageContentPart::Text { text } => json!({"text": text}),
                             MessageContentPart::ImageUrl {
-                                image_url: ImageUrl { url },
+                                image_url: ImageUrl { url, .. },
                             } => {
                                 if url.starts_with("oss:") {
                                     has_upload = true;
@@ -250,7 +254,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
 }
 
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let data: Value = builder.send().await?.json().await?;
+    let data: Value = send_request(builder).await?.json().await?;
     maybe_catch_error(&data)?;
     let res_body: EmbeddingsResBody =
         serde_json::from_value(data).context("Invalid request data")?;
@@ -308,7 +312,7 @@ async fn patch_messages(model: &str, api_key: &str, messages: &mut Vec<Message>)
         if let MessageContent::Array(list) = message.content.borrow_mut() {
             for item in list {
                 if let MessageContentPart::ImageUrl {
-                    image_url: ImageUrl { url },
+                    image_url: ImageUrl { url, .. },
                 } = item
                 {
                     if url.starts_with("data:") {
@@ -353,15 +357,12 @@ async fn upload(model: &str, api_key: &str, url: &str) -> Result<String> {
     let data = base64_decode(data)?;
 
     let client = reqwest::Client::new();
-    let policy: Policy = client
+    let builder = client
         .get(format!(
             "https://dashscope.aliyuncs.com/api/v1/uploads?action=getPolicy&model={model}"
         ))
-        .header("Authorization", format!("Bearer {api_key}"))
-        .send()
-        .await?
-        .json()
-        .await?;
+        .header("Authorization", format!("Bearer {api_key}"));
+    let policy: Policy = send_request(builder).await?.json().await?;
     let PolicyData {
         policy,
         signature,
@@ -386,7 +387,7 @@ async fn upload(model: &str, api_key: &str, url: &str) -> Result<String> {
         .text("x-oss-content-type", mime_type.to_string())
         .part("file", file);
 
-    let res = client.post(upload_host).multipart(form).send().await?;
+    let res = send_request(client.post(upload_host).multipart(form)).await?;
 
     let status = res.status();
     if !status.is_success() {
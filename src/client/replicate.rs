@@ -76,7 +76,7 @@ async fn chat_completions(
     builder: RequestBuilder,
     api_key: &str,
 ) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -87,13 +87,8 @@ async fn chat_completions(
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
     loop {
         tokio::time::sleep(Duration::from_millis(500)).await;
-        let prediction_data: Value = client
-            .get(prediction_url)
-            .bearer_auth(api_key)
-            .send()
-            .await?
-            .json()
-            .await?;
+        let builder = client.get(prediction_url).bearer_auth(api_key);
+        let prediction_data: Value = send_request(builder).await?.json().await?;
         debug!("non-stream-data: {prediction_data}");
         let err = || anyhow!("Invalid response data: {prediction_data}");
         let status = prediction_data["status"].as_str().ok_or_else(err)?;
@@ -110,7 +105,7 @@ async fn chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -1,10 +1,13 @@
-use super::{catch_error, ToolCall};
+use super::{catch_error, send_request, ChatCompletionsOutput, ToolCall};
 use crate::utils::AbortSignal;
 
 use anyhow::{anyhow, bail, Context, Result};
+use eventsource_stream::Eventsource;
 use futures_util::{Stream, StreamExt};
-use reqwest::RequestBuilder;
-use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    RequestBuilder,
+};
 use serde_json::Value;
 use tokio::sync::mpsc::UnboundedSender;
 
@@ -13,6 +16,8 @@ pub struct SseHandler {
     abort: AbortSignal,
     buffer: String,
     tool_calls: Vec<ToolCall>,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
 }
 
 impl SseHandler {
@@ -22,6 +27,8 @@ impl SseHandler {
             abort,
             buffer: String::new(),
             tool_calls: Vec::new(),
+            input_tokens: None,
+            output_tokens: None,
         }
     }
 
@@ -55,15 +62,36 @@ impl SseHandler {
         Ok(())
     }
 
+    /// Record the token counts reported by the provider, a count reported again replacing
+    /// the previous one.
+    pub fn usage(&mut self, input_tokens: Option<u64>, output_tokens: Option<u64>) {
+        if input_tokens.is_some() {
+            self.input_tokens = input_tokens;
+        }
+        if output_tokens.is_some() {
+            self.output_tokens = output_tokens;
+        }
+    }
+
     pub fn get_abort(&self) -> AbortSignal {
         self.abort.clone()
     }
 
-    pub fn take(self) -> (String, Vec<ToolCall>) {
+    pub fn take(self) -> ChatCompletionsOutput {
         let Self {
-            buffer, tool_calls, ..
+            buffer,
+            tool_calls,
+            input_tokens,
+            output_tokens,
+            ..
         } = self;
-        (buffer, tool_calls)
+        ChatCompletionsOutput {
+            text: buffer,
+            tool_calls,
+            input_tokens,
+            output_tokens,
+            ..Default::default()
+        }
     }
 
     fn safe_ret(&self, ret: Result<()>) -> Result<()> {
@@ -90,48 +118,45 @@ pub async fn sse_stream<F>(builder: RequestBuilder, mut handle: F) -> Result<()>
 where
     F: FnMut(SseMmessage) -> Result<bool>,
 {
-    let mut es = builder.eventsource()?;
-    while let Some(event) = es.next().await {
-        match event {
-            Ok(Event::Open) => {}
-            Ok(Event::Message(message)) => {
-                let message = SseMmessage {
-                    event: message.event,
-                    data: message.data,
-                };
-                if handle(message)? {
-                    break;
-                }
-            }
-            Err(err) => {
-                match err {
-                    EventSourceError::StreamEnded => {}
-                    EventSourceError::InvalidStatusCode(status, res) => {
-                        let text = res.text().await?;
-                        let data: Value = match text.parse() {
-                            Ok(data) => data,
-                            Err(_) => {
-                                bail!(
-                                    "Invalid response data: {text} (status: {})",
-                                    status.as_u16()
-                                );
-                            }
-                        };
-                        catch_error(&data, status.as_u16())?;
-                    }
-                    EventSourceError::InvalidContentType(header_value, res) => {
-                        let text = res.text().await?;
-                        bail!(
-                            "Invalid response event-stream. content-type: {}, data: {text}",
-                            header_value.to_str().unwrap_or_default()
-                        );
-                    }
-                    _ => {
-                        bail!("{}", err);
-                    }
-                }
-                es.close();
+    let builder = builder.header(ACCEPT, "text/event-stream");
+    let res = send_request(builder).await?;
+    let status = res.status();
+    if !status.is_success() {
+        let text = res.text().await?;
+        let data: Value = match text.parse() {
+            Ok(data) => data,
+            Err(_) => {
+                bail!(
+                    "Invalid response data: {text} (status: {})",
+                    status.as_u16()
+                );
             }
+        };
+        catch_error(&data, status.as_u16())?;
+        bail!(
+            "Invalid response data: {data} (status: {})",
+            status.as_u16()
+        );
+    }
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.starts_with("text/event-stream") {
+        let text = res.text().await?;
+        bail!("Invalid response event-stream. content-type: {content_type}, data: {text}");
+    }
+    let mut stream = res.bytes_stream().eventsource();
+    while let Some(event) = stream.next().await {
+        let message = event.map_err(|err| anyhow!("Failed to read event-stream, {err}"))?;
+        let message = SseMmessage {
+            event: message.event,
+            data: message.data,
+        };
+        if handle(message)? {
+            break;
         }
     }
     Ok(())
@@ -0,0 +1,360 @@
+use super::{ImageUrl, Model};
+
+use crate::config::Config;
+use crate::utils::{data_url_image_dimensions, estimate_token_length};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, p50k_edit_singleton,
+    r50k_base_singleton, tokenizer::get_tokenizer, tokenizer::Tokenizer, CoreBPE,
+};
+
+/// The dimensions assumed for the images whose size is unknown, like remote ones
+const DEFAULT_IMAGE_DIMENSIONS: (u32, u32) = (1024, 1024);
+/// The tokens of an image seen by Gemini models, whatever its size
+const GEMINI_IMAGE_TOKENS: usize = 258;
+
+/// Where the Llama 3 tokenizer is cached, relative to the config dir
+const LLAMA3_TOKENIZER: &str = "tokenizers/llama3.tiktoken";
+
+/// The pre-tokenization pattern of cl100k_base, which the Llama 3 tokenizer shares
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+lazy_static! {
+    static ref LLAMA3_RE: Regex = Regex::new(r"(?i)(^|[^a-z0-9])llama-?v?3([^0-9]|$)").unwrap();
+    static ref BPE_FILES: RwLock<HashMap<String, &'static CoreBPE>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct TokenizersConfig {
+    /// Where to download the Llama 3 `tokenizer.model` from, once, none by default
+    pub llama3_url: Option<String>,
+}
+
+/// Count the tokens of the text with the model's tokenizer, estimating them for the model
+/// families without a known one.
+pub fn count_tokens(model: &Model, text: &str) -> usize {
+    match model_bpe(model) {
+        Some(bpe) => bpe.encode_ordinary(text).len(),
+        None => estimate_token_length(text),
+    }
+}
+
+/// Count the tokens of an image with the formula of the model's provider.
+pub fn count_image_tokens(model: &Model, image_url: &ImageUrl) -> usize {
+    let name = model.name();
+    if name.contains("gemini") {
+        return GEMINI_IMAGE_TOKENS;
+    }
+    let (width, height) =
+        data_url_image_dimensions(&image_url.url).unwrap_or(DEFAULT_IMAGE_DIMENSIONS);
+    let (width, height) = (width as f64, height as f64);
+    if name.contains("claude") {
+        // Images are scaled down to a long edge of 1568 pixels and about 1.15 megapixels,
+        // each 750 pixels making a token
+        let scale = (1568.0 / width.max(height))
+            .min((1_150_000.0 / (width * height)).sqrt())
+            .min(1.0);
+        return ((width * scale) * (height * scale) / 750.0).ceil() as usize;
+    }
+    // OpenAI sees low detail images at 512x512, and the others in 512 pixels tiles once
+    // scaled to fit in 2048x2048 with a short edge of at most 768 pixels
+    if image_url.detail.as_deref() == Some("low") {
+        return 85;
+    }
+    let scale = (2048.0 / width.max(height)).min(1.0);
+    let (width, height) = (width * scale, height * scale);
+    let scale = (768.0 / width.min(height)).min(1.0);
+    let (width, height) = (width * scale, height * scale);
+    let tiles = (width / 512.0).ceil() * (height / 512.0).ceil();
+    85 + 170 * tiles as usize
+}
+
+/// The name of the model's tokenizer, `estimate` when its tokens are estimated.
+pub fn tokenizer_name(model: &Model) -> String {
+    if let Some(name) = &model.data().tokenizer {
+        return name.clone();
+    }
+    let name = match family_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => "o200k_base",
+        Some(Tokenizer::Cl100kBase) => "cl100k_base",
+        Some(Tokenizer::P50kBase) => "p50k_base",
+        Some(Tokenizer::P50kEdit) => "p50k_edit",
+        Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => "r50k_base",
+        None if is_llama3(model) && llama3_bpe().is_some() => "llama3",
+        None => "estimate",
+    };
+    name.to_string()
+}
+
+/// Check that the tokenizers configured for the models load.
+pub fn validate(models: &[Model]) -> Result<()> {
+    for model in models {
+        if let Some(name) = &model.data().tokenizer {
+            load(name).with_context(|| format!("Invalid tokenizer of '{}'", model.id()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The configured tokenizer of the model, else the one of its family, e.g. o200k_base for
+/// the gpt-4o models served by any client.
+fn model_bpe(model: &Model) -> Option<&'static CoreBPE> {
+    match &model.data().tokenizer {
+        Some(name) => match load(name) {
+            Ok(bpe) => Some(bpe),
+            Err(err) => {
+                log::warn!("Failed to load the tokenizer of '{}': {err:#}", model.id());
+                None
+            }
+        },
+        None => match family_tokenizer(model) {
+            Some(tokenizer) => Some(builtin(tokenizer)),
+            None if is_llama3(model) => llama3_bpe(),
+            None => None,
+        },
+    }
+}
+
+/// Download the Llama 3 tokenizer into the config dir when `tokenizers.llama3_url` is set
+/// and it is not there yet, the Llama 3 models being estimated until then.
+pub async fn sync(config: &TokenizersConfig) {
+    let Some(url) = &config.llama3_url else {
+        return;
+    };
+    let result = match Config::local_path(LLAMA3_TOKENIZER) {
+        Ok(path) => download(url, &path).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        log::warn!("Failed to download the Llama 3 tokenizer: {err:#}");
+    }
+}
+
+async fn download(url: &str, path: &Path) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let content = reqwest::get(url).await?.error_for_status()?.text().await?;
+    parse_bpe_file(&content).with_context(|| format!("Invalid tokenizer at {url}"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    // Written aside then renamed, so that a partial file is never loaded
+    let partial = path.with_extension("part");
+    fs::write(&partial, content)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    fs::rename(&partial, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    log::info!("Downloaded the Llama 3 tokenizer to {}", path.display());
+    Ok(())
+}
+
+/// Llama 3 and its point releases, e.g. `llama3-70b-8192`, `Meta-Llama-3.1-8B-Instruct` or
+/// `llama-v3-8b-instruct`.
+fn is_llama3(model: &Model) -> bool {
+    LLAMA3_RE.is_match(model.name()).unwrap_or_default()
+}
+
+/// The Llama 3 tokenizer once downloaded.
+fn llama3_bpe() -> Option<&'static CoreBPE> {
+    if let Some(bpe) = BPE_FILES.read().get(LLAMA3_TOKENIZER) {
+        return Some(bpe);
+    }
+    if !Config::local_path(LLAMA3_TOKENIZER).ok()?.exists() {
+        return None;
+    }
+    match load(LLAMA3_TOKENIZER) {
+        Ok(bpe) => Some(bpe),
+        Err(err) => {
+            log::warn!("Failed to load the Llama 3 tokenizer: {err:#}");
+            None
+        }
+    }
+}
+
+fn family_tokenizer(model: &Model) -> Option<Tokenizer> {
+    // Routers prefix their model names with the vendor, e.g. `openai/gpt-4o`
+    let name = model.name().rsplit('/').next().unwrap_or_default();
+    get_tokenizer(name)
+}
+
+fn builtin(tokenizer: Tokenizer) -> &'static CoreBPE {
+    match tokenizer {
+        Tokenizer::O200kBase => o200k_base_singleton(),
+        Tokenizer::Cl100kBase => cl100k_base_singleton(),
+        Tokenizer::P50kBase => p50k_base_singleton(),
+        Tokenizer::P50kEdit => p50k_edit_singleton(),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => r50k_base_singleton(),
+    }
+}
+
+/// Load a built-in tokenizer by name, or a BPE ranks file in the tiktoken format, like the
+/// `tokenizer.model` of Llama 3, from a path relative to the config dir.
+fn load(name: &str) -> Result<&'static CoreBPE> {
+    let bpe = match name {
+        "o200k_base" => o200k_base_singleton(),
+        "cl100k_base" => cl100k_base_singleton(),
+        "p50k_base" => p50k_base_singleton(),
+        "p50k_edit" => p50k_edit_singleton(),
+        "r50k_base" => r50k_base_singleton(),
+        _ => {
+            if let Some(bpe) = BPE_FILES.read().get(name) {
+                return Ok(bpe);
+            }
+            let path = if Path::new(name).is_absolute() {
+                name.into()
+            } else {
+                Config::local_path(name)?
+            };
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?;
+            let bpe: &'static CoreBPE = Box::leak(Box::new(parse_bpe_file(&content)?));
+            BPE_FILES.write().insert(name.to_string(), bpe);
+            bpe
+        }
+    };
+    Ok(bpe)
+}
+
+/// Parse the lines of base64 encoded tokens and their ranks of a tiktoken BPE file.
+fn parse_bpe_file(content: &str) -> Result<CoreBPE> {
+    let mut encoder = FxHashMap::default();
+    for (i, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let Some((token, rank)) = line.split_once(' ') else {
+            bail!("Invalid BPE rank at line {}", i + 1);
+        };
+        let token = STANDARD
+            .decode(token)
+            .with_context(|| format!("Invalid BPE token at line {}", i + 1))?;
+        let rank = rank
+            .parse()
+            .with_context(|| format!("Invalid BPE rank at line {}", i + 1))?;
+        encoder.insert(token, rank);
+    }
+    if encoder.is_empty() {
+        bail!("No BPE ranks");
+    }
+    CoreBPE::new(encoder, FxHashMap::default(), CL100K_PATTERN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tokens() {
+        let text = "Hello world, the quick brown fox jumps over the lazy dog.";
+        assert_eq!(count_tokens(&Model::new("openai", "gpt-4o-mini"), text), 13);
+        assert_eq!(
+            count_tokens(&Model::new("openrouter", "openai/gpt-4-turbo"), text),
+            13
+        );
+        assert_eq!(
+            count_tokens(&Model::new("claude", "claude-3-5-sonnet"), text),
+            estimate_token_length(text)
+        );
+
+        let ranks = [b"a".as_slice(), b"b", b"ab", b" ", b" ab"]
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("{} {i}", STANDARD.encode(v)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let bpe = parse_bpe_file(&ranks).unwrap();
+        assert_eq!(bpe.encode_ordinary("ab ab b").len(), 4);
+        assert!(parse_bpe_file("YWI=").is_err());
+
+        for name in [
+            "o200k_base",
+            "cl100k_base",
+            "p50k_base",
+            "p50k_edit",
+            "r50k_base",
+        ] {
+            assert!(load(name).is_ok(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            for body in ["YQ== 0\nYg== 1\nYWI= 2\n", "not a tokenizer"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        let dir =
+            std::env::temp_dir().join(format!("agent-panel-tokenizers-{}", std::process::id()));
+        let path = dir.join("llama3.tiktoken");
+        let url = format!("http://{addr}/tokenizer.model");
+
+        download(&url, &path).await.unwrap();
+        let bpe = parse_bpe_file(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(bpe.encode_ordinary("abab").len(), 2);
+        // Cached, the server is not asked again
+        download(&url, &path).await.unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert!(download(&url, &path).await.is_err());
+        assert!(!path.exists());
+        server.await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for name in [
+            "llama3-70b-8192",
+            "meta-llama/Meta-Llama-3.1-8B-Instruct",
+            "llama-v3-8b-instruct",
+        ] {
+            assert!(is_llama3(&Model::new("groq", name)), "{name}");
+        }
+        for name in ["codellama-34b", "llama-30b", "llama2-70b"] {
+            assert!(!is_llama3(&Model::new("ollama", name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_count_image_tokens() {
+        let image = |width: u32, height: u32, detail: Option<&str>| {
+            let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+            png.extend(width.to_be_bytes());
+            png.extend(height.to_be_bytes());
+            ImageUrl {
+                url: format!("data:image/png;base64,{}", STANDARD.encode(png)),
+                detail: detail.map(|v| v.to_string()),
+            }
+        };
+        let gpt = Model::new("openai", "gpt-4o");
+        assert_eq!(count_image_tokens(&gpt, &image(1024, 1024, None)), 765);
+        assert_eq!(count_image_tokens(&gpt, &image(2048, 4096, None)), 1105);
+        assert_eq!(
+            count_image_tokens(&gpt, &image(4096, 4096, Some("low"))),
+            85
+        );
+        let claude = Model::new("claude", "claude-3-5-sonnet");
+        assert_eq!(count_image_tokens(&claude, &image(200, 200, None)), 54);
+        assert_eq!(count_image_tokens(&claude, &image(4000, 3000, None)), 1534);
+        let gemini = Model::new("gemini", "gemini-1.5-pro");
+        assert_eq!(count_image_tokens(&gemini, &image(4000, 3000, None)), 258);
+    }
+}
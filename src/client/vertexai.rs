@@ -127,7 +127,7 @@ impl Client for VertexAIClient {
 }
 
 pub async fn gemini_chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -141,7 +141,7 @@ pub async fn gemini_chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     if !status.is_success() {
         let data: Value = res.json().await?;
@@ -150,6 +150,11 @@ pub async fn gemini_chat_completions_streaming(
         let handle = |value: &str| -> Result<()> {
             let data: Value = serde_json::from_str(value)?;
             debug!("stream-data: {data}");
+            // Every chunk carries the counts so far
+            handler.usage(
+                data["usageMetadata"]["promptTokenCount"].as_u64(),
+                data["usageMetadata"]["candidatesTokenCount"].as_u64(),
+            );
             if let Some(text) = data["candidates"][0]["content"]["parts"][0]["text"].as_str() {
                 if !text.is_empty() {
                     handler.text(text)?;
@@ -178,7 +183,7 @@ pub async fn gemini_chat_completions_streaming(
 }
 
 async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
-    let res = builder.send().await?;
+    let res = send_request(builder).await?;
     let status = res.status();
     let data: Value = res.json().await?;
     if !status.is_success() {
@@ -283,7 +288,7 @@ pub fn gemini_build_chat_completions_body(
                             .into_iter()
                             .map(|item| match item {
                                 MessageContentPart::Text { text } => json!({"text": text}),
-                                MessageContentPart::ImageUrl { image_url: ImageUrl { url } } => {
+                                MessageContentPart::ImageUrl { image_url: ImageUrl { url, .. } } => {
                                     if let Some((mime_type, data)) = url.strip_prefix("data:").and_then(|v| v.split_once(";base64,")) {
                                         json!({ "inline_data": { "mime_type": mime_type, "data": data } })
                                     } else {
@@ -372,13 +377,10 @@ async fn fetch_access_token(
     file: &Option<String>,
 ) -> Result<(String, i64)> {
     let credentials = load_adc(file).await?;
-    let value: Value = client
+    let builder = client
         .post("https://oauth2.googleapis.com/token")
-        .json(&credentials)
-        .send()
-        .await?
-        .json()
-        .await?;
+        .json(&credentials);
+    let value: Value = send_request(builder).await?.json().await?;
 
     if let (Some(access_token), Some(expires_in)) =
         (value["access_token"].as_str(), value["expires_in"].as_i64())
@@ -289,7 +289,7 @@ impl Input {
                 .iter()
                 .cloned()
                 .map(|url| MessageContentPart::ImageUrl {
-                    image_url: ImageUrl { url },
+                    image_url: ImageUrl { url, detail: None },
                 })
                 .collect();
             if !self.text.is_empty() {
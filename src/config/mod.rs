@@ -2,13 +2,22 @@ mod input;
 mod session;
 
 pub use self::input::{Input, InputContext};
-use self::session::{Session, TEMP_SESSION_NAME};
+use self::session::TEMP_SESSION_NAME;
+pub use self::session::{validate_session_name, Session, MIN_COMPRESS_THRESHOLD};
 
+use crate::admin::AdminConfig;
 use crate::client::{
-    create_client_config, list_chat_models, list_client_types, ClientConfig, Model,
-    OPENAI_COMPATIBLE_PLATFORMS,
+    create_client_config, list_chat_models, list_client_types, tokenizer,
+    tokenizer::TokenizersConfig, ClientConfig, Model, OPENAI_COMPATIBLE_PLATFORMS,
 };
-use crate::function::{Function, ToolCallResult};
+use crate::escalation::{self, Escalations};
+use crate::function::{Function, ToolCallResult, ToolsConfig};
+use crate::health::HealthConfig;
+use crate::mcp::{self, McpServerConfig};
+use crate::overflow::OverflowStrategy;
+use crate::rag::RagConfig;
+use crate::summarize::SummarizeConfig;
+use crate::telemetry::TelemetryConfig;
 use crate::utils::{
     format_option_value, get_env_name, now, 
     set_text, 
@@ -18,7 +27,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use inquire::{Confirm, Select};
 use parking_lot::RwLock;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::{
     env,
@@ -32,14 +41,16 @@ const CONFIG_FILE_NAME: &str = "config.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
 const FUNCTIONS_DIR_NAME: &str = "functions";
+const RAGS_DIR_NAME: &str = "rags";
+const VECTOR_STORES_DIR_NAME: &str = "vector_stores";
 
 const CLIENTS_FIELD: &str = "clients";
 
-const SUMMARIZE_PROMPT: &str =
+pub const SUMMARIZE_PROMPT: &str =
     "Summarize the discussion briefly in 200 words or less to use as a prompt for future context.";
-const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
+pub const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
 
-const RAG_TEMPLATE: &str = r#"Answer the following question based only on the provided context:
+pub const RAG_TEMPLATE: &str = r#"Answer the following question based only on the provided context:
 <context>
 __CONTEXT__
 </context>
@@ -61,7 +72,17 @@ pub struct Config {
     pub save: bool,
     pub save_session: Option<bool>,
     pub function_calling: bool,
+    pub overflow_strategy: OverflowStrategy,
+    pub escalations: Escalations,
+    pub tools: ToolsConfig,
+    pub mcp_servers: Vec<McpServerConfig>,
     pub clients: Vec<ClientConfig>,
+    pub telemetry: TelemetryConfig,
+    pub admin: AdminConfig,
+    pub health: HealthConfig,
+    pub tokenizers: TokenizersConfig,
+    pub summarize: SummarizeConfig,
+    pub rag: RagConfig,
     #[serde(skip)]
     pub session: Option<Session>,
     #[serde(skip)]
@@ -81,7 +102,17 @@ impl Default for Config {
             save: false,
             save_session: None,
             function_calling: false,
+            overflow_strategy: Default::default(),
+            escalations: Default::default(),
+            tools: Default::default(),
+            mcp_servers: vec![],
             clients: vec![],
+            telemetry: Default::default(),
+            admin: Default::default(),
+            health: Default::default(),
+            tokenizers: Default::default(),
+            summarize: Default::default(),
+            rag: Default::default(),
             session: None,
             model: Default::default(),
             function: Default::default(),
@@ -96,11 +127,18 @@ impl Config {
     pub fn init() -> Result<Self> {
         let config_path = Self::config_file()?;
 
-        let platform = env::var(get_env_name("platform")).ok();
         if !config_path.exists() {
             create_config_file(&config_path)?;
         }
 
+        Self::load()
+    }
+
+    /// Load and validate the config, used on startup and whenever it is reloaded.
+    pub fn load() -> Result<Self> {
+        let config_path = Self::config_file()?;
+        let platform = env::var(get_env_name("platform")).ok();
+
         log::debug!("Loading config from {}", config_path.display());
         let mut config = if platform.is_some() {
             Self::load_config_env(&platform.unwrap())?
@@ -109,12 +147,53 @@ impl Config {
         };
 
         config.function = Function::init(&Self::functions_dir()?)?;
+        mcp::validate(&config.mcp_servers)?;
 
         config.setup_model()?;
+        escalation::validate(&config.escalations, &list_chat_models(&config))?;
+        tokenizer::validate(&list_chat_models(&config))?;
 
         Ok(config)
     }
 
+    /// Build the config from a document edited through the admin API.
+    pub fn from_document(document: &Value) -> Result<Self> {
+        let content = serde_yaml::to_string(document)?;
+        let mut config = Self::parse_config(&content)?;
+
+        config.function = Function::init(&Self::functions_dir()?)?;
+        mcp::validate(&config.mcp_servers)?;
+
+        config.setup_model()?;
+        escalation::validate(&config.escalations, &list_chat_models(&config))?;
+        tokenizer::validate(&list_chat_models(&config))?;
+
+        Ok(config)
+    }
+
+    /// Load the raw config document, the source the admin API edits.
+    pub fn load_document() -> Result<Value> {
+        if let Ok(platform) = env::var(get_env_name("platform")) {
+            return Ok(Self::env_document(&platform));
+        }
+        let config_path = Self::config_file()?;
+        let content = read_to_string(&config_path)
+            .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
+        let document = serde_yaml::from_str(&content)?;
+        Ok(document)
+    }
+
+    /// Write the config document back to the config file, comments are not preserved.
+    pub fn save_document(document: &Value) -> Result<()> {
+        if env::var(get_env_name("platform")).is_ok() {
+            bail!("Cannot save the config, it is loaded from the environment");
+        }
+        let config_path = Self::config_file()?;
+        let content = serde_yaml::to_string(document)?;
+        std::fs::write(&config_path, content)
+            .with_context(|| format!("Failed to write config to {}", config_path.display()))
+    }
+
     pub fn config_dir() -> Result<PathBuf> {
         let env_name = get_env_name("config_dir");
         let path = if let Some(v) = env::var_os(env_name) {
@@ -190,6 +269,20 @@ impl Config {
         }
     }
 
+    pub fn rags_dir() -> Result<PathBuf> {
+        match env::var(get_env_name("rags_dir")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Self::local_path(RAGS_DIR_NAME),
+        }
+    }
+
+    pub fn vector_stores_dir() -> Result<PathBuf> {
+        match env::var(get_env_name("vector_stores_dir")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Self::local_path(VECTOR_STORES_DIR_NAME),
+        }
+    }
+
     pub fn session_file(name: &str) -> Result<PathBuf> {
         let mut path = Self::sessions_dir()?;
         path.push(&format!("{name}.yaml"));
@@ -500,7 +593,11 @@ impl Config {
     fn load_config_file(config_path: &Path) -> Result<Self> {
         let content = read_to_string(config_path)
             .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
-        let config: Self = serde_yaml::from_str(&content).map_err(|err| {
+        Self::parse_config(&content)
+    }
+
+    fn parse_config(content: &str) -> Result<Self> {
+        let mut config: Self = serde_yaml::from_str(content).map_err(|err| {
             let err_msg = err.to_string();
             let err_msg = if err_msg.starts_with(&format!("{}: ", CLIENTS_FIELD)) {
                 // location is incorrect, get rid of it
@@ -516,10 +613,33 @@ impl Config {
             anyhow!("{err_msg}")
         })?;
 
+        // Clients marked as `disabled: true` are kept in the file but not served.
+        #[derive(Deserialize)]
+        struct ClientFlags {
+            #[serde(default)]
+            disabled: bool,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct Flags {
+            clients: Vec<ClientFlags>,
+        }
+        let flags: Flags = serde_yaml::from_str(content).unwrap_or_default();
+        let mut flags = flags.clients.into_iter();
+        config
+            .clients
+            .retain(|_| !flags.next().map(|v| v.disabled).unwrap_or_default());
+
         Ok(config)
     }
 
     fn load_config_env(platform: &str) -> Result<Self> {
+        let config = serde_json::from_value(Self::env_document(platform))
+            .with_context(|| "Failed to load config from env")?;
+        Ok(config)
+    }
+
+    fn env_document(platform: &str) -> Value {
         let model_id = match env::var(get_env_name("model_name")) {
             Ok(model_name) => format!("{platform}:{model_name}"),
             Err(_) => platform.to_string(),
@@ -532,24 +652,20 @@ impl Config {
         } else {
             json!({ "type": platform })
         };
-        let config = json!({
+        json!({
             "model": model_id,
             "save": false,
             "clients": vec![client],
-        });
-        let config =
-            serde_json::from_value(config).with_context(|| "Failed to load config from env")?;
-        Ok(config)
+        })
     }
 
     fn setup_model(&mut self) -> Result<()> {
         let model_id = if self.model_id.is_empty() {
             let models = list_chat_models(self);
-            if models.is_empty() {
-                bail!("No available model");
+            match models.iter().find(|v| !v.disabled()) {
+                Some(model) => model.id(),
+                None => bail!("No available model"),
             }
-
-            models[0].id()
         } else {
             self.model_id.clone()
         };
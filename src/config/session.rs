@@ -2,6 +2,7 @@ use super::input::resolve_data_url;
 use super::{Config, Input, Model};
 
 use crate::client::{Message, MessageContent, MessageRole};
+use crate::store::validate_name;
 
 use anyhow::{bail, Context, Result};
 use inquire::{Confirm, Text};
@@ -9,9 +10,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::fs::{self, create_dir_all, read_to_string};
+use std::ops::Range;
 use std::path::Path;
 
 pub const TEMP_SESSION_NAME: &str = "temp";
+/// Below this many tokens, conversations are never worth compressing
+pub const MIN_COMPRESS_THRESHOLD: usize = 1000;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Session {
@@ -115,7 +119,7 @@ impl Session {
         let threshold = self
             .compress_threshold
             .unwrap_or(current_compress_threshold);
-        threshold >= 1000 && self.tokens() > threshold
+        threshold >= MIN_COMPRESS_THRESHOLD && self.tokens() > threshold
     }
 
     pub fn tokens(&self) -> usize {
@@ -293,6 +297,13 @@ impl Session {
         self.dirty = true;
     }
 
+    /// Move the messages in the range to the compressed history, replaced by their summary.
+    pub fn compress_range(&mut self, range: Range<usize>, summary: Message) {
+        let removed: Vec<Message> = self.messages.splice(range, [summary]).collect();
+        self.compressed_messages.extend(removed);
+        self.dirty = true;
+    }
+
     pub fn exit(&mut self, sessions_dir: &Path, is_repl: bool) -> Result<()> {
         let save_session = self.save_session();
         if self.dirty && save_session != Some(false) {
@@ -355,18 +366,29 @@ impl Session {
     }
 
     pub fn add_message(&mut self, input: &Input, output: &str) -> Result<()> {
-        let mut need_add_msg = true;
-        if need_add_msg {
-            self.messages
-                .push(Message::new(MessageRole::User, input.message_content()));
-        }
         self.data_urls.extend(input.data_urls());
+        self.add_turn(input.message_content(), output);
+        Ok(())
+    }
+
+    /// Append a user message and the assistant reply to the history.
+    pub fn add_turn(&mut self, content: MessageContent, output: &str) {
+        self.messages.push(Message::new(MessageRole::User, content));
         self.messages.push(Message::new(
             MessageRole::Assistant,
             MessageContent::Text(output.to_string()),
         ));
         self.dirty = true;
-        Ok(())
+    }
+
+    /// Seed the history, e.g. with a system prompt.
+    pub fn extend_messages(&mut self, messages: Vec<Message>) {
+        self.messages.extend(messages);
+        self.dirty = true;
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
     }
 
     pub fn clear_messages(&mut self) {
@@ -382,17 +404,41 @@ impl Session {
     }
 
     pub fn build_messages(&self, input: &Input) -> Vec<Message> {
+        self.build_messages_with(input.message_content())
+    }
+
+    /// The messages to send for a new user turn: the history followed by the turn.
+    pub fn build_messages_with(&self, content: MessageContent) -> Vec<Message> {
         let mut messages = self.messages.clone();
-        let mut need_add_msg = true;
-        let len = messages.len();
         // TODO: Review the change
-        if len == 0 && self.compressed_messages.len() >= 2 {
+        if messages.is_empty() && self.compressed_messages.len() >= 2 {
             messages
                 .extend(self.compressed_messages[self.compressed_messages.len() - 2..].to_vec());
         }
-        if need_add_msg {
-            messages.push(Message::new(MessageRole::User, input.message_content()));
-        }
+        messages.push(Message::new(MessageRole::User, content));
         messages
     }
+
+    /// The names of the sessions saved in the directory.
+    pub fn list(sessions_dir: &Path) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(sessions_dir) else {
+            return vec![];
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "yaml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+/// Session names end up in file paths, so they are restricted to a safe charset.
+pub fn validate_session_name(name: &str) -> Result<()> {
+    validate_name("session", name)
 }
@@ -0,0 +1,80 @@
+use crate::client::Model;
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+
+/// Ladders of models of increasing context size, keyed by the alias or model id requested.
+pub type Escalations = IndexMap<String, Vec<String>>;
+
+/// Check that every ladder lists served chat models.
+pub fn validate(escalations: &Escalations, models: &[Model]) -> Result<()> {
+    for (alias, ladder) in escalations {
+        if alias.is_empty() || alias == "default" {
+            bail!("Invalid escalation alias '{alias}'");
+        }
+        if ladder.is_empty() {
+            bail!("The escalation ladder of '{alias}' is empty");
+        }
+        for (i, model) in ladder.iter().enumerate() {
+            if ladder[..i].contains(model) {
+                bail!("The escalation ladder of '{alias}' lists '{model}' twice");
+            }
+            if !models.iter().any(|v| v.id() == *model && !v.disabled()) {
+                bail!("The escalation ladder of '{alias}' lists an unknown model '{model}'");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The models tried for the requested one, in order, `None` when it has no ladder. A model
+/// id missing from its own ladder is tried first.
+pub fn ladder(escalations: &Escalations, model: &str) -> Option<Vec<String>> {
+    let ladder = escalations.get(model)?;
+    let mut output = vec![];
+    if model.contains(':') && !ladder.iter().any(|v| v == model) {
+        output.push(model.to_string());
+    }
+    output.extend(ladder.iter().cloned());
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ladder() {
+        let escalations: Escalations = serde_yaml::from_str(
+            r#"
+long: [openai:gpt-4o-mini, openai:gpt-4o]
+openai:gpt-4o-mini: [openai:gpt-4o, gemini:gemini-1.5-pro]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ladder(&escalations, "long").unwrap(),
+            vec!["openai:gpt-4o-mini", "openai:gpt-4o"]
+        );
+        assert_eq!(
+            ladder(&escalations, "openai:gpt-4o-mini").unwrap(),
+            vec![
+                "openai:gpt-4o-mini",
+                "openai:gpt-4o",
+                "gemini:gemini-1.5-pro"
+            ]
+        );
+        assert_eq!(ladder(&escalations, "openai:gpt-4o"), None);
+
+        let models = vec![
+            Model::new("openai", "gpt-4o-mini"),
+            Model::new("openai", "gpt-4o"),
+        ];
+        assert!(validate(&escalations, &models).is_err());
+        assert!(validate(
+            &IndexMap::from([("long".into(), escalations["long"].clone())]),
+            &models
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,890 @@
+use crate::{
+    client::*,
+    config::{Config, GlobalConfig},
+    rag::cosine_similarity,
+    utils::CODE_BLOCK_RE,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use fancy_regex::Regex;
+use futures_util::{stream, StreamExt};
+use indexmap::IndexMap;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// Describes an evaluation run, loaded from a YAML file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvalConfig {
+    /// JSONL dataset, relative paths are resolved from the eval file
+    pub dataset: PathBuf,
+    /// The model ids to evaluate, defaults to the configured model
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub scorers: Vec<ScorerConfig>,
+    /// Where to write the JSON report
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScorerConfig {
+    Exact {
+        #[serde(default)]
+        ignore_case: bool,
+    },
+    Regex {
+        pattern: Option<String>,
+    },
+    JsonSchema {
+        schema: Option<Value>,
+    },
+    Similarity {
+        model: String,
+        threshold: Option<f64>,
+    },
+}
+
+/// A line of the dataset.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvalRecord {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub messages: Vec<Message>,
+    /// The expected output, used by the `exact` and `similarity` scorers
+    #[serde(default)]
+    pub expected: Option<String>,
+    /// Overrides the pattern of the `regex` scorer
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Overrides the schema of the `json_schema` scorer
+    #[serde(default)]
+    pub schema: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Score {
+    pub value: f64,
+    pub passed: bool,
+}
+
+impl Score {
+    fn from_bool(passed: bool) -> Self {
+        Self {
+            value: if passed { 1.0 } else { 0.0 },
+            passed,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Scorer: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Score an output, `None` when the record has nothing to compare against.
+    async fn score(&self, record: &EvalRecord, output: &str) -> Result<Option<Score>>;
+}
+
+pub fn create_scorer(config: &GlobalConfig, scorer: &ScorerConfig) -> Result<Box<dyn Scorer>> {
+    let scorer: Box<dyn Scorer> = match scorer {
+        ScorerConfig::Exact { ignore_case } => Box::new(ExactScorer {
+            ignore_case: *ignore_case,
+        }),
+        ScorerConfig::Regex { pattern } => {
+            let pattern = pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| "Invalid regex scorer pattern")?;
+            Box::new(RegexScorer { pattern })
+        }
+        ScorerConfig::JsonSchema { schema } => Box::new(JsonSchemaScorer {
+            schema: schema.clone(),
+        }),
+        ScorerConfig::Similarity { model, threshold } => {
+            let embedding_model = Model::find(&list_embedding_models(&config.read()), model)
+                .ok_or_else(|| anyhow!("No embedding model '{model}'"))?;
+            Box::new(SimilarityScorer {
+                client: init_client(config, Some(embedding_model))?,
+                threshold: threshold.unwrap_or(DEFAULT_SIMILARITY_THRESHOLD),
+            })
+        }
+    };
+    Ok(scorer)
+}
+
+struct ExactScorer {
+    ignore_case: bool,
+}
+
+#[async_trait]
+impl Scorer for ExactScorer {
+    fn name(&self) -> &str {
+        "exact"
+    }
+
+    async fn score(&self, record: &EvalRecord, output: &str) -> Result<Option<Score>> {
+        let Some(expected) = &record.expected else {
+            return Ok(None);
+        };
+        let (expected, output) = (expected.trim(), output.trim());
+        let passed = if self.ignore_case {
+            expected.to_lowercase() == output.to_lowercase()
+        } else {
+            expected == output
+        };
+        Ok(Some(Score::from_bool(passed)))
+    }
+}
+
+struct RegexScorer {
+    pattern: Option<Regex>,
+}
+
+#[async_trait]
+impl Scorer for RegexScorer {
+    fn name(&self) -> &str {
+        "regex"
+    }
+
+    async fn score(&self, record: &EvalRecord, output: &str) -> Result<Option<Score>> {
+        let pattern = match &record.pattern {
+            Some(pattern) => {
+                Regex::new(pattern).with_context(|| format!("Invalid regex pattern '{pattern}'"))?
+            }
+            None => match &self.pattern {
+                Some(pattern) => pattern.clone(),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(Score::from_bool(pattern.is_match(output)?)))
+    }
+}
+
+struct JsonSchemaScorer {
+    schema: Option<Value>,
+}
+
+#[async_trait]
+impl Scorer for JsonSchemaScorer {
+    fn name(&self) -> &str {
+        "json_schema"
+    }
+
+    async fn score(&self, record: &EvalRecord, output: &str) -> Result<Option<Score>> {
+        let Some(schema) = record.schema.as_ref().or(self.schema.as_ref()) else {
+            return Ok(None);
+        };
+        let passed = match extract_json(output) {
+            Some(value) => validate_json_schema(&value, schema).is_ok(),
+            None => false,
+        };
+        Ok(Some(Score::from_bool(passed)))
+    }
+}
+
+struct SimilarityScorer {
+    client: Box<dyn Client>,
+    threshold: f64,
+}
+
+#[async_trait]
+impl Scorer for SimilarityScorer {
+    fn name(&self) -> &str {
+        "similarity"
+    }
+
+    async fn score(&self, record: &EvalRecord, output: &str) -> Result<Option<Score>> {
+        let Some(expected) = &record.expected else {
+            return Ok(None);
+        };
+        let texts = [expected.clone(), output.to_string()];
+        let batch_size = self.client.model().max_concurrent_chunks().max(1);
+        let mut embeddings = vec![];
+        for batch in texts.chunks(batch_size) {
+            let data = EmbeddingsData::new(batch.to_vec(), false);
+            embeddings.extend(self.client.embeddings(data).await?);
+        }
+        let [a, b] = embeddings.as_slice() else {
+            bail!("Invalid embeddings response");
+        };
+        let value = cosine_similarity(a, b);
+        Ok(Some(Score {
+            value,
+            passed: value >= self.threshold,
+        }))
+    }
+}
+
+/// Run the evaluation described in the given file and write its report.
+pub async fn run(config: GlobalConfig, path: &Path) -> Result<()> {
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to read eval file at '{}'", path.display()))?;
+    let eval_config: EvalConfig = serde_yaml::from_str(&content)
+        .with_context(|| format!("Failed to load eval file at '{}'", path.display()))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let dataset_path = base_dir.join(&eval_config.dataset);
+    let records = load_dataset(&dataset_path)?;
+
+    let models = if eval_config.models.is_empty() {
+        vec![config.read().model.id()]
+    } else {
+        eval_config.models.clone()
+    };
+    let scorers = eval_config
+        .scorers
+        .iter()
+        .map(|v| create_scorer(&config, v))
+        .collect::<Result<Vec<_>>>()?;
+    let concurrency = eval_config
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .max(1);
+    let tokenizers = config.read().tokenizers.clone();
+    tokenizer::sync(&tokenizers).await;
+
+    let mut results = vec![];
+    for model_id in &models {
+        let client = init_chat_client(&config, model_id)?;
+        let http_client = client.build_client()?;
+        info!("Evaluating {model_id} on {} records", records.len());
+        let model_results: Vec<EvalResult> = stream::iter(records.iter().enumerate())
+            .map(|(index, record)| {
+                let data = ChatCompletionsData {
+                    messages: record.messages.clone(),
+                    temperature: eval_config.temperature,
+                    top_p: eval_config.top_p,
+                    functions: None,
+                    stream: false,
+                };
+                let (client, http_client, scorers) = (&client, &http_client, &scorers);
+                async move {
+                    let started = Instant::now();
+                    let ret = client.chat_completions_inner(http_client, data).await;
+                    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+                    let mut result = EvalResult::new(model_id, record, index, latency_ms);
+                    match ret {
+                        Ok(output) => {
+                            result.set_output(client.model(), record, &output);
+                            for scorer in scorers {
+                                match scorer.score(record, &output.text).await {
+                                    Ok(Some(score)) => {
+                                        result.scores.insert(scorer.name().to_string(), score);
+                                    }
+                                    Ok(None) => {}
+                                    Err(err) => warn!(
+                                        "Failed to score record {} with {}, {err}",
+                                        result.record,
+                                        scorer.name()
+                                    ),
+                                }
+                            }
+                        }
+                        Err(err) => result.error = Some(format!("{err:?}")),
+                    }
+                    result
+                }
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+        results.extend(model_results);
+    }
+
+    let summaries: Vec<Value> = models
+        .iter()
+        .map(|model_id| {
+            let model_results: Vec<&EvalResult> =
+                results.iter().filter(|v| &v.model == model_id).collect();
+            summarize(model_id, &model_results)
+        })
+        .collect();
+    print_summaries(&summaries);
+
+    let report = json!({
+        "dataset": dataset_path,
+        "records": records.len(),
+        "models": summaries,
+        "results": results.iter().map(|v| v.to_json()).collect::<Vec<_>>(),
+    });
+    if let Some(output) = &eval_config.output {
+        let output = base_dir.join(output);
+        write(&output, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write eval report to '{}'", output.display()))?;
+        println!("Report written to {}", output.display());
+    }
+    Ok(())
+}
+
+fn load_dataset(path: &Path) -> Result<Vec<EvalRecord>> {
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to read dataset at '{}'", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid record at line {} of the dataset", i + 1))
+        })
+        .collect()
+}
+
+fn init_chat_client(config: &GlobalConfig, model_id: &str) -> Result<Box<dyn Client>> {
+    let config = {
+        let config = config.read();
+        Config {
+            clients: config.clients.clone(),
+            model: config.model.clone(),
+            ..Default::default()
+        }
+    };
+    let config = Arc::new(RwLock::new(config));
+    config.write().set_model(model_id)?;
+    init_client(&config, None)
+}
+
+#[derive(Debug)]
+struct EvalResult {
+    model: String,
+    record: String,
+    output: Option<String>,
+    error: Option<String>,
+    latency_ms: f64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost: Option<f64>,
+    estimated: bool,
+    scores: IndexMap<String, Score>,
+}
+
+impl EvalResult {
+    fn new(model: &str, record: &EvalRecord, index: usize, latency_ms: f64) -> Self {
+        Self {
+            model: model.to_string(),
+            record: record.id.clone().unwrap_or_else(|| index.to_string()),
+            output: None,
+            error: None,
+            latency_ms,
+            input_tokens: 0,
+            output_tokens: 0,
+            cost: None,
+            estimated: false,
+            scores: IndexMap::new(),
+        }
+    }
+
+    fn set_output(&mut self, model: &Model, record: &EvalRecord, output: &ChatCompletionsOutput) {
+        let input_tokens = model.input_tokens(&record.messages) as u64;
+        let usage = CallUsage::new(model, input_tokens, output);
+        self.input_tokens = usage.input_tokens;
+        self.output_tokens = usage.output_tokens;
+        self.cost = usage.cost;
+        self.estimated = usage.estimated;
+        self.output = Some(output.text.clone());
+    }
+
+    /// Whether the record passed all the applicable scorers, `None` when none applied.
+    fn passed(&self) -> Option<bool> {
+        if self.error.is_some() {
+            return Some(false);
+        }
+        if self.scores.is_empty() {
+            return None;
+        }
+        Some(self.scores.values().all(|v| v.passed))
+    }
+
+    fn to_json(&self) -> Value {
+        let scores: IndexMap<&str, Value> = self
+            .scores
+            .iter()
+            .map(|(name, score)| {
+                (
+                    name.as_str(),
+                    json!({ "score": score.value, "passed": score.passed }),
+                )
+            })
+            .collect();
+        json!({
+            "model": self.model,
+            "record": self.record,
+            "output": self.output,
+            "error": self.error,
+            "latency_ms": self.latency_ms,
+            "input_tokens": self.input_tokens,
+            "output_tokens": self.output_tokens,
+            "cost": self.cost,
+            "estimated": self.estimated,
+            "scores": scores,
+            "passed": self.passed(),
+        })
+    }
+}
+
+fn summarize(model_id: &str, results: &[&EvalResult]) -> Value {
+    let graded: Vec<bool> = results.iter().filter_map(|v| v.passed()).collect();
+    let accuracy = if graded.is_empty() {
+        None
+    } else {
+        Some(graded.iter().filter(|v| **v).count() as f64 / graded.len() as f64)
+    };
+    let mut scores: IndexMap<&str, Vec<f64>> = IndexMap::new();
+    for result in results {
+        for (name, score) in &result.scores {
+            scores.entry(name).or_default().push(score.value);
+        }
+    }
+    let scores: IndexMap<&str, f64> = scores
+        .into_iter()
+        .map(|(name, values)| (name, values.iter().sum::<f64>() / values.len() as f64))
+        .collect();
+    let mut latencies: Vec<f64> = results.iter().map(|v| v.latency_ms).collect();
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let costs: Vec<f64> = results.iter().filter_map(|v| v.cost).collect();
+    json!({
+        "model": model_id,
+        "records": results.len(),
+        "errors": results.iter().filter(|v| v.error.is_some()).count(),
+        "accuracy": accuracy,
+        "scores": scores,
+        "input_tokens": results.iter().map(|v| v.input_tokens).sum::<u64>(),
+        "output_tokens": results.iter().map(|v| v.output_tokens).sum::<u64>(),
+        "cost": if costs.is_empty() { None } else { Some(costs.iter().sum::<f64>()) },
+        "latency_ms": {
+            "mean": latencies.iter().sum::<f64>() / latencies.len().max(1) as f64,
+            "p50": percentile(&latencies, 0.5),
+            "p95": percentile(&latencies, 0.95),
+        },
+    })
+}
+
+fn print_summaries(summaries: &[Value]) {
+    println!(
+        "{:<40} {:>8} {:>8} {:>10} {:>12} {:>10}",
+        "MODEL", "ACCURACY", "ERRORS", "TOKENS", "COST", "P50 (ms)"
+    );
+    for summary in summaries {
+        let accuracy = summary["accuracy"]
+            .as_f64()
+            .map(|v| format!("{:.1}%", v * 100.0))
+            .unwrap_or_else(|| "-".into());
+        let cost = summary["cost"]
+            .as_f64()
+            .map(|v| format!("${v:.6}"))
+            .unwrap_or_else(|| "-".into());
+        let tokens = summary["input_tokens"].as_u64().unwrap_or_default()
+            + summary["output_tokens"].as_u64().unwrap_or_default();
+        println!(
+            "{:<40} {:>8} {:>8} {:>10} {:>12} {:>10.0}",
+            summary["model"].as_str().unwrap_or_default(),
+            accuracy,
+            summary["errors"],
+            tokens,
+            cost,
+            summary["latency_ms"]["p50"].as_f64().unwrap_or_default(),
+        );
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+/// Parse the output as JSON, falling back to the content of a markdown code block.
+fn extract_json(output: &str) -> Option<Value> {
+    if let Ok(value) = serde_json::from_str(output.trim()) {
+        return Some(value);
+    }
+    let captures = CODE_BLOCK_RE.captures(output).ok()??;
+    serde_json::from_str(captures.get(1)?.as_str().trim()).ok()
+}
+
+/// Validate a value against the commonly used subset of JSON Schema: `type`, `enum`,
+/// `const`, `properties`, `required`, `additionalProperties`, `items` and the length
+/// and range keywords.
+pub fn validate_json_schema(value: &Value, schema: &Value) -> Result<(), String> {
+    validate_at(value, schema, "$")
+}
+
+fn validate_at(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return match schema {
+            Value::Bool(false) => Err(format!("{path}: not allowed")),
+            _ => Ok(()),
+        };
+    };
+    if let Some(typ) = schema.get("type") {
+        let types: Vec<&str> = match typ {
+            Value::String(v) => vec![v.as_str()],
+            Value::Array(v) => v.iter().filter_map(|v| v.as_str()).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|typ| is_json_type(value, typ)) {
+            return Err(format!("{path}: expected {}", types.join(" or ")));
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(|v| v.as_array()) {
+        if !values.contains(value) {
+            return Err(format!("{path}: not one of the enum values"));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{path}: expected {expected}"));
+        }
+    }
+    let get_f64 = |key: &str| schema.get(key).and_then(|v| v.as_f64());
+    let get_usize = |key: &str| schema.get(key).and_then(|v| v.as_u64()).map(|v| v as usize);
+    match value {
+        Value::Object(object) => {
+            if let Some(required) = schema.get("required").and_then(|v| v.as_array()) {
+                for key in required.iter().filter_map(|v| v.as_str()) {
+                    if !object.contains_key(key) {
+                        return Err(format!("{path}: missing property '{key}'"));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(|v| v.as_object());
+            for (key, child) in object {
+                let child_path = format!("{path}.{key}");
+                match properties.and_then(|v| v.get(key)) {
+                    Some(child_schema) => validate_at(child, child_schema, &child_path)?,
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate_at(child, additional, &child_path)?;
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = get_usize("minItems") {
+                if items.len() < min {
+                    return Err(format!("{path}: expected at least {min} items"));
+                }
+            }
+            if let Some(max) = get_usize("maxItems") {
+                if items.len() > max {
+                    return Err(format!("{path}: expected at most {max} items"));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item, item_schema, &format!("{path}[{i}]"))?;
+                }
+            }
+        }
+        Value::String(text) => {
+            let len = text.chars().count();
+            if let Some(min) = get_usize("minLength") {
+                if len < min {
+                    return Err(format!("{path}: expected at least {min} characters"));
+                }
+            }
+            if let Some(max) = get_usize("maxLength") {
+                if len > max {
+                    return Err(format!("{path}: expected at most {max} characters"));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(min) = get_f64("minimum") {
+                if number < min {
+                    return Err(format!("{path}: expected a minimum of {min}"));
+                }
+            }
+            if let Some(max) = get_f64("maximum") {
+                if number > max {
+                    return Err(format!("{path}: expected a maximum of {max}"));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_json_type(value: &Value, typ: &str) -> bool {
+    match typ {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().is_some_and(|v| v.fract() == 0.0),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_config() -> GlobalConfig {
+        let mut config: Config = serde_json::from_value(json!({
+            "model": "mock:echo",
+            "clients": [{
+                "type": "mock",
+                "models": [
+                    { "name": "echo" },
+                    { "name": "paris", "input_price": 1.0, "output_price": 2.0 },
+                    { "name": "embed", "mode": "embedding" },
+                ],
+                "responses": {
+                    "paris": {
+                        "type": "text",
+                        "text": "Paris",
+                        "usage": { "input_tokens": 10, "output_tokens": 2 },
+                    },
+                },
+            }],
+        }))
+        .unwrap();
+        config.set_model("mock:echo").unwrap();
+        Arc::new(RwLock::new(config))
+    }
+
+    fn record(expected: Option<&str>, pattern: Option<&str>) -> EvalRecord {
+        EvalRecord {
+            id: None,
+            messages: vec![],
+            expected: expected.map(|v| v.to_string()),
+            pattern: pattern.map(|v| v.to_string()),
+            schema: None,
+        }
+    }
+
+    async fn score(scorer: &dyn Scorer, record: &EvalRecord, output: &str) -> Option<bool> {
+        scorer
+            .score(record, output)
+            .await
+            .unwrap()
+            .map(|v| v.passed)
+    }
+
+    #[tokio::test]
+    async fn test_scorers() {
+        let config = mock_config();
+        let paris = record(Some("Paris"), None);
+
+        let scorer = create_scorer(&config, &ScorerConfig::Exact { ignore_case: false }).unwrap();
+        assert_eq!(scorer.name(), "exact");
+        assert_eq!(score(scorer.as_ref(), &paris, " Paris\n").await, Some(true));
+        assert_eq!(score(scorer.as_ref(), &paris, "paris").await, Some(false));
+        let unscored = record(None, None);
+        assert_eq!(score(scorer.as_ref(), &unscored, "Paris").await, None);
+        let scorer = create_scorer(&config, &ScorerConfig::Exact { ignore_case: true }).unwrap();
+        assert_eq!(score(scorer.as_ref(), &paris, "paris").await, Some(true));
+
+        let scorer = create_scorer(
+            &config,
+            &ScorerConfig::Regex {
+                pattern: Some(r"^\d+$".into()),
+            },
+        )
+        .unwrap();
+        assert_eq!(scorer.name(), "regex");
+        assert_eq!(score(scorer.as_ref(), &paris, "42").await, Some(true));
+        assert_eq!(score(scorer.as_ref(), &paris, "Paris").await, Some(false));
+        // The pattern of the record overrides the scorer's
+        let prefix = record(None, Some("^Par"));
+        assert_eq!(score(scorer.as_ref(), &prefix, "Paris").await, Some(true));
+        let scorer = create_scorer(&config, &ScorerConfig::Regex { pattern: None }).unwrap();
+        assert_eq!(score(scorer.as_ref(), &paris, "42").await, None);
+        assert!(create_scorer(
+            &config,
+            &ScorerConfig::Regex {
+                pattern: Some("(".into()),
+            },
+        )
+        .is_err());
+
+        let scorer = create_scorer(
+            &config,
+            &ScorerConfig::Similarity {
+                model: "mock:embed".into(),
+                threshold: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(scorer.name(), "similarity");
+        let similar = scorer.score(&paris, "paris").await.unwrap().unwrap();
+        assert!(similar.passed && (similar.value - 1.0).abs() < 1e-6);
+        assert_eq!(score(scorer.as_ref(), &paris, "Rome").await, Some(false));
+        assert!(create_scorer(
+            &config,
+            &ScorerConfig::Similarity {
+                model: "openai:text-embedding-3-small".into(),
+                threshold: None,
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let result = |latency_ms: f64, passed: Option<bool>, cost: Option<f64>| {
+            let mut result = EvalResult::new("mock:echo", &record(None, None), 0, latency_ms);
+            result.input_tokens = 10;
+            result.output_tokens = 5;
+            result.cost = cost;
+            match passed {
+                Some(passed) => {
+                    let score = Score::from_bool(passed);
+                    result.scores.insert("exact".into(), score);
+                }
+                None => result.error = Some("failed".into()),
+            }
+            result
+        };
+        let results = [
+            result(300.0, Some(true), Some(0.25)),
+            result(100.0, Some(false), Some(0.5)),
+            result(200.0, None, None),
+        ];
+        let summary = summarize("mock:echo", &results.iter().collect::<Vec<_>>());
+        assert_eq!(summary["records"], 3);
+        assert_eq!(summary["errors"], 1);
+        // A failed call counts as a failed record
+        assert_eq!(summary["accuracy"], 1.0 / 3.0);
+        assert_eq!(summary["scores"]["exact"], 0.5);
+        assert_eq!(summary["input_tokens"], 30);
+        assert_eq!(summary["output_tokens"], 15);
+        assert_eq!(summary["cost"], 0.75);
+        assert_eq!(summary["latency_ms"]["mean"], 200.0);
+        assert_eq!(summary["latency_ms"]["p50"], 200.0);
+        assert_eq!(summary["latency_ms"]["p95"], 300.0);
+
+        let summary = summarize("mock:echo", &[]);
+        assert_eq!(summary["accuracy"], Value::Null);
+        assert_eq!(summary["cost"], Value::Null);
+        assert_eq!(summary["latency_ms"]["mean"], 0.0);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<f64> = (1..=10).map(|v| v as f64).collect();
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 0.5), 6.0);
+        assert_eq!(percentile(&values, 0.95), 10.0);
+        assert_eq!(percentile(&values, 1.0), 10.0);
+        assert_eq!(percentile(&[7.0], 0.95), 7.0);
+        assert_eq!(percentile(&[], 0.5), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dir = std::env::temp_dir().join(format!("agent-panel-eval-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("dataset.jsonl"),
+            [
+                json!({
+                    "id": "capital",
+                    "messages": [{ "role": "user", "content": "Paris" }],
+                    "expected": "Paris",
+                }),
+                json!({
+                    "messages": [{ "role": "user", "content": "Rome?" }],
+                    "expected": "Rome",
+                }),
+            ]
+            .map(|v| v.to_string())
+            .join("\n"),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("eval.yaml"),
+            "dataset: dataset.jsonl\nmodels: [mock:echo, mock:paris]\nscorers:\n  - type: exact\noutput: report.json\n",
+        )
+        .unwrap();
+
+        run(mock_config(), &dir.join("eval.yaml")).await.unwrap();
+        let report: Value =
+            serde_json::from_str(&read_to_string(dir.join("report.json")).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report["records"], 2);
+        let results = report["results"].as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        let passed = |model: &str, record: &str| {
+            results
+                .iter()
+                .find(|v| v["model"] == model && v["record"] == record)
+                .map(|v| v["passed"].clone())
+                .unwrap()
+        };
+        assert_eq!(passed("mock:echo", "capital"), true);
+        assert_eq!(passed("mock:echo", "1"), false);
+        assert_eq!(passed("mock:paris", "capital"), true);
+        assert_eq!(passed("mock:paris", "1"), false);
+
+        let summaries = report["models"].as_array().unwrap();
+        assert_eq!(summaries[0]["model"], "mock:echo");
+        assert_eq!(summaries[0]["accuracy"], 0.5);
+        assert_eq!(summaries[0]["cost"], Value::Null);
+        // The usage reported by the model is priced per million tokens
+        assert_eq!(summaries[1]["model"], "mock:paris");
+        assert_eq!(summaries[1]["input_tokens"], 20);
+        assert_eq!(summaries[1]["output_tokens"], 4);
+        assert_eq!(summaries[1]["cost"], (20.0 * 1.0 + 4.0 * 2.0) / 1e6);
+    }
+
+    #[test]
+    fn test_validate_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } },
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+        });
+        assert!(validate_json_schema(&json!({ "name": "x", "tags": ["a"] }), &schema).is_ok());
+        assert!(validate_json_schema(&json!({ "tags": [] }), &schema).is_err());
+        assert!(validate_json_schema(&json!({ "name": "x", "tags": ["c"] }), &schema).is_err());
+        assert!(validate_json_schema(&json!({ "name": "x", "other": 1 }), &schema).is_err());
+        assert!(validate_json_schema(&json!({ "name": 1 }), &schema).is_err());
+
+        let schema = json!({ "type": "integer" });
+        assert!(validate_json_schema(&json!(3), &schema).is_ok());
+        assert!(validate_json_schema(&json!(3.0), &schema).is_ok());
+        assert!(validate_json_schema(&json!(3.5), &schema).is_err());
+    }
+
+    #[test]
+    fn test_extract_json() {
+        assert_eq!(extract_json(r#" {"a": 1} "#), Some(json!({ "a": 1 })));
+        assert_eq!(
+            extract_json("Here you go:\n```json\n{\"a\": 1}\n```"),
+            Some(json!({ "a": 1 }))
+        );
+        assert_eq!(extract_json("no json"), None);
+    }
+}
@@ -1,19 +1,30 @@
-use anyhow::{Context, Result};
+use crate::mcp;
+
+use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use indexmap::{IndexMap, IndexSet};
 use inquire::{validator::Validation, Text};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::{
     collections::HashSet,
-    fs,
-    path::Path,
+    env, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
 };
 use threadpool::ThreadPool;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    process::Command,
+};
 
 const BIN_DIR_NAME: &str = "bin";
 const DECLARATIONS_FILE_PATH: &str = "functions.json";
+const DEFAULT_MAX_STEPS: usize = 8;
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
 
 lazy_static! {
     static ref THREAD_POOL: ThreadPool = ThreadPool::new(num_cpus::get());
@@ -41,11 +52,38 @@ impl ToolCallResult {
 pub struct Function {
     names: IndexSet<String>,
     declarations: Vec<FunctionDeclaration>,
-    #[cfg(windows)]
-    bin_dir: std::path::PathBuf,
+    bin_dir: PathBuf,
     env_path: Option<String>,
 }
 
+/// Limits of the functions run by the gateway on behalf of the models.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct ToolsConfig {
+    /// Most model calls of a single tool loop
+    pub max_steps: Option<usize>,
+    /// Seconds after which a function is killed
+    pub timeout: Option<u64>,
+    /// Bytes of output kept from a function, the rest being discarded
+    pub max_output_bytes: Option<usize>,
+    /// Environment variables passed through to the functions, which only get PATH otherwise
+    pub env: Vec<String>,
+}
+
+impl ToolsConfig {
+    pub fn max_steps(&self) -> usize {
+        self.max_steps.unwrap_or(DEFAULT_MAX_STEPS).max(1)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+
+    pub fn max_output_bytes(&self) -> usize {
+        self.max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES)
+    }
+}
+
 impl Function {
     pub fn init(functions_dir: &Path) -> Result<Self> {
         let bin_dir = functions_dir.join(BIN_DIR_NAME);
@@ -75,7 +113,6 @@ impl Function {
         Ok(Self {
             names: func_names,
             declarations,
-            #[cfg(windows)]
             bin_dir,
             env_path,
         })
@@ -86,8 +123,9 @@ impl Function {
         let output: Vec<FunctionDeclaration> = self
             .declarations
             .iter()
-            .filter(|v| regex.is_match(&v.name).unwrap_or_default())
             .cloned()
+            .chain(mcp::declarations())
+            .filter(|v| regex.is_match(&v.name).unwrap_or_default())
             .collect();
         if output.is_empty() {
             None
@@ -95,6 +133,84 @@ impl Function {
             Some(output)
         }
     }
+
+    /// Run the executable of the called function with the JSON arguments, returning its
+    /// output, or an `error` object the model can react to.
+    pub async fn execute(&self, call: &ToolCall, config: &ToolsConfig) -> Value {
+        match self.run(call, config).await {
+            Ok(output) => output,
+            Err(err) => json!({ "error": format!("{err:#}") }),
+        }
+    }
+
+    async fn run(&self, call: &ToolCall, config: &ToolsConfig) -> Result<Value> {
+        if !self.names.contains(&call.name) {
+            return mcp::call_tool(&call.name, &call.arguments, config).await;
+        }
+        #[cfg(windows)]
+        let program = polyfill_cmd_name(&call.name, &self.bin_dir);
+        #[cfg(not(windows))]
+        let program = self.bin_dir.join(&call.name);
+        let arguments = match &call.arguments {
+            Value::String(arguments) => arguments.clone(),
+            arguments => arguments.to_string(),
+        };
+        let mut command = Command::new(program);
+        command
+            .arg(arguments)
+            .env_clear()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(env_path) = &self.env_path {
+            command.env("PATH", env_path);
+        }
+        for name in &config.env {
+            if let Ok(value) = env::var(name) {
+                command.env(name, value);
+            }
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run function '{}'", call.name))?;
+
+        let limit = config.max_output_bytes();
+        let mut stdout = child.stdout.take().context("No stdout")?;
+        let mut stderr = child.stderr.take().context("No stderr")?;
+        // Dropping the child on timeout kills it.
+        let wait = async move {
+            let (stdout, stderr) = tokio::try_join!(
+                read_limited(&mut stdout, limit),
+                read_limited(&mut stderr, limit)
+            )?;
+            let status = child.wait().await?;
+            Ok::<_, anyhow::Error>((status, stdout, stderr))
+        };
+        let (status, (stdout, truncated), (stderr, _)) =
+            tokio::time::timeout(config.timeout(), wait)
+                .await
+                .map_err(|_| anyhow!("Timed out after {}s", config.timeout().as_secs()))??;
+
+        if !status.success() {
+            bail!("Exited with {status}: {}", stderr.trim());
+        }
+        if truncated {
+            return Ok(format!("{stdout}\n[output truncated to {limit} bytes]").into());
+        }
+        Ok(serde_json::from_str(&stdout).unwrap_or_else(|_| stdout.trim().into()))
+    }
+}
+
+/// Read up to `limit` bytes, discarding the rest so that the writer never blocks.
+async fn read_limited<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> Result<(String, bool)> {
+    let mut buf = vec![];
+    reader.take(limit as u64).read_to_end(&mut buf).await?;
+    let truncated = io::copy(reader, &mut io::sink()).await? > 0;
+    Ok((String::from_utf8_lossy(&buf).into_owned(), truncated))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -119,6 +235,8 @@ pub struct JsonSchema {
     pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub properties: Option<IndexMap<String, JsonSchema>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<JsonSchema>>,
     #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
     pub enum_value: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -186,3 +304,150 @@ fn polyfill_cmd_name(name: &str, bin_dir: &std::path::Path) -> String {
     }
     name
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    /// Create a functions dir whose executables are the given shell scripts.
+    fn functions_dir(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("agent-panel-{name}-{}", std::process::id()));
+        let bin_dir = dir.join(BIN_DIR_NAME);
+        fs::create_dir_all(&bin_dir).unwrap();
+        let mut declarations = vec![];
+        for (name, script) in scripts {
+            let path = bin_dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            declarations.push(json!({
+                "name": name,
+                "description": "",
+                "parameters": { "type": "object", "properties": {} },
+            }));
+        }
+        fs::write(
+            dir.join(DECLARATIONS_FILE_PATH),
+            json!(declarations).to_string(),
+        )
+        .unwrap();
+        dir
+    }
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        ToolCall::new(name.into(), arguments, None)
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let dir = functions_dir(
+            "execute",
+            &[
+                ("echo_args", r#"printf '%s' "$1""#),
+                ("fail", "echo oops >&2; exit 3"),
+                ("big", "head -c 100000 /dev/zero | tr '\\0' a"),
+                ("show_env", "env"),
+            ],
+        );
+        let function = Function::init(&dir).unwrap();
+        let config = ToolsConfig::default();
+
+        let arguments = json!({ "city": "Paris", "days": 2 });
+        assert_eq!(
+            function
+                .execute(&call("echo_args", arguments.clone()), &config)
+                .await,
+            arguments
+        );
+        assert_eq!(
+            function
+                .execute(&call("echo_args", arguments.to_string().into()), &config)
+                .await,
+            arguments
+        );
+        assert_eq!(
+            function.execute(&call("fail", json!({})), &config).await,
+            json!({ "error": "Exited with exit status: 3: oops" })
+        );
+        let limited = ToolsConfig {
+            max_output_bytes: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            function.execute(&call("big", json!({})), &limited).await,
+            json!("aaaaaaaaaa\n[output truncated to 10 bytes]")
+        );
+
+        env::set_var("AGENT_PANEL_TEST_PASSED", "yes");
+        env::set_var("AGENT_PANEL_TEST_HIDDEN", "no");
+        let output = function
+            .execute(&call("show_env", json!({})), &config)
+            .await;
+        let names: Vec<&str> = output
+            .as_str()
+            .unwrap()
+            .lines()
+            .filter_map(|v| v.split_once('=').map(|(name, _)| name))
+            .collect();
+        assert!(names.contains(&"PATH"));
+        assert!(!names.contains(&"AGENT_PANEL_TEST_HIDDEN"));
+        assert!(!names.contains(&"HOME"));
+        // The shell sets its own PWD, SHLVL and _
+        assert!(names
+            .iter()
+            .all(|v| matches!(*v, "PATH" | "PWD" | "SHLVL" | "_")));
+        let config = ToolsConfig {
+            env: vec!["AGENT_PANEL_TEST_PASSED".into()],
+            ..Default::default()
+        };
+        let output = function
+            .execute(&call("show_env", json!({})), &config)
+            .await;
+        assert!(output
+            .as_str()
+            .unwrap()
+            .contains("AGENT_PANEL_TEST_PASSED=yes"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout() {
+        let dir = functions_dir(
+            "timeout",
+            &[("slow", "sleep 2; touch \"$(dirname \"$0\")/done\"")],
+        );
+        let function = Function::init(&dir).unwrap();
+        let config = ToolsConfig {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert_eq!(
+            function.execute(&call("slow", json!({})), &config).await,
+            json!({ "error": "Timed out after 1s" })
+        );
+        assert!(started.elapsed() < Duration::from_millis(1900));
+        // The killed script never gets to write its file
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!dir.join(BIN_DIR_NAME).join("done").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_limited() {
+        let mut reader: &[u8] = b"hello world";
+        assert_eq!(
+            read_limited(&mut reader, 5).await.unwrap(),
+            ("hello".into(), true)
+        );
+        let mut reader: &[u8] = b"hello";
+        assert_eq!(
+            read_limited(&mut reader, 5).await.unwrap(),
+            ("hello".into(), false)
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::client::{
+    catch_error, init_client, list_models, send_request, ChatCompletionsData, ClientConfig,
+    EmbeddingsData, Message, MessageContent, MessageRole, Model, RerankData,
+};
+use crate::config::Config;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{SecondsFormat, Utc};
+use fancy_regex::Regex;
+use futures_util::future::join_all;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_PROBE_INTERVAL: u64 = 300;
+const DEFAULT_PROBE_TIMEOUT: u64 = 10;
+const PROBE_PROMPT: &str = "ping";
+
+lazy_static! {
+    static ref STATUSES: RwLock<IndexMap<String, ProviderStatus>> = RwLock::new(IndexMap::new());
+    static ref AUTH_ERROR_RE: Regex = Regex::new(
+        r"(?i)unauthori[sz]ed|authenticat|forbidden|permission|access denied|(invalid|incorrect).{0,20}(key|token|credential)|miss '\w*(key|token)\w*'|status: 40[13]\b"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds between two probes of the clients, 0 disables the probes
+    pub probe_interval: Option<u64>,
+    /// Seconds after which a probe is considered failed
+    pub probe_timeout: Option<u64>,
+    /// Probe the clients without an endpoint listing their models with a paid call
+    pub probe_paid: bool,
+}
+
+impl HealthConfig {
+    pub fn probe_interval(&self) -> Option<Duration> {
+        match self.probe_interval.unwrap_or(DEFAULT_PROBE_INTERVAL) {
+            0 => None,
+            v => Some(Duration::from_secs(v)),
+        }
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_secs(self.probe_timeout.unwrap_or(DEFAULT_PROBE_TIMEOUT))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeOutcome {
+    Ok,
+    /// The provider answered but rejected the credentials
+    Unauthorized,
+    /// The provider could not be reached in time
+    Unreachable,
+    Error,
+    /// The client was not probed, its probe being a paid call
+    Unknown,
+}
+
+/// The result of the last probe of a client.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStatus {
+    pub client: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub model: Option<String>,
+    pub status: ProbeOutcome,
+    /// Unset when the client was not probed
+    pub reachable: Option<bool>,
+    pub authorized: Option<bool>,
+    /// Unset when the provider could not be reached or was not probed
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+    pub checked_at: String,
+}
+
+/// Probe every client concurrently, then replace the stored statuses.
+pub async fn probe_clients(clients: &[ClientConfig], config: &HealthConfig) {
+    let (timeout, paid) = (config.probe_timeout(), config.probe_paid);
+    let statuses = join_all(clients.iter().map(|v| probe_client(v, timeout, paid))).await;
+    *STATUSES.write() = statuses
+        .into_iter()
+        .map(|v| (v.client.clone(), v))
+        .collect();
+}
+
+pub fn statuses() -> Vec<ProviderStatus> {
+    STATUSES.read().values().cloned().collect()
+}
+
+/// The clients able to serve requests: having a model and not failing their last probe,
+/// clients that were not probed, yet or at all, being assumed usable.
+pub fn usable_clients(clients: &[ClientConfig]) -> Vec<String> {
+    let statuses = STATUSES.read();
+    clients
+        .iter()
+        .filter(|v| probe_model(v).is_some())
+        .filter(|v| {
+            statuses
+                .get(v.name())
+                .map(|status| matches!(status.status, ProbeOutcome::Ok | ProbeOutcome::Unknown))
+                .unwrap_or(true)
+        })
+        .map(|v| v.name().to_string())
+        .collect()
+}
+
+async fn probe_client(
+    client_config: &ClientConfig,
+    timeout: Duration,
+    paid: bool,
+) -> ProviderStatus {
+    let model = probe_model(client_config);
+    let started = Instant::now();
+    let ret = match &model {
+        Some(model) => tokio::time::timeout(timeout, probe(client_config, model, paid))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timed out after {}s", timeout.as_secs()))),
+        None => Err(anyhow!("No model to probe")),
+    };
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (status, error) = match ret {
+        Ok(true) => (ProbeOutcome::Ok, None),
+        Ok(false) => (ProbeOutcome::Unknown, None),
+        Err(err) => {
+            warn!(
+                "Probe of the client '{}' failed, {err:#}",
+                client_config.name()
+            );
+            (classify_error(&err), Some(format!("{err:#}")))
+        }
+    };
+    ProviderStatus {
+        client: client_config.name().to_string(),
+        kind: client_config.kind().to_string(),
+        model: model.map(|v| v.id()),
+        status,
+        reachable: match status {
+            ProbeOutcome::Unreachable => Some(false),
+            ProbeOutcome::Unknown => None,
+            _ => Some(true),
+        },
+        authorized: match status {
+            ProbeOutcome::Ok => Some(true),
+            ProbeOutcome::Unauthorized => Some(false),
+            _ => None,
+        },
+        latency_ms: Some(latency_ms)
+            .filter(|_| !matches!(status, ProbeOutcome::Unreachable | ProbeOutcome::Unknown)),
+        error,
+        checked_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    }
+}
+
+/// The model used to probe the client, chat models being preferred.
+fn probe_model(client_config: &ClientConfig) -> Option<Model> {
+    let config = Config {
+        clients: vec![client_config.clone()],
+        ..Default::default()
+    };
+    let models: Vec<Model> = list_models(&config)
+        .into_iter()
+        .filter(|v| !v.disabled())
+        .collect();
+    models
+        .iter()
+        .find(|v| v.mode() == "chat")
+        .or_else(|| models.iter().find(|v| v.mode() == "embedding"))
+        .or_else(|| models.iter().find(|v| v.mode() == "rerank"))
+        .cloned()
+}
+
+/// List the models of the provider when it has an endpoint for it, else send the cheapest
+/// request the model supports when paid probes are on: a one-token chat completion, or the
+/// embedding or reranking of a single word. Returns whether the client was probed.
+async fn probe(client_config: &ClientConfig, model: &Model, paid: bool) -> Result<bool> {
+    let config = Config {
+        clients: vec![client_config.clone()],
+        model: model.clone(),
+        ..Default::default()
+    };
+    let config = Arc::new(RwLock::new(config));
+    let mut client = init_client(&config, Some(model.clone()))?;
+    let http_client = client.build_client()?;
+    if let Some(builder) = client.list_models_builder(&http_client) {
+        let res = send_request(builder?).await?;
+        let status = res.status();
+        if !status.is_success() {
+            let data = res.json().await.unwrap_or_default();
+            catch_error(&data, status.as_u16())?;
+            bail!("Failed to list models (status: {})", status.as_u16());
+        }
+    } else if !paid {
+        return Ok(false);
+    } else if model.mode() == "embedding" {
+        let data = EmbeddingsData::new(vec![PROBE_PROMPT.to_string()], true);
+        client.embeddings_inner(&http_client, data).await?;
+    } else if model.mode() == "rerank" {
+        let data = RerankData::new(
+            PROBE_PROMPT.to_string(),
+            vec![PROBE_PROMPT.to_string()],
+            None,
+        );
+        client.rerank_inner(&http_client, data).await?;
+    } else {
+        client.model_mut().set_max_tokens(Some(1), true);
+        let data = ChatCompletionsData {
+            messages: vec![Message::new(
+                MessageRole::User,
+                MessageContent::Text(PROBE_PROMPT.to_string()),
+            )],
+            temperature: None,
+            top_p: None,
+            functions: None,
+            stream: false,
+        };
+        client.chat_completions_inner(&http_client, data).await?;
+    }
+    Ok(true)
+}
+
+fn classify_error(err: &anyhow::Error) -> ProbeOutcome {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if err.is_connect() || err.is_timeout() {
+                return ProbeOutcome::Unreachable;
+            }
+        }
+    }
+    let message = format!("{err:#}");
+    if message.starts_with("Timed out after") {
+        ProbeOutcome::Unreachable
+    } else if AUTH_ERROR_RE.is_match(&message).unwrap_or_default() {
+        ProbeOutcome::Unauthorized
+    } else {
+        ProbeOutcome::Error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_client() {
+        let client: ClientConfig = serde_json::from_value(serde_json::json!({
+            "type": "mock",
+            "models": [{ "name": "echo" }],
+        }))
+        .unwrap();
+        let timeout = Duration::from_secs(1);
+
+        // The mock client cannot list its models, its probe is a chat completion
+        let status = probe_client(&client, timeout, false).await;
+        assert_eq!(status.status, ProbeOutcome::Unknown);
+        assert_eq!(status.reachable, None);
+        assert_eq!(status.latency_ms, None);
+
+        let status = probe_client(&client, timeout, true).await;
+        assert_eq!(status.status, ProbeOutcome::Ok);
+        assert_eq!(status.reachable, Some(true));
+        assert_eq!(status.authorized, Some(true));
+    }
+
+    #[test]
+    fn test_classify_error() {
+        let classify = |message: &str| classify_error(&anyhow!("{message}"));
+        assert_eq!(
+            classify("Incorrect API key provided (type: invalid_request_error)"),
+            ProbeOutcome::Unauthorized
+        );
+        assert_eq!(
+            classify("invalid x-api-key (type: authentication_error)"),
+            ProbeOutcome::Unauthorized
+        );
+        assert_eq!(
+            classify("Miss 'api_key' in client configuration"),
+            ProbeOutcome::Unauthorized
+        );
+        assert_eq!(classify("Timed out after 10s"), ProbeOutcome::Unreachable);
+        assert_eq!(
+            classify("The model is overloaded (status: 529)"),
+            ProbeOutcome::Error
+        );
+    }
+}
@@ -1,21 +1,34 @@
+mod admin;
 mod client;
 mod config;
+mod escalation;
+mod eval;
 mod function;
+mod health;
 mod logger;
+mod mcp;
+mod metrics;
+mod overflow;
+mod rag;
 mod serve;
+mod store;
+mod summarize;
+mod telemetry;
 #[macro_use]
 mod utils;
+mod vector_store;
 
 #[macro_use]
 extern crate log;
 
+use crate::client::{init_cassette, CassetteMode};
 use crate::config::Config;
 use crate::utils::{create_abort_signal, CODE_BLOCK_RE, IS_STDOUT_TERMINAL};
 
 use anyhow::{bail, Result};
 use clap::Parser;
 use parking_lot::RwLock;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -23,13 +36,30 @@ struct Args {
     /// Port number to run the server on (optional)
     #[arg(long)]
     port: Option<u16>,
+    /// Run the evaluation described in the given file instead of starting the server
+    #[arg(long, value_name = "FILE")]
+    eval: Option<PathBuf>,
+    /// Record every upstream request and response to the given cassette file
+    #[arg(long, value_name = "CASSETTE")]
+    record: Option<PathBuf>,
+    /// Serve upstream responses from the given cassette file instead of the network
+    #[arg(long, value_name = "CASSETTE", conflicts_with = "record")]
+    replay: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
     crate::logger::setup_logger()?;
+    if let Some(path) = &args.record {
+        init_cassette(CassetteMode::Record, path)?;
+    } else if let Some(path) = &args.replay {
+        init_cassette(CassetteMode::Replay, path)?;
+    }
     let config = Arc::new(RwLock::new(Config::init()?));
+    if let Some(path) = args.eval {
+        return eval::run(config, &path).await;
+    }
 
     return serve::run(config, args.port).await;
 }
@@ -0,0 +1,459 @@
+use crate::function::{FunctionDeclaration, ToolsConfig};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use futures_util::future::join_all;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::oneshot,
+};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+/// Separates the server name from the tool name in the functions offered to the models
+const NAME_SEPARATOR: &str = "__";
+const MAX_FUNCTION_NAME_LEN: usize = 64;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+lazy_static! {
+    static ref SERVERS: RwLock<IndexMap<String, Arc<McpServer>>> = RwLock::new(IndexMap::new());
+    static ref SYNC_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    static ref NAME_RE: Regex = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
+}
+
+/// A Model Context Protocol server launched by the gateway and spoken to over stdio.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct McpServerConfig {
+    /// Prefix of the server's tools, offered to the models as `{name}__{tool}`
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables of the server, which otherwise only gets PATH and `tools.env`
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+}
+
+pub fn validate(configs: &[McpServerConfig]) -> Result<()> {
+    let mut names = HashSet::new();
+    for config in configs {
+        if !NAME_RE.is_match(&config.name).unwrap_or_default() {
+            bail!(
+                "Invalid MCP server name '{}', only letters, digits, '_' and '-' are allowed",
+                config.name
+            );
+        }
+        if !names.insert(&config.name) {
+            bail!("Duplicate MCP server '{}'", config.name);
+        }
+    }
+    Ok(())
+}
+
+/// Start the configured servers that are not running yet, restarting the changed or exited
+/// ones and stopping the removed ones.
+pub async fn sync(configs: &[McpServerConfig], tools: &ToolsConfig) {
+    let _guard = SYNC_LOCK.lock().await;
+    let current = SERVERS.read().clone();
+    let timeout = tools.timeout();
+    let servers = join_all(configs.iter().map(|config| {
+        let env = server_env(config, tools);
+        let existing = current.get(&config.name).cloned();
+        async move {
+            if let Some(server) =
+                existing.filter(|v| v.config == *config && v.env == env && v.is_running())
+            {
+                return Some(server);
+            }
+            match McpServer::start(config.clone(), env, timeout).await {
+                Ok(server) => {
+                    info!(
+                        "Started MCP server '{}' with {} tool(s)",
+                        config.name,
+                        server.tools.read().len()
+                    );
+                    Some(server)
+                }
+                Err(err) => {
+                    warn!("Failed to start MCP server '{}', {err:#}", config.name);
+                    None
+                }
+            }
+        }
+    }))
+    .await;
+    *SERVERS.write() = configs
+        .iter()
+        .zip(servers)
+        .filter_map(|(config, server)| Some((config.name.clone(), server?)))
+        .collect();
+}
+
+/// The tools of the running servers.
+pub fn declarations() -> Vec<FunctionDeclaration> {
+    SERVERS
+        .read()
+        .values()
+        .filter(|v| v.is_running())
+        .flat_map(|v| {
+            v.tools
+                .read()
+                .iter()
+                .map(|(_, declaration)| declaration.clone())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Call the server tool offered to the models under the given name.
+pub async fn call_tool(name: &str, arguments: &Value, config: &ToolsConfig) -> Result<Value> {
+    let found = SERVERS.read().values().find_map(|server| {
+        let tools = server.tools.read();
+        let (tool, _) = tools.iter().find(|(_, v)| v.name == name)?;
+        Some((server.clone(), tool.clone()))
+    });
+    let Some((server, tool)) = found else {
+        bail!("Unknown function '{name}'");
+    };
+    let arguments = match arguments {
+        Value::String(arguments) => serde_json::from_str(arguments)
+            .with_context(|| format!("Invalid arguments of '{name}'"))?,
+        Value::Null => json!({}),
+        arguments => arguments.clone(),
+    };
+    let result = server
+        .request(
+            "tools/call",
+            json!({ "name": tool, "arguments": arguments }),
+            config.timeout(),
+        )
+        .await?;
+
+    let text = result["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|v| match v["type"].as_str().unwrap_or_default() {
+            "text" => v["text"].as_str().unwrap_or_default().to_string(),
+            "resource" if v["resource"]["text"].is_string() => v["resource"]["text"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            kind => format!("[{kind} content omitted]"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if result["isError"].as_bool().unwrap_or_default() {
+        bail!("{}", truncate(&text, config.max_output_bytes()));
+    }
+    let structured = &result["structuredContent"];
+    let text = if structured.is_null() {
+        text
+    } else {
+        structured.to_string()
+    };
+    let limit = config.max_output_bytes();
+    if text.len() > limit {
+        return Ok(format!(
+            "{}\n[output truncated to {limit} bytes]",
+            truncate(&text, limit)
+        )
+        .into());
+    }
+    Ok(serde_json::from_str(&text).unwrap_or_else(|_| text.trim().into()))
+}
+
+struct McpServer {
+    config: McpServerConfig,
+    /// The environment the server was started with, a change restarts it
+    env: Vec<(String, String)>,
+    /// Bounds the requests the gateway makes on its own, like refreshing the tools
+    timeout: Duration,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>,
+    next_id: AtomicU64,
+    running: AtomicBool,
+    /// The tool names and their declarations as offered to the models
+    tools: RwLock<Vec<(String, FunctionDeclaration)>>,
+    /// Dropping the server kills the process
+    _child: Mutex<Child>,
+}
+
+impl McpServer {
+    async fn start(
+        config: McpServerConfig,
+        env: Vec<(String, String)>,
+        timeout: Duration,
+    ) -> Result<Arc<Self>> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .env_clear()
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run '{}'", config.command))?;
+        let stdin = child.stdin.take().context("No stdin")?;
+        let stdout = child.stdout.take().context("No stdout")?;
+        let stderr = child.stderr.take().context("No stderr")?;
+        let name = config.name.clone();
+        let server = Arc::new(Self {
+            config,
+            env,
+            timeout,
+            stdin: tokio::sync::Mutex::new(stdin),
+            pending: Default::default(),
+            next_id: AtomicU64::new(1),
+            running: AtomicBool::new(true),
+            tools: Default::default(),
+            _child: Mutex::new(child),
+        });
+        tokio::spawn(read_messages(Arc::downgrade(&server), name.clone(), stdout));
+        tokio::spawn(log_stderr(name, stderr));
+
+        server
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_CRATE_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+                timeout,
+            )
+            .await
+            .context("Failed to initialize")?;
+        server
+            .send(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+        server.refresh_tools().await?;
+        Ok(server)
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    async fn refresh_tools(&self) -> Result<()> {
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self
+                .request("tools/list", params, self.timeout)
+                .await
+                .context("Failed to list the tools")?;
+            for tool in result["tools"].as_array().into_iter().flatten() {
+                match self.declaration(tool) {
+                    Ok(v) => tools.push(v),
+                    Err(err) => warn!(
+                        "Skipped a tool of MCP server '{}', {err:#}",
+                        self.config.name
+                    ),
+                }
+            }
+            cursor = result["nextCursor"].as_str().map(|v| v.to_string());
+            if cursor.is_none() {
+                break;
+            }
+        }
+        *self.tools.write() = tools;
+        Ok(())
+    }
+
+    fn declaration(&self, tool: &Value) -> Result<(String, FunctionDeclaration)> {
+        let tool_name = tool["name"].as_str().context("Missing tool name")?;
+        let name = format!("{}{NAME_SEPARATOR}{tool_name}", self.config.name);
+        if name.len() > MAX_FUNCTION_NAME_LEN || !NAME_RE.is_match(&name).unwrap_or_default() {
+            bail!("Invalid function name '{name}'");
+        }
+        let parameters = serde_json::from_value(tool["inputSchema"].clone())
+            .with_context(|| format!("Unsupported input schema of '{tool_name}'"))?;
+        let declaration = FunctionDeclaration {
+            name,
+            description: tool["description"].as_str().unwrap_or_default().to_string(),
+            parameters,
+        };
+        Ok((tool_name.to_string(), declaration))
+    }
+
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        if !self.is_running() {
+            bail!("MCP server '{}' is not running", self.config.name);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id, tx);
+        let response = async {
+            self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+                .await?;
+            rx.await
+                .map_err(|_| anyhow!("MCP server '{}' exited", self.config.name))?
+        };
+        let ret = tokio::time::timeout(timeout, response).await;
+        self.pending.lock().remove(&id);
+        match ret {
+            Ok(ret) => ret,
+            Err(_) => {
+                let _ = self
+                    .send(json!({
+                        "jsonrpc": "2.0",
+                        "method": "notifications/cancelled",
+                        "params": { "requestId": id, "reason": "Timed out" },
+                    }))
+                    .await;
+                bail!("Timed out after {}s", timeout.as_secs())
+            }
+        }
+    }
+
+    async fn send(&self, message: Value) -> Result<()> {
+        let mut line = message.to_string();
+        line.push('\n');
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(line.as_bytes()).await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+
+    fn handle(self: Arc<Self>, message: Value) {
+        let method = message["method"].as_str().map(|v| v.to_string());
+        match (method, message["id"].clone()) {
+            // A response to one of our requests
+            (None, id) => {
+                let Some(tx) = id.as_u64().and_then(|id| self.pending.lock().remove(&id)) else {
+                    return;
+                };
+                let error = &message["error"];
+                let ret = if error.is_null() {
+                    Ok(message["result"].clone())
+                } else {
+                    Err(anyhow!(
+                        "{} (code: {})",
+                        error["message"].as_str().unwrap_or("Unknown error"),
+                        error["code"]
+                    ))
+                };
+                let _ = tx.send(ret);
+            }
+            (Some(method), Value::Null) => {
+                if method == "notifications/tools/list_changed" {
+                    tokio::spawn(async move {
+                        if let Err(err) = self.refresh_tools().await {
+                            warn!("MCP server '{}': {err:#}", self.config.name);
+                        }
+                    });
+                }
+            }
+            // The gateway answers pings and declares no other capability.
+            (Some(method), id) => {
+                let response = if method == "ping" {
+                    json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+                } else {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": METHOD_NOT_FOUND, "message": format!("Method not found: {method}") },
+                    })
+                };
+                tokio::spawn(async move {
+                    let _ = self.send(response).await;
+                });
+            }
+        }
+    }
+}
+
+async fn read_messages(server: Weak<McpServer>, name: String, stdout: ChildStdout) {
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                warn!("Failed to read from MCP server '{name}', {err}");
+                break;
+            }
+        };
+        let Some(server) = server.upgrade() else {
+            return;
+        };
+        match serde_json::from_str::<Value>(&line) {
+            Ok(message) if message.is_object() => server.handle(message),
+            _ => debug!("MCP server '{name}' printed: {line}"),
+        }
+    }
+    // Fail the pending requests instead of letting them wait for their timeout.
+    if let Some(server) = server.upgrade() {
+        warn!("MCP server '{name}' exited");
+        server.running.store(false, Ordering::SeqCst);
+        server.pending.lock().clear();
+    }
+}
+
+async fn log_stderr(name: String, stderr: ChildStderr) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!("MCP server '{name}': {line}");
+    }
+}
+
+fn server_env(config: &McpServerConfig, tools: &ToolsConfig) -> Vec<(String, String)> {
+    let mut output: Vec<(String, String)> = ["PATH"]
+        .iter()
+        .map(|v| v.to_string())
+        .chain(tools.env.iter().cloned())
+        .filter_map(|name| Some((name.clone(), env::var(&name).ok()?)))
+        .collect();
+    output.extend(config.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    output
+}
+
+fn truncate(text: &str, limit: usize) -> &str {
+    let mut end = limit.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = |name: &str| McpServerConfig {
+            name: name.to_string(),
+            command: "mcp-server".to_string(),
+            args: vec![],
+            env: Default::default(),
+        };
+        assert!(validate(&[config("github"), config("local-fs")]).is_ok());
+        assert!(validate(&[config("github"), config("github")]).is_err());
+        assert!(validate(&[config("git hub")]).is_err());
+    }
+}
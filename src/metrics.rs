@@ -0,0 +1,401 @@
+use indexmap::{IndexMap, IndexSet};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::fmt::Write;
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0,
+];
+const TOKENS_PER_SECOND_BUCKETS: [f64; 10] =
+    [1.0, 5.0, 10.0, 20.0, 40.0, 60.0, 80.0, 100.0, 150.0, 250.0];
+/// The longest agent name kept as a label value
+const MAX_AGENT_LABEL_LEN: usize = 64;
+/// The number of distinct agent names kept as label values, later ones counting as unknown
+const MAX_AGENT_LABELS: usize = 100;
+
+/// The label value of anything not configured, e.g. a model that failed to resolve
+pub const UNKNOWN_LABEL: &str = "unknown";
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// The labels attached to every LLM call metric.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CallLabels {
+    pub client: String,
+    pub model: String,
+    pub agent: String,
+}
+
+impl CallLabels {
+    pub fn new(client: &str, model: &str, agent: &str) -> Self {
+        Self {
+            client: client.to_string(),
+            model: model.to_string(),
+            agent: agent.to_string(),
+        }
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.client.clone(), self.model.clone(), self.agent.clone()]
+    }
+}
+
+const CALL_LABEL_NAMES: [&str; 3] = ["client", "model", "agent"];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    agents: IndexSet<String>,
+    requests: IndexMap<Vec<String>, f64>,
+    input_tokens: IndexMap<Vec<String>, f64>,
+    output_tokens: IndexMap<Vec<String>, f64>,
+    cost: IndexMap<Vec<String>, f64>,
+    fallbacks: IndexMap<Vec<String>, f64>,
+    inflight_streams: IndexMap<Vec<String>, f64>,
+    latency: IndexMap<Vec<String>, Histogram>,
+    time_to_first_token: IndexMap<Vec<String>, Histogram>,
+    tokens_per_second: IndexMap<Vec<String>, Histogram>,
+}
+
+impl Metrics {
+    /// The labels of a call, see `agent_label` for the agent.
+    pub fn call_labels(&self, client: &str, model: &str, agent: &str) -> CallLabels {
+        CallLabels::new(client, model, &self.agent_label(agent))
+    }
+
+    /// The agent name being client controlled, names too long, with characters outside
+    /// `[A-Za-z0-9_.-]` or beyond the first `MAX_AGENT_LABELS` ones count as unknown so that
+    /// the number of series stays bounded.
+    fn agent_label(&self, agent: &str) -> String {
+        let valid = !agent.is_empty()
+            && agent.len() <= MAX_AGENT_LABEL_LEN
+            && agent
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if !valid {
+            return UNKNOWN_LABEL.to_string();
+        }
+        let mut inner = self.inner.lock();
+        if inner.agents.contains(agent) || inner.agents.len() < MAX_AGENT_LABELS {
+            inner.agents.insert(agent.to_string());
+            agent.to_string()
+        } else {
+            UNKNOWN_LABEL.to_string()
+        }
+    }
+
+    pub fn inc_requests(&self, labels: &CallLabels, status: &str) {
+        let mut key = labels.values();
+        key.push(status.to_string());
+        *self.inner.lock().requests.entry(key).or_default() += 1.0;
+    }
+
+    pub fn add_tokens(&self, labels: &CallLabels, input_tokens: u64, output_tokens: u64) {
+        let mut inner = self.inner.lock();
+        *inner.input_tokens.entry(labels.values()).or_default() += input_tokens as f64;
+        *inner.output_tokens.entry(labels.values()).or_default() += output_tokens as f64;
+    }
+
+    pub fn add_cost(&self, labels: &CallLabels, cost: f64) {
+        *self.inner.lock().cost.entry(labels.values()).or_default() += cost;
+    }
+
+    /// Count a call answered by another model than the requested one, both being model ids
+    /// from the config.
+    pub fn inc_fallbacks(&self, requested_model: &str, model: &str, agent: &str) {
+        let key = vec![
+            requested_model.to_string(),
+            model.to_string(),
+            self.agent_label(agent),
+        ];
+        *self.inner.lock().fallbacks.entry(key).or_default() += 1.0;
+    }
+
+    pub fn observe_latency(&self, labels: &CallLabels, seconds: f64) {
+        self.inner
+            .lock()
+            .latency
+            .entry(labels.values())
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(seconds);
+    }
+
+    pub fn observe_time_to_first_token(&self, labels: &CallLabels, seconds: f64) {
+        self.inner
+            .lock()
+            .time_to_first_token
+            .entry(labels.values())
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(seconds);
+    }
+
+    pub fn observe_tokens_per_second(&self, labels: &CallLabels, value: f64) {
+        self.inner
+            .lock()
+            .tokens_per_second
+            .entry(labels.values())
+            .or_insert_with(|| Histogram::new(&TOKENS_PER_SECOND_BUCKETS))
+            .observe(value);
+    }
+
+    pub fn stream_started(&self, labels: &CallLabels) {
+        *self
+            .inner
+            .lock()
+            .inflight_streams
+            .entry(labels.values())
+            .or_default() += 1.0;
+    }
+
+    pub fn stream_finished(&self, labels: &CallLabels) {
+        if let Some(value) = self.inner.lock().inflight_streams.get_mut(&labels.values()) {
+            *value = (*value - 1.0).max(0.0);
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock();
+        let mut output = String::new();
+        let mut status_label_names = CALL_LABEL_NAMES.to_vec();
+        status_label_names.push("status");
+        render_samples(
+            &mut output,
+            "agent_panel_requests_total",
+            "counter",
+            "Total number of LLM calls.",
+            &status_label_names,
+            &inner.requests,
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_input_tokens_total",
+            "counter",
+            "Total number of input tokens.",
+            &CALL_LABEL_NAMES,
+            &inner.input_tokens,
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_output_tokens_total",
+            "counter",
+            "Total number of output tokens.",
+            &CALL_LABEL_NAMES,
+            &inner.output_tokens,
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_cost_total",
+            "counter",
+            "Total cost of LLM calls in dollars.",
+            &CALL_LABEL_NAMES,
+            &inner.cost,
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_fallbacks_total",
+            "counter",
+            "Total number of calls answered by another model than the requested one.",
+            &["requested_model", "model", "agent"],
+            &inner.fallbacks,
+        );
+        // The gateway neither retries failed calls nor caches responses yet.
+        render_samples(
+            &mut output,
+            "agent_panel_retries_total",
+            "counter",
+            "Total number of retried LLM calls.",
+            &[],
+            &[(vec![], 0.0)].into(),
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_cache_hits_total",
+            "counter",
+            "Total number of LLM calls answered from a cache.",
+            &[],
+            &[(vec![], 0.0)].into(),
+        );
+        render_samples(
+            &mut output,
+            "agent_panel_inflight_streams",
+            "gauge",
+            "Number of streaming responses currently in flight.",
+            &CALL_LABEL_NAMES,
+            &inner.inflight_streams,
+        );
+        render_histograms(
+            &mut output,
+            "agent_panel_request_duration_seconds",
+            "End-to-end latency of LLM calls.",
+            &inner.latency,
+        );
+        render_histograms(
+            &mut output,
+            "agent_panel_time_to_first_token_seconds",
+            "Time until the first token of a streaming response.",
+            &inner.time_to_first_token,
+        );
+        render_histograms(
+            &mut output,
+            "agent_panel_output_tokens_per_second",
+            "Output tokens generated per second.",
+            &inner.tokens_per_second,
+        );
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in self.buckets.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+fn render_samples(
+    output: &mut String,
+    name: &str,
+    typ: &str,
+    help: &str,
+    label_names: &[&str],
+    samples: &IndexMap<Vec<String>, f64>,
+) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {typ}");
+    for (values, value) in samples {
+        let labels = format_labels(label_names, values, None);
+        let _ = writeln!(output, "{name}{labels} {value}");
+    }
+}
+
+fn render_histograms(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &IndexMap<Vec<String>, Histogram>,
+) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} histogram");
+    for (values, histogram) in histograms {
+        for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+            let labels = format_labels(&CALL_LABEL_NAMES, values, Some(&bound.to_string()));
+            let _ = writeln!(output, "{name}_bucket{labels} {count}");
+        }
+        let labels = format_labels(&CALL_LABEL_NAMES, values, Some("+Inf"));
+        let _ = writeln!(output, "{name}_bucket{labels} {}", histogram.count);
+        let labels = format_labels(&CALL_LABEL_NAMES, values, None);
+        let _ = writeln!(output, "{name}_sum{labels} {}", histogram.sum);
+        let _ = writeln!(output, "{name}_count{labels} {}", histogram.count);
+    }
+}
+
+fn format_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values.iter())
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let labels = CallLabels::new("openai", "gpt-4o", "qna");
+        metrics.inc_requests(&labels, "success");
+        metrics.add_tokens(&labels, 10, 20);
+        metrics.observe_latency(&labels, 0.3);
+        let output = metrics.render();
+        assert!(output.contains(
+            r#"agent_panel_requests_total{client="openai",model="gpt-4o",agent="qna",status="success"} 1"#
+        ));
+        assert!(output.contains(
+            r#"agent_panel_output_tokens_total{client="openai",model="gpt-4o",agent="qna"} 20"#
+        ));
+        assert!(output.contains(
+            r#"agent_panel_request_duration_seconds_bucket{client="openai",model="gpt-4o",agent="qna",le="0.25"} 0"#
+        ));
+        assert!(output.contains(
+            r#"agent_panel_request_duration_seconds_bucket{client="openai",model="gpt-4o",agent="qna",le="0.5"} 1"#
+        ));
+        assert!(output.contains(
+            r#"agent_panel_request_duration_seconds_count{client="openai",model="gpt-4o",agent="qna"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_call_labels() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.call_labels("openai", "gpt-4o", "qna").agent, "qna");
+        assert_eq!(metrics.call_labels("openai", "gpt-4o", "").agent, "unknown");
+        assert_eq!(
+            metrics.call_labels("openai", "gpt-4o", "a b").agent,
+            "unknown"
+        );
+        let long_name = "a".repeat(MAX_AGENT_LABEL_LEN + 1);
+        assert_eq!(
+            metrics.call_labels("openai", "gpt-4o", &long_name).agent,
+            "unknown"
+        );
+        for i in 1..MAX_AGENT_LABELS {
+            metrics.call_labels("openai", "gpt-4o", &format!("agent-{i}"));
+        }
+        assert_eq!(
+            metrics.call_labels("openai", "gpt-4o", "extra").agent,
+            "unknown"
+        );
+        assert_eq!(metrics.call_labels("openai", "gpt-4o", "qna").agent, "qna");
+        assert_eq!(metrics.inner.lock().agents.len(), MAX_AGENT_LABELS);
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+}
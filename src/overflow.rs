@@ -0,0 +1,194 @@
+use crate::client::{Message, MessageContent, Model};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Tokens kept of each message cut by `middle_out`, half at each end
+const MIN_KEPT_TOKENS: usize = 64;
+
+/// What to do with a conversation exceeding the model's `max_input_tokens`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// Fail the request
+    #[default]
+    Reject,
+    /// Drop the oldest turns after the system prompts
+    DropOldest,
+    /// Cut the middle out of the longest messages
+    MiddleOut,
+    /// Replace the older turns with a summary
+    Summarize,
+}
+
+impl OverflowStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverflowStrategy::Reject => "reject",
+            OverflowStrategy::DropOldest => "drop_oldest",
+            OverflowStrategy::MiddleOut => "middle_out",
+            OverflowStrategy::Summarize => "summarize",
+        }
+    }
+}
+
+/// What was taken out of a conversation to fit it in the context window.
+#[derive(Debug, Clone, Copy)]
+pub struct Trimmed {
+    pub strategy: OverflowStrategy,
+    /// The messages dropped, cut or summarized
+    pub messages: usize,
+    /// The estimated input tokens saved
+    pub tokens: usize,
+}
+
+impl Trimmed {
+    pub fn header_value(&self) -> String {
+        format!(
+            "strategy={}; messages={}; tokens={}",
+            self.strategy.as_str(),
+            self.messages,
+            self.tokens
+        )
+    }
+}
+
+/// Drop whole turns, oldest first, until the conversation fits, keeping the leading system
+/// prompts and the last turn. Returns the count of dropped messages, `None` when the
+/// conversation still does not fit.
+pub fn drop_oldest(messages: &mut Vec<Message>, model: &Model) -> Option<usize> {
+    let start = messages.iter().take_while(|v| v.role.is_system()).count();
+    let mut dropped = 0;
+    while model.excess_input_tokens(messages) > 0 {
+        let next = messages
+            .iter()
+            .enumerate()
+            .skip(start + 1)
+            .find(|(_, v)| is_user_turn(v))
+            .map(|(i, _)| i)?;
+        messages.drain(start..next);
+        dropped += next - start;
+    }
+    Some(dropped)
+}
+
+/// Cut the middle out of the longest text messages until the conversation fits, marking
+/// the cuts. Returns the count of cut messages, `None` when the conversation still does
+/// not fit.
+pub fn middle_out(messages: &mut [Message], model: &Model) -> Option<usize> {
+    let mut cut = HashSet::new();
+    let mut exhausted = HashSet::new();
+    loop {
+        let excess = model.excess_input_tokens(messages);
+        if excess == 0 {
+            return Some(cut.len());
+        }
+        let (index, tokens) = messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !exhausted.contains(i))
+            .filter_map(|(i, v)| match &v.content {
+                MessageContent::Text(text) => Some((i, model.count_tokens(text))),
+                _ => None,
+            })
+            .filter(|(_, tokens)| *tokens > MIN_KEPT_TOKENS)
+            .max_by_key(|(_, tokens)| *tokens)?;
+        let MessageContent::Text(text) = &messages[index].content else {
+            return None;
+        };
+        let keep = tokens.saturating_sub(excess).max(MIN_KEPT_TOKENS);
+        let text = cut_middle(text, tokens, keep);
+        if model.count_tokens(&text) >= tokens {
+            exhausted.insert(index);
+            continue;
+        }
+        messages[index].content = MessageContent::Text(text);
+        cut.insert(index);
+    }
+}
+
+/// Keep about `keep` of the `tokens` tokens of the text, from its start and its end.
+fn cut_middle(text: &str, tokens: usize, keep: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let marker = format!("\n\n[... {} tokens trimmed ...]\n\n", tokens - keep);
+    let keep_chars = (chars.len() * keep / tokens.max(1))
+        .saturating_sub(marker.chars().count())
+        .min(chars.len());
+    let head = keep_chars / 2;
+    let tail = keep_chars - head;
+    let mut output: String = chars[..head].iter().collect();
+    output.push_str(&marker);
+    output.extend(&chars[chars.len() - tail..]);
+    output
+}
+
+fn is_user_turn(message: &Message) -> bool {
+    message.role.is_user() && !matches!(message.content, MessageContent::ToolResults(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MessageRole;
+
+    fn message(role: MessageRole, text: &str) -> Message {
+        Message::new(role, MessageContent::Text(text.to_string()))
+    }
+
+    fn model(max_input_tokens: usize) -> Model {
+        let mut model = Model::new("mock", "test");
+        model.data_mut().max_input_tokens = Some(max_input_tokens);
+        model
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut messages = vec![message(MessageRole::System, "Be brief")];
+        for i in 0..3 {
+            messages.push(message(
+                MessageRole::User,
+                &format!("question {i} ").repeat(20),
+            ));
+            messages.push(message(
+                MessageRole::Assistant,
+                &format!("answer {i} ").repeat(20),
+            ));
+        }
+        messages.push(message(MessageRole::User, "last question"));
+        let model = model(200);
+        assert_eq!(drop_oldest(&mut messages, &model), Some(4));
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].role.is_system());
+        assert_eq!(messages[3].content.to_text(), "last question");
+        assert_eq!(model.excess_input_tokens(&messages), 0);
+
+        let mut messages = vec![message(MessageRole::User, &"word ".repeat(1000))];
+        assert_eq!(drop_oldest(&mut messages, &model), None);
+    }
+
+    #[test]
+    fn test_middle_out() {
+        let long = (0..400)
+            .map(|i| format!("w{i}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut messages = vec![
+            message(MessageRole::System, "Be brief"),
+            message(MessageRole::User, &long),
+        ];
+        let model = model(300);
+        assert_eq!(middle_out(&mut messages, &model), Some(1));
+        let text = messages[1].content.to_text();
+        assert!(text.starts_with("w0 w1"));
+        assert!(text.ends_with("w398 w399"));
+        assert!(text.contains("tokens trimmed"));
+        assert_eq!(model.excess_input_tokens(&messages), 0);
+
+        let mut messages = vec![message(MessageRole::User, &long)];
+        assert_eq!(
+            middle_out(&mut messages, &Model::new("mock", "test")),
+            Some(0)
+        );
+        assert_eq!(middle_out(&mut messages, &self::model(20)), None);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+/// An inverted index scoring texts against keyword queries with Okapi BM25, catching the
+/// exact identifiers and rare terms that embeddings tend to blur.
+#[derive(Debug)]
+pub struct Bm25Index {
+    /// The positions of the texts containing each term, with the term frequency
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    lens: Vec<u32>,
+    avg_len: f32,
+}
+
+impl Bm25Index {
+    /// Index the texts, their positions being their ids.
+    pub fn build(texts: &[&str]) -> Self {
+        let mut postings: HashMap<String, Vec<(usize, u32)>> = HashMap::new();
+        let mut lens = Vec::with_capacity(texts.len());
+        for (id, text) in texts.iter().enumerate() {
+            let terms = tokenize(text);
+            lens.push(terms.len() as u32);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for term in terms {
+                *counts.entry(term).or_default() += 1;
+            }
+            for (term, count) in counts {
+                postings.entry(term).or_default().push((id, count));
+            }
+        }
+        let avg_len = match lens.len() {
+            0 => 0.0,
+            n => lens.iter().sum::<u32>() as f32 / n as f32,
+        };
+        Self {
+            postings,
+            lens,
+            avg_len,
+        }
+    }
+
+    /// The ids of the texts matching the most query terms, with their BM25 score, best first.
+    pub fn search(&self, query: &str, top_k: usize) -> Vec<(usize, f32)> {
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        let n = self.lens.len() as f32;
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for term in terms {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let df = postings.len() as f32;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (id, tf) in postings {
+                let tf = *tf as f32;
+                let len_norm = 1.0 - B + B * self.lens[*id] as f32 / self.avg_len.max(1.0);
+                *scores.entry(*id).or_default() += idf * tf * (K1 + 1.0) / (tf + K1 * len_norm);
+            }
+        }
+        let mut output: Vec<(usize, f32)> = scores.into_iter().collect();
+        output.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        output.truncate(top_k);
+        output
+    }
+}
+
+/// Lowercased words, identifiers like `parse_config` being kept whole.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bm25() {
+        let index = Bm25Index::build(&[
+            "The config is parsed at startup.",
+            "Call parse_config to load the config file.",
+            "Penguins live in Antarctica.",
+        ]);
+        let results = index.search("parse_config", 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 1);
+        let results = index.search("the config", 10);
+        assert_eq!(results.iter().map(|v| v.0).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(index.search("kangaroo", 10).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use hnsw_rs::prelude::{DataId, DistCosine, Hnsw};
+
+const MAX_NB_CONNECTION: usize = 32;
+const MAX_LAYER: usize = 16;
+const EF_CONSTRUCTION: usize = 200;
+const EF_SEARCH: usize = 64;
+
+/// An HNSW graph over embeddings, built in memory from the stored vectors since removing
+/// points from it is not supported.
+pub struct VectorIndex {
+    hnsw: Hnsw<'static, f32, DistCosine>,
+    len: usize,
+}
+
+impl std::fmt::Debug for VectorIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VectorIndex")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl VectorIndex {
+    /// Index the vectors, their positions being their ids, `None` when there are none.
+    pub fn build(vectors: &[&Vec<f32>]) -> Option<Self> {
+        if vectors.is_empty() {
+            return None;
+        }
+        let mut hnsw = Hnsw::new(
+            MAX_NB_CONNECTION,
+            vectors.len(),
+            MAX_LAYER,
+            EF_CONSTRUCTION,
+            DistCosine {},
+        );
+        let points: Vec<(&Vec<f32>, usize)> = vectors
+            .iter()
+            .enumerate()
+            .map(|(id, vector)| (*vector, id))
+            .collect();
+        hnsw.parallel_insert(&points);
+        hnsw.set_searching_mode(true);
+        Some(Self {
+            hnsw,
+            len: vectors.len(),
+        })
+    }
+
+    /// The ids of the vectors closest to the query that pass the filter, with their cosine
+    /// similarity, most similar first.
+    pub fn search(
+        &self,
+        query: &[f32],
+        top_k: usize,
+        filter: Option<&dyn Fn(usize) -> bool>,
+    ) -> Vec<(usize, f32)> {
+        let ef = EF_SEARCH.max(top_k);
+        let neighbours = match filter {
+            Some(filter) => {
+                let filter = |id: &DataId| filter(*id);
+                self.hnsw.search_filter(query, top_k, ef, Some(&filter))
+            }
+            None => self.hnsw.search(query, top_k, ef),
+        };
+        let mut output: Vec<(usize, f32)> = neighbours
+            .into_iter()
+            .filter(|v| v.d_id < self.len)
+            .map(|v| (v.d_id, 1.0 - v.distance))
+            .collect();
+        output.sort_by(|a, b| b.1.total_cmp(&a.1));
+        output
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f64, 0.0f64, 0.0f64);
+    for (x, y) in a.iter().zip(b.iter()) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
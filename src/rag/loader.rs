@@ -0,0 +1,75 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The extensions of the files picked up when a directory is ingested.
+const SUPPORTED_EXTS: [&str; 5] = ["pdf", "md", "markdown", "txt", "text"];
+
+/// The files to ingest: the given files, and the supported files found in the given
+/// directories, recursively.
+pub fn expand_paths(paths: &[String]) -> Result<Vec<PathBuf>> {
+    let mut output = vec![];
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            walk_dir(path, &mut output)
+                .with_context(|| format!("Failed to read directory '{}'", path.display()))?;
+        } else if path.is_file() {
+            output.push(path.to_path_buf());
+        } else {
+            bail!("File '{}' not found", path.display());
+        }
+    }
+    Ok(output)
+}
+
+fn walk_dir(dir: &Path, output: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|v| Some(v.ok()?.path()))
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            walk_dir(&path, output)?;
+        } else if is_supported(&path) {
+            output.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|v| v.to_str())
+        .map(|v| SUPPORTED_EXTS.contains(&v.to_lowercase().as_str()))
+        .unwrap_or_default()
+}
+
+/// Extract the text of a PDF, Markdown or text file.
+pub fn load_file(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    load_bytes(&path.display().to_string(), &bytes)
+}
+
+/// Extract the text of an uploaded document, PDFs being recognized by their name.
+pub fn load_bytes(name: &str, bytes: &[u8]) -> Result<String> {
+    if name.to_lowercase().ends_with(".pdf") {
+        // The extraction panics on some malformed PDFs.
+        let ret = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(bytes))
+            .map_err(|_| anyhow!("Malformed PDF"));
+        return ret
+            .and_then(|v| Ok(v?))
+            .with_context(|| format!("Failed to extract the text of '{name}'"));
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.to_string()),
+        Err(_) => bail!("Unsupported document '{name}', not a PDF or text file"),
+    }
+}
+
+pub fn is_markdown(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".md") || name.ends_with(".markdown")
+}
@@ -0,0 +1,577 @@
+mod bm25;
+mod index;
+mod loader;
+mod splitter;
+
+pub use self::index::{cosine_similarity, VectorIndex};
+pub use self::loader::load_bytes;
+
+use self::bm25::Bm25Index;
+use self::loader::{expand_paths, is_markdown, load_file};
+use self::splitter::{split_text, DEFAULT_SEPARATORS, MARKDOWN_SEPARATORS};
+
+use crate::client::{Client, EmbeddingsData, Message, MessageContent, MessageContentPart, Model};
+use crate::config::{Config, RAG_TEMPLATE};
+use crate::store::Store;
+use crate::utils::now;
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+const DEFAULT_TOP_K: usize = 4;
+const DEFAULT_VECTOR_WEIGHT: f32 = 0.5;
+/// The smoothing constant of reciprocal rank fusion, damping the lead of the top ranks
+const RRF_K: f32 = 60.0;
+/// The depth of the rankings fused, as the best chunks of one search may rank low in the other
+const MIN_FUSED_CANDIDATES: usize = 50;
+
+lazy_static! {
+    static ref RAGS: Store<Rag> = Store::new("knowledge base", Config::rags_dir);
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct RagConfig {
+    /// Embedding model of the knowledge bases created without one
+    pub embedding_model: Option<String>,
+    /// Characters per chunk, defaults to the embedding model's `default_chunk_size`
+    pub chunk_size: Option<usize>,
+    /// Characters shared by consecutive chunks, defaults to 5% of the chunk size
+    pub chunk_overlap: Option<usize>,
+    /// Chunks injected into a prompt
+    pub top_k: Option<usize>,
+    /// Weight of the vector search against the keyword search, from 0 (keywords only) to 1
+    /// (vectors only)
+    pub vector_weight: Option<f32>,
+    /// Rerank or embedding model reordering the retrieved chunks, none by default
+    pub rerank_model: Option<String>,
+    /// Directories whose files may be ingested by path, none by default
+    pub allowed_paths: Vec<String>,
+}
+
+impl RagConfig {
+    /// The vector weight of a query, defaulting to the configured one.
+    pub fn vector_weight(&self, value: Option<f32>) -> Result<f32> {
+        let value = value
+            .or(self.vector_weight)
+            .unwrap_or(DEFAULT_VECTOR_WEIGHT);
+        if !(0.0..=1.0).contains(&value) {
+            bail!("The vector weight must be between 0 and 1");
+        }
+        Ok(value)
+    }
+
+    /// Check that a path sent to the API lies in one of the allowed directories.
+    pub fn check_path(&self, path: &str) -> Result<PathBuf> {
+        let canonical = Path::new(path)
+            .canonicalize()
+            .with_context(|| format!("File '{path}' not found"))?;
+        let allowed = self
+            .allowed_paths
+            .iter()
+            .filter_map(|v| Path::new(v).canonicalize().ok())
+            .any(|v| canonical.starts_with(v));
+        if !allowed {
+            bail!("Path '{path}' is not in 'rag.allowed_paths'");
+        }
+        Ok(canonical)
+    }
+}
+
+/// A named knowledge base: documents split into chunks, with the embedding of each chunk
+/// indexed for similarity search.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rag {
+    pub name: String,
+    pub embedding_model: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub top_k: usize,
+    pub created_at: String,
+    pub documents: IndexMap<String, RagDocument>,
+    /// Built on the first search, `None` when there is no chunk
+    #[serde(skip)]
+    index: OnceLock<Option<RagIndex>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RagDocument {
+    pub added_at: String,
+    pub chunks: Vec<RagChunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RagChunk {
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RagResult {
+    pub document: String,
+    pub text: String,
+    pub score: f32,
+}
+
+/// The indexes of the chunks, by embedding and by keyword. They are not stored: the HNSW
+/// graph cannot drop points, so they are built again in memory on the first search after a
+/// change or a restart.
+#[derive(Debug)]
+struct RagIndex {
+    vectors: VectorIndex,
+    keywords: Bm25Index,
+    /// The document and chunk positions of the indexed vectors
+    ids: Vec<(usize, usize)>,
+}
+
+impl Rag {
+    pub fn new(
+        name: &str,
+        embedding_model: &Model,
+        config: &RagConfig,
+        chunk_size: Option<usize>,
+        chunk_overlap: Option<usize>,
+        top_k: Option<usize>,
+    ) -> Result<Self> {
+        validate_name(name)?;
+        let chunk_size = chunk_size
+            .or(config.chunk_size)
+            .unwrap_or_else(|| embedding_model.default_chunk_size())
+            .max(1);
+        let chunk_overlap = chunk_overlap
+            .or(config.chunk_overlap)
+            .unwrap_or(chunk_size / 20);
+        if chunk_overlap >= chunk_size {
+            bail!("The chunk overlap must be smaller than the chunk size");
+        }
+        Ok(Self {
+            name: name.to_string(),
+            embedding_model: embedding_model.id(),
+            chunk_size,
+            chunk_overlap,
+            top_k: top_k.or(config.top_k).unwrap_or(DEFAULT_TOP_K).max(1),
+            created_at: now(),
+            documents: Default::default(),
+            index: OnceLock::new(),
+        })
+    }
+
+    pub fn list() -> Result<Vec<String>> {
+        RAGS.list()
+    }
+
+    pub fn exists(name: &str) -> Result<bool> {
+        RAGS.exists(name)
+    }
+
+    /// The knowledge base, loaded from disk on first use.
+    pub fn load(name: &str) -> Result<Arc<Self>> {
+        RAGS.load(name)
+    }
+
+    /// The stored knowledge base, uncached, to be changed and saved again.
+    pub fn load_file(name: &str) -> Result<Self> {
+        RAGS.load_file(name)
+    }
+
+    /// Write the knowledge base to disk, then serve it, its indexes being built on the next
+    /// search so that successive changes only pay for a single build.
+    pub fn save(self) -> Result<Arc<Self>> {
+        RAGS.save(&self.name.clone(), self)
+    }
+
+    pub fn delete(name: &str) -> Result<()> {
+        RAGS.delete(name)
+    }
+
+    /// Split and embed the documents, replacing the ones with the same names. Returns the
+    /// chunk count of each document.
+    pub async fn add_documents(
+        &mut self,
+        documents: Vec<(String, String)>,
+        client: &dyn Client,
+    ) -> Result<Vec<(String, usize)>> {
+        let batch_size = client.model().max_concurrent_chunks().max(1);
+        let mut output = vec![];
+        for (name, text) in documents {
+            let separators: &[&str] = if is_markdown(&name) {
+                &MARKDOWN_SEPARATORS
+            } else {
+                &DEFAULT_SEPARATORS
+            };
+            let texts = split_text(&text, self.chunk_size, self.chunk_overlap, separators);
+            let mut chunks = Vec::with_capacity(texts.len());
+            for batch in texts.chunks(batch_size) {
+                let data = EmbeddingsData::new(batch.to_vec(), false);
+                let embeddings = client
+                    .embeddings(data)
+                    .await
+                    .with_context(|| format!("Failed to embed '{name}'"))?;
+                if embeddings.len() != batch.len() {
+                    bail!("Invalid embeddings response for '{name}'");
+                }
+                chunks.extend(
+                    batch
+                        .iter()
+                        .zip(embeddings)
+                        .map(|(text, embedding)| RagChunk {
+                            text: text.clone(),
+                            embedding,
+                        }),
+                );
+            }
+            debug!(
+                "Embedded '{name}' into {} chunk(s) for knowledge base '{}'",
+                chunks.len(),
+                self.name
+            );
+            output.push((name.clone(), chunks.len()));
+            self.documents.shift_remove(&name);
+            self.documents.insert(
+                name,
+                RagDocument {
+                    added_at: now(),
+                    chunks,
+                },
+            );
+        }
+        Ok(output)
+    }
+
+    pub fn remove_document(&mut self, name: &str) -> Result<()> {
+        if self.documents.shift_remove(name).is_none() {
+            bail!(
+                "Document '{name}' not found in knowledge base '{}'",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn chunks_count(&self) -> usize {
+        self.documents.values().map(|v| v.chunks.len()).sum()
+    }
+
+    /// The chunks best matching the query, best first. The rankings of the vector search,
+    /// skipped without an embedding, and of the keyword search are fused by reciprocal rank
+    /// with the vector weight, a single ranking keeping its own scores.
+    pub fn search(
+        &self,
+        query: &str,
+        embedding: Option<&[f32]>,
+        top_k: usize,
+        vector_weight: f32,
+    ) -> Vec<RagResult> {
+        let Some(index) = self.index.get_or_init(|| self.build_index()) else {
+            return vec![];
+        };
+        let embedding = embedding.filter(|_| vector_weight > 0.0);
+        let results = match embedding {
+            Some(embedding) if vector_weight >= 1.0 => index.vectors.search(embedding, top_k, None),
+            None => index.keywords.search(query, top_k),
+            Some(embedding) => {
+                let depth = top_k.max(MIN_FUSED_CANDIDATES);
+                let rankings = [
+                    (index.vectors.search(embedding, depth, None), vector_weight),
+                    (index.keywords.search(query, depth), 1.0 - vector_weight),
+                ];
+                let mut scores: IndexMap<usize, f32> = IndexMap::new();
+                for (ranking, weight) in rankings {
+                    for (rank, (id, _)) in ranking.into_iter().enumerate() {
+                        *scores.entry(id).or_default() += weight / (RRF_K + rank as f32 + 1.0);
+                    }
+                }
+                let mut results: Vec<(usize, f32)> = scores.into_iter().collect();
+                results.sort_by(|a, b| b.1.total_cmp(&a.1));
+                results.truncate(top_k);
+                results
+            }
+        };
+        results
+            .into_iter()
+            .filter_map(|(id, score)| {
+                let (document_index, chunk_index) = *index.ids.get(id)?;
+                let (name, document) = self.documents.get_index(document_index)?;
+                Some(RagResult {
+                    document: name.clone(),
+                    text: document.chunks.get(chunk_index)?.text.clone(),
+                    score,
+                })
+            })
+            .collect()
+    }
+
+    fn build_index(&self) -> Option<RagIndex> {
+        let ids: Vec<(usize, usize)> = self
+            .documents
+            .values()
+            .enumerate()
+            .flat_map(|(i, document)| (0..document.chunks.len()).map(move |j| (i, j)))
+            .collect();
+        let vectors: Vec<&Vec<f32>> = ids
+            .iter()
+            .map(|(i, j)| &self.documents[*i].chunks[*j].embedding)
+            .collect();
+        let texts: Vec<&str> = ids
+            .iter()
+            .map(|(i, j)| self.documents[*i].chunks[*j].text.as_str())
+            .collect();
+        VectorIndex::build(&vectors).map(|vectors| RagIndex {
+            vectors,
+            keywords: Bm25Index::build(&texts),
+            ids,
+        })
+    }
+}
+
+/// Read the documents at the paths, directories being walked for PDF, Markdown and text
+/// files, as (name, text) pairs.
+pub fn read_documents(paths: &[PathBuf]) -> Result<Vec<(String, String)>> {
+    let paths: Vec<String> = paths.iter().map(|v| v.display().to_string()).collect();
+    expand_paths(&paths)?
+        .into_iter()
+        .map(|path| Ok((path.display().to_string(), load_file(&path)?)))
+        .collect()
+}
+
+/// Replace the text of the message with the RAG prompt built from the retrieved chunks.
+pub fn augment_message(message: &mut Message, results: &[RagResult]) {
+    let input = message.content.to_text();
+    let context = results
+        .iter()
+        .map(|v| format!("Source: {}\n{}", v.document, v.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let prompt = RAG_TEMPLATE
+        .replace("__CONTEXT__", &context)
+        .replace("__INPUT__", &input);
+    message.content = match &message.content {
+        MessageContent::Array(parts) => {
+            let mut parts: Vec<MessageContentPart> = parts
+                .iter()
+                .filter(|v| !matches!(v, MessageContentPart::Text { .. }))
+                .cloned()
+                .collect();
+            parts.insert(0, MessageContentPart::Text { text: prompt });
+            MessageContent::Array(parts)
+        }
+        _ => MessageContent::Text(prompt),
+    };
+}
+
+/// Knowledge base names end up in file paths, so they are restricted to a safe charset.
+pub fn validate_name(name: &str) -> Result<()> {
+    RAGS.validate_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{mock_embed, ImageUrl, MessageRole};
+
+    /// A document name with the text and embedding of each of its chunks
+    type Document<'a> = (&'a str, &'a [(&'a str, [f32; 3])]);
+
+    fn rag(documents: &[Document]) -> Rag {
+        let model = Model::new("mock", "embed");
+        let mut rag = Rag::new("test", &model, &RagConfig::default(), None, None, None).unwrap();
+        for (name, chunks) in documents {
+            let chunks = chunks
+                .iter()
+                .map(|(text, embedding)| RagChunk {
+                    text: text.to_string(),
+                    embedding: embedding.to_vec(),
+                })
+                .collect();
+            let document = RagDocument {
+                added_at: now(),
+                chunks,
+            };
+            rag.documents.insert(name.to_string(), document);
+        }
+        rag
+    }
+
+    /// A knowledge base of one document, its chunks embedded by the mock client
+    fn embedded_rag(texts: &[String]) -> Rag {
+        let model = Model::new("mock", "embed");
+        let mut rag = Rag::new("test", &model, &RagConfig::default(), None, None, None).unwrap();
+        let chunks = texts
+            .iter()
+            .map(|text| RagChunk {
+                text: text.clone(),
+                embedding: mock_embed(text),
+            })
+            .collect();
+        let document = RagDocument {
+            added_at: now(),
+            chunks,
+        };
+        rag.documents.insert("handbook.md".into(), document);
+        rag
+    }
+
+    #[test]
+    fn test_new() {
+        let model = Model::new("mock", "embed");
+        let config = RagConfig::default();
+        let rag = Rag::new("docs", &model, &config, None, None, None).unwrap();
+        assert_eq!(rag.embedding_model, "mock:embed");
+        assert_eq!((rag.chunk_size, rag.chunk_overlap), (1000, 50));
+        assert_eq!(rag.top_k, DEFAULT_TOP_K);
+
+        let config = RagConfig {
+            chunk_size: Some(200),
+            top_k: Some(8),
+            ..Default::default()
+        };
+        let rag = Rag::new("docs", &model, &config, None, Some(0), Some(2)).unwrap();
+        assert_eq!((rag.chunk_size, rag.chunk_overlap, rag.top_k), (200, 0, 2));
+
+        assert!(Rag::new("docs", &model, &config, Some(100), Some(100), None).is_err());
+        assert!(Rag::new("../docs", &model, &config, None, None, None).is_err());
+        assert!(Rag::new("", &model, &config, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        assert!(rag(&[])
+            .search("cats", Some(&[1.0, 0.0, 0.0]), 4, 0.5)
+            .is_empty());
+
+        let rag = rag(&[
+            (
+                "cats.md",
+                &[
+                    ("Cats purr.", [1.0, 0.0, 0.0]),
+                    ("Cats nap.", [0.9, 0.1, 0.0]),
+                ],
+            ),
+            ("dogs.md", &[("Dogs bark.", [0.0, 1.0, 0.0])]),
+        ]);
+        let texts = |results: Vec<RagResult>| -> Vec<String> {
+            results.into_iter().map(|v| v.text).collect()
+        };
+
+        // By embedding only, scored by cosine similarity
+        let results = rag.search("", Some(&[0.0, 1.0, 0.0]), 2, 1.0);
+        assert_eq!(results[0].document, "dogs.md");
+        assert!((results[0].score - 1.0).abs() < 1e-4);
+        assert_eq!(texts(results), ["Dogs bark.", "Cats nap."]);
+
+        // By keyword only, without an embedding or with a zero vector weight
+        assert_eq!(texts(rag.search("purr", None, 4, 0.5)), ["Cats purr."]);
+        let results = rag.search("purr", Some(&[0.0, 1.0, 0.0]), 4, 0.0);
+        assert_eq!(texts(results), ["Cats purr."]);
+
+        let results = rag.search("nap", Some(&[1.0, 0.0, 0.0]), 3, 0.5);
+        assert_eq!(texts(results)[..2], ["Cats nap.", "Cats purr."]);
+    }
+
+    #[test]
+    fn test_search_fusion() {
+        let query = "PTO_POLICY_2024";
+        let embedding = mock_embed(query);
+        // More chunks than the fused depth share the words of the identifier, not the
+        // identifier itself, so they rank above its chunk by embedding
+        let mut texts: Vec<String> = (0..MIN_FUSED_CANDIDATES + 10)
+            .map(|i| format!("Vacation day {i} follows the PTO policy of 2024."))
+            .collect();
+        let identifier = "Form PTO_POLICY_2024 replaced the paper request of leave.";
+        texts.push(identifier.to_string());
+        let rag = embedded_rag(&texts);
+        let position = |results: &[RagResult]| results.iter().position(|v| v.text == identifier);
+
+        let results = rag.search(query, Some(&embedding), texts.len(), 1.0);
+        assert!(position(&results).unwrap() >= MIN_FUSED_CANDIDATES);
+        for result in &results {
+            let score = cosine_similarity(&embedding, &mock_embed(&result.text));
+            assert!((result.score as f64 - score).abs() < 1e-4);
+        }
+        assert_eq!(position(&rag.search(query, Some(&embedding), 4, 1.0)), None);
+
+        let texts: Vec<&str> = texts.iter().map(|v| v.as_str()).collect();
+        let keywords = Bm25Index::build(&texts).search(query, 4);
+        for results in [
+            rag.search(query, Some(&embedding), 4, 0.0),
+            rag.search(query, None, 4, 0.5),
+        ] {
+            assert_eq!(results.len(), keywords.len());
+            assert_eq!(results[0].text, identifier);
+            assert_eq!(results[0].score, keywords[0].1);
+        }
+
+        let results = rag.search(query, Some(&embedding), 4, 0.5);
+        assert!(position(&results).is_some());
+        assert!(results.iter().all(|v| v.score < 1.0 / RRF_K));
+    }
+
+    #[test]
+    fn test_search_depth() {
+        let rag = rag(&[
+            (
+                "cats.md",
+                &[
+                    ("Cats nap.", [1.0, 0.0, 0.0]),
+                    ("Cats purr loudly at night.", [0.9, 0.1, 0.0]),
+                    ("Purr.", [0.0, 0.1, 1.0]),
+                ],
+            ),
+            ("dogs.md", &[("Dogs bark.", [0.1, 1.0, 0.0])]),
+        ]);
+        let search = |vector_weight| {
+            let results = rag.search("purr", Some(&[1.0, 0.0, 0.0]), 1, vector_weight);
+            results[0].text.clone()
+        };
+        assert_eq!(search(1.0), "Cats nap.");
+        assert_eq!(search(0.0), "Purr.");
+        // Second in both rankings, beyond the top 1 of each but not the fused depth
+        assert_eq!(search(0.5), "Cats purr loudly at night.");
+    }
+
+    #[test]
+    fn test_augment_message() {
+        let results = [RagResult {
+            document: "cats.md".into(),
+            text: "Cats purr.".into(),
+            score: 1.0,
+        }];
+        let mut message = Message::new(
+            MessageRole::User,
+            MessageContent::Text("Do cats purr?".into()),
+        );
+        augment_message(&mut message, &results);
+        let MessageContent::Text(text) = &message.content else {
+            panic!("Expected a text message");
+        };
+        assert!(text.contains("Source: cats.md\nCats purr."));
+        assert!(text.contains("Question: Do cats purr?"));
+
+        // The images of the message are kept after the prompt
+        let image = MessageContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: "data:image/png;base64,AA==".into(),
+                detail: None,
+            },
+        };
+        let text = MessageContentPart::Text {
+            text: "What breed is this cat?".into(),
+        };
+        let mut message = Message::new(MessageRole::User, MessageContent::Array(vec![text, image]));
+        augment_message(&mut message, &results);
+        let MessageContent::Array(parts) = &message.content else {
+            panic!("Expected a multipart message");
+        };
+        assert_eq!(parts.len(), 2);
+        assert!(
+            matches!(&parts[0], MessageContentPart::Text { text } if text.contains("Question: What breed is this cat?"))
+        );
+        assert!(matches!(parts[1], MessageContentPart::ImageUrl { .. }));
+    }
+}
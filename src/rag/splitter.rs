@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_SEPARATORS: [&str; 4] = ["\n\n", "\n", " ", ""];
+pub const MARKDOWN_SEPARATORS: [&str; 10] = [
+    "\n# ", "\n## ", "\n### ", "\n#### ", "\n```", "\n---", "\n\n", "\n", " ", "",
+];
+
+/// Split the text into chunks of at most `chunk_size` characters, preferring the earlier
+/// separators as boundaries, consecutive chunks sharing up to `chunk_overlap` characters.
+pub fn split_text(
+    text: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    separators: &[&str],
+) -> Vec<String> {
+    let mut chunks = vec![];
+    split_into(
+        text,
+        chunk_size.max(1),
+        chunk_overlap.min(chunk_size / 2),
+        separators,
+        &mut chunks,
+    );
+    chunks
+}
+
+fn split_into(
+    text: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    separators: &[&str],
+    output: &mut Vec<String>,
+) {
+    let index = separators
+        .iter()
+        .position(|v| v.is_empty() || text.contains(v));
+    let Some(index) = index.filter(|i| !separators[*i].is_empty()) else {
+        // No separator left, cut at the character count.
+        let chars: Vec<char> = text.chars().collect();
+        let step = chunk_size - chunk_overlap;
+        for start in (0..chars.len()).step_by(step) {
+            let end = (start + chunk_size).min(chars.len());
+            push_chunk(&chars[start..end].iter().collect::<String>(), output);
+            if end == chars.len() {
+                break;
+            }
+        }
+        return;
+    };
+    let separator = separators[index];
+    let rest = &separators[index + 1..];
+
+    let mut pieces = vec![];
+    for piece in split_before(text, separator) {
+        if len(piece) > chunk_size {
+            merge(&pieces, chunk_size, chunk_overlap, output);
+            pieces.clear();
+            split_into(piece, chunk_size, chunk_overlap, rest, output);
+        } else {
+            pieces.push(piece);
+        }
+    }
+    merge(&pieces, chunk_size, chunk_overlap, output);
+}
+
+/// Split the text before every occurrence of the separator, so that joining the pieces
+/// gives the text back.
+fn split_before<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    for (i, _) in text.match_indices(separator) {
+        if i > start {
+            pieces.push(&text[start..i]);
+            start = i;
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+fn merge(pieces: &[&str], chunk_size: usize, chunk_overlap: usize, output: &mut Vec<String>) {
+    let mut current: VecDeque<&str> = VecDeque::new();
+    let mut current_len = 0;
+    for piece in pieces {
+        let piece_len = len(piece);
+        if current_len + piece_len > chunk_size && !current.is_empty() {
+            push_chunk(&current.iter().copied().collect::<String>(), output);
+            while current_len > chunk_overlap || current_len + piece_len > chunk_size {
+                let Some(first) = current.pop_front() else {
+                    break;
+                };
+                current_len -= len(first);
+            }
+        }
+        current.push_back(piece);
+        current_len += piece_len;
+    }
+    if !current.is_empty() {
+        push_chunk(&current.iter().copied().collect::<String>(), output);
+    }
+}
+
+fn push_chunk(chunk: &str, output: &mut Vec<String>) {
+    let chunk = chunk.trim();
+    if !chunk.is_empty() {
+        output.push(chunk.to_string());
+    }
+}
+
+fn len(text: &str) -> usize {
+    text.chars().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_text() {
+        let text = "# Title\n\nFirst paragraph.\n\n## Section\n\nSecond paragraph, a bit longer.";
+        let chunks = split_text(text, 40, 0, &MARKDOWN_SEPARATORS);
+        assert_eq!(
+            chunks,
+            vec![
+                "# Title\n\nFirst paragraph.",
+                "## Section",
+                "Second paragraph, a bit longer."
+            ]
+        );
+        assert!(chunks.iter().all(|v| v.chars().count() <= 40));
+
+        let chunks = split_text("one two three four five six", 10, 5, &DEFAULT_SEPARATORS);
+        assert_eq!(
+            chunks,
+            vec!["one two", "two three", "four five", "five six"]
+        );
+    }
+}
@@ -1,9 +1,24 @@
-use crate::{client::*, config::*, utils::*};
+use crate::{
+    admin::{self, AdminConfig},
+    client::*,
+    config::*,
+    escalation::{self, Escalations},
+    function::{Function, FunctionDeclaration, ToolCall, ToolCallResult, ToolsConfig},
+    health::{self, HealthConfig},
+    mcp,
+    metrics::{CallLabels, METRICS, UNKNOWN_LABEL},
+    overflow::{drop_oldest, middle_out, OverflowStrategy, Trimmed},
+    rag::{self, augment_message, cosine_similarity, Rag, RagConfig, RagResult},
+    summarize::{summary_message, summary_range, summary_request, SummarizeConfig},
+    telemetry::{self, gen_ai_system, parse_traceparent, Span, SpanContext},
+    utils::*,
+    vector_store::{self, VectorDocumentInput, VectorStore},
+};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
 use chrono::{Timelike, Utc};
-use futures_util::StreamExt;
+use futures_util::{future::join_all, StreamExt};
 use http::{Method, Response, StatusCode};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::{
@@ -11,15 +26,25 @@ use hyper::{
     service::service_fn,
 };
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use parking_lot::RwLock;
+use indexmap::{IndexMap, IndexSet};
+use parking_lot::{Mutex, RwLock};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{convert::Infallible, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::remove_file,
+    net::IpAddr,
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     net::TcpListener,
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        oneshot, Notify,
     },
 };
 use tokio_graceful::Shutdown;
@@ -27,6 +52,26 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:8000";
 const DEFAULT_MODEL_NAME: &str = "default";
+const AGENT_HEADER: &str = "X-Agent-Panel-Agent";
+const UNKNOWN_AGENT: &str = "unknown";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const COST_HEADER: &str = "X-Agent-Panel-Cost";
+const SUMMARIZE_HEADER: &str = "X-Agent-Panel-Summarize";
+const SUMMARIZED_HEADER: &str = "X-Agent-Panel-Summarized";
+const TRIMMED_HEADER: &str = "X-Agent-Panel-Trimmed";
+const MODEL_HEADER: &str = "X-Agent-Panel-Model";
+const DEFAULT_SPANS_LIMIT: usize = 100;
+const DEFAULT_TRACES_LIMIT: usize = 50;
+/// The cells of the line table of a replay diff, 8 MB, above which lines are not matched
+const MAX_DIFF_CELLS: usize = 1_000_000;
+const DEFAULT_VECTOR_QUERY_TOP_K: usize = 10;
+/// The retrieved chunks sent to the rerank model, per chunk kept
+const RERANK_CANDIDATES_FACTOR: usize = 4;
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const DASHBOARD_HTML: &str = include_str!("../assets/ui/index.html");
+const DASHBOARD_JS: &str = include_str!("../assets/ui/app.js");
+const DASHBOARD_CSS: &str = include_str!("../assets/ui/style.css");
 
 type AppResponse = Response<BoxBody<Bytes, Infallible>>;
 
@@ -35,8 +80,21 @@ pub async fn run(config: GlobalConfig, addr: Option<u16>) -> Result<()> {
         Some(port) =>   format!("127.0.0.1:{port}"),
         None => DEFAULT_ADDRESS.to_string(),
     };
-    let server = Arc::new(Server::new(&config));
+    telemetry::init(&config.read().telemetry)?;
+    let server = Arc::new(Server::new(&config)?);
+    let (mcp_servers, tools, tokenizers) = {
+        let config = config.read();
+        (
+            config.mcp_servers.clone(),
+            config.tools.clone(),
+            config.tokenizers.clone(),
+        )
+    };
+    mcp::sync(&mcp_servers, &tools).await;
+    tokenizer::sync(&tokenizers).await;
     let listener = TcpListener::bind(&addr).await?;
+    watch_config(server.clone());
+    watch_health(server.clone());
     let stop_server = server.run(listener).await?;
     info!("Chat Completions API: http://{addr}/v1/chat/completions");
     
@@ -46,22 +104,93 @@ pub async fn run(config: GlobalConfig, addr: Option<u16>) -> Result<()> {
 }
 
 struct Server {
+    config: GlobalConfig,
+    state: RwLock<Arc<ServerState>>,
+    /// Serializes the changes made through the admin API
+    admin_lock: Mutex<()>,
+    /// Wakes up the provider probes when the config is reloaded
+    probe_notify: Notify,
+    /// Serializes the turns of each session
+    session_locks: NamedLocks,
+    /// Serializes the changes of each knowledge base
+    rag_locks: NamedLocks,
+    /// Serializes the changes of each vector store
+    vector_store_locks: NamedLocks,
+    /// Where the sessions are saved, resolved once at startup
+    sessions_dir: PathBuf,
+}
+
+type LockMap = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+/// Async locks by name, an entry being dropped once its lock is neither held nor awaited.
+#[derive(Debug, Default)]
+struct NamedLocks(Arc<LockMap>);
+
+impl NamedLocks {
+    async fn lock(&self, name: &str) -> NamedLockGuard {
+        let lock = self.0.lock().entry(name.to_string()).or_default().clone();
+        NamedLockGuard {
+            guard: Some(lock.lock_owned().await),
+            locks: self.0.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+}
+
+struct NamedLockGuard {
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    locks: Arc<LockMap>,
+    name: String,
+}
+
+impl Drop for NamedLockGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock();
+        self.guard.take();
+        if locks
+            .get(&self.name)
+            .is_some_and(|v| Arc::strong_count(v) == 1)
+        {
+            locks.remove(&self.name);
+        }
+    }
+}
+
+/// The clients and models being served, swapped as a whole when the config is reloaded
+/// so that in-flight requests finish on the state they started with.
+struct ServerState {
     clients: Vec<ClientConfig>,
     model: Model,
     models: Vec<Value>,
+    admin: AdminConfig,
+    health: HealthConfig,
+    summarize: SummarizeConfig,
+    rag: RagConfig,
+    function: Function,
+    function_calling: bool,
+    overflow_strategy: OverflowStrategy,
+    escalations: Escalations,
+    tools: ToolsConfig,
+    /// The raw config document, edited through the admin API
+    document: Value,
 }
 
-impl Server {
-    fn new(config: &GlobalConfig) -> Self {
-        let config = config.read();
+impl ServerState {
+    fn new(config: &Config, document: Value) -> Self {
         let clients = config.clients.clone();
         let model = config.model.clone();
-        let mut models = list_chat_models(&config);
+        let mut models = list_chat_models(config);
+        models.retain(|v| !v.disabled());
         let mut default_model = model.clone();
         default_model.data_mut().name = DEFAULT_MODEL_NAME.into();
-        models.insert(0, &default_model);
+        models.insert(0, default_model);
         let models: Vec<Value> = models
-            .into_iter()
+            .iter()
             .enumerate()
             .map(|(i, model)| {
                 let id = if i == 0 {
@@ -90,13 +219,75 @@ impl Server {
                     "supports_function_calling": supports_function_calling,
                 })
             })
+            .chain(
+                config
+                    .escalations
+                    .iter()
+                    .filter(|(alias, _)| !alias.contains(':'))
+                    .map(|(alias, ladder)| json!({ "id": alias, "escalation": ladder })),
+            )
             .collect();
         Self {
             clients,
             model,
             models,
+            admin: config.admin.clone(),
+            health: config.health.clone(),
+            summarize: config.summarize.clone(),
+            rag: config.rag.clone(),
+            function: config.function.clone(),
+            function_calling: config.function_calling,
+            overflow_strategy: config.overflow_strategy,
+            escalations: config.escalations.clone(),
+            tools: config.tools.clone(),
+            document,
         }
     }
+}
+
+impl Server {
+    fn new(config: &GlobalConfig) -> Result<Self> {
+        let state = ServerState::new(&config.read(), Config::load_document()?);
+        Ok(Self {
+            config: config.clone(),
+            state: RwLock::new(Arc::new(state)),
+            admin_lock: Mutex::new(()),
+            probe_notify: Notify::new(),
+            session_locks: Default::default(),
+            rag_locks: Default::default(),
+            vector_store_locks: Default::default(),
+            sessions_dir: Config::sessions_dir()?,
+        })
+    }
+
+    fn state(&self) -> Arc<ServerState> {
+        self.state.read().clone()
+    }
+
+    /// Load and validate the config file again, then swap in the new clients and models.
+    fn reload(&self) -> Result<()> {
+        let document = Config::load_document()?;
+        let config = Config::load()?;
+        self.swap(config, document);
+        Ok(())
+    }
+
+    fn swap(&self, config: Config, document: Value) {
+        let state = ServerState::new(&config, document);
+        info!(
+            "Reloaded config, serving {} client(s) and {} model(s)",
+            state.clients.len(),
+            state.models.len() - 1
+        );
+        *self.state.write() = Arc::new(state);
+        let (mcp_servers, tools) = (config.mcp_servers.clone(), config.tools.clone());
+        tokio::spawn(async move { mcp::sync(&mcp_servers, &tools).await });
+        let tokenizers = config.tokenizers.clone();
+        tokio::spawn(async move { tokenizer::sync(&tokenizers).await });
+        *self.config.write() = config;
+        self.probe_notify.notify_one();
+    }
+
     async fn run(self: Arc<Self>, listener: TcpListener) -> Result<oneshot::Sender<()>> {
         let (tx, rx) = oneshot::channel();
         tokio::spawn(async move {
@@ -137,19 +328,77 @@ impl Server {
         let method = req.method().clone();
         let uri = req.uri().clone();
         let path = uri.path();
+        // The admin API, the dashboard and the traces, holding the prompts, need the admin
+        // token and are not shared with other origins
+        let private = path.starts_with("/admin/")
+            || path == "/ui"
+            || path.starts_with("/ui/")
+            || path == "/v1/spans"
+            || path == "/v1/traces"
+            || path.starts_with("/v1/traces/");
 
         if method == Method::OPTIONS {
             let mut res = Response::default();
             *res.status_mut() = StatusCode::NO_CONTENT;
-            set_cors_header(&mut res);
+            if !private {
+                set_cors_header(&mut res);
+            }
             return Ok(res);
         }
 
         let mut status = StatusCode::OK;
-        let res = if path == "/v1/chat/completions" {
+        let authorization = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let res = if let Some(err) = private
+            .then(|| self.state().admin.check_token(authorization).err())
+            .flatten()
+        {
+            status = StatusCode::UNAUTHORIZED;
+            Err(err)
+        } else if path == "/health" {
+            ret_json(&json!({ "status": "ok" }))
+        } else if path == "/ready" {
+            self.ready(&mut status)
+        } else if path == "/v1/providers/status" {
+            ret_json(&json!({ "data": health::statuses() }))
+        } else if path == "/v1/chat/completions" {
             self.chat_completion(req).await
         } else if path == "/v1/models" {
             self.list_models()
+        } else if path == "/v1/rerank" {
+            self.rerank_documents(req).await
+        } else if path == "/v1/tokenize" || path == "/v1/messages/count_tokens" {
+            self.count_tokens(req).await
+        } else if path == "/metrics" {
+            self.metrics()
+        } else if path == "/v1/spans" {
+            self.list_spans(&uri)
+        } else if path == "/v1/traces" {
+            self.list_traces(&uri)
+        } else if let Some(span_id) = path
+            .strip_prefix("/v1/traces/")
+            .and_then(|v| v.strip_suffix("/replay"))
+        {
+            if method != Method::POST {
+                status = StatusCode::METHOD_NOT_ALLOWED;
+                Err(anyhow!("The replay endpoint only accepts POST requests."))
+            } else {
+                self.replay(span_id, req).await
+            }
+        } else if let Some(trace_id) = path.strip_prefix("/v1/traces/") {
+            self.get_trace(trace_id)
+        } else if path == "/ui" || path.starts_with("/ui/") {
+            self.dashboard(path)
+        } else if path == "/v1/sessions" || path.starts_with("/v1/sessions/") {
+            self.sessions(&method, path, req, &mut status).await
+        } else if path == "/v1/knowledge_bases" || path.starts_with("/v1/knowledge_bases/") {
+            self.knowledge_bases(&method, path, req, &mut status).await
+        } else if path == "/v1/vector_stores" || path.starts_with("/v1/vector_stores/") {
+            self.vector_stores(&method, path, req, &mut status).await
+        } else if path.starts_with("/admin/") {
+            self.admin(&method, path, req, &mut status).await
         } else {
             status = StatusCode::NOT_FOUND;
             Err(anyhow!("The requested endpoint was not found."))
@@ -160,233 +409,2321 @@ impl Server {
                 res
             }
             Err(err) => {
-                status = StatusCode::BAD_REQUEST;
+                if status == StatusCode::OK {
+                    status = StatusCode::BAD_REQUEST;
+                }
                 error!("{method} {uri} {} {err}", status.as_u16());
                 ret_err(err)
             }
         };
+        if status == StatusCode::UNAUTHORIZED && (path == "/ui" || path.starts_with("/ui/")) {
+            res.headers_mut().insert(
+                hyper::header::WWW_AUTHENTICATE,
+                hyper::header::HeaderValue::from_static("Basic realm=\"agent-panel\""),
+            );
+        }
         *res.status_mut() = status;
-        set_cors_header(&mut res);
+        if !private {
+            set_cors_header(&mut res);
+        }
         Ok(res)
     }
 
     fn list_models(&self) -> Result<AppResponse> {
-        let data = json!({ "data": self.models });
+        let data = json!({ "data": self.state().models });
         let res = Response::builder()
             .header("Content-Type", "application/json; charset=utf-8")
             .body(Full::new(Bytes::from(data.to_string())).boxed())?;
         Ok(res)
     }
 
-    async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
-        let req_body = req.collect().await?.to_bytes();
-        let req_body: ChatCompletionReqBody = serde_json::from_slice(&req_body)
-            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
-
-        let ChatCompletionReqBody {
-            model,
-            messages,
-            temperature,
-            top_p,
-            max_tokens,
-            stream,
-        } = req_body;
+    fn ready(&self, status: &mut StatusCode) -> Result<AppResponse> {
+        let state = self.state();
+        let usable = health::usable_clients(&state.clients);
+        if usable.is_empty() {
+            let reason = if state.clients.is_empty() {
+                "No client is configured"
+            } else {
+                "No client is usable, see /v1/providers/status"
+            };
+            *status = StatusCode::SERVICE_UNAVAILABLE;
+            return ret_json(&json!({ "status": "unavailable", "reason": reason }));
+        }
+        ret_json(&json!({ "status": "ready", "clients": usable }))
+    }
 
-        log::debug!(
-            "Chat completion request: model={model}, messages={messages:?}, temperature={temperature:?}, top_p={top_p:?}, max_tokens={max_tokens:?}, stream={stream}"
-        );
-        let config = Config {
-            clients: self.clients.to_vec(),
-            model: self.model.clone(),
-            ..Default::default()
-        };
-        let config = Arc::new(RwLock::new(config));
+    fn metrics(&self) -> Result<AppResponse> {
+        let res = Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(Full::new(Bytes::from(METRICS.render())).boxed())?;
+        Ok(res)
+    }
 
-        let (model_name, change) = if model == DEFAULT_MODEL_NAME {
-            (self.model.id(), true)
-        } else if self.model.id() == model {
-            (model, false)
-        } else {
-            (model, true)
-        };
+    fn list_spans(&self, uri: &http::Uri) -> Result<AppResponse> {
+        let limit = parse_limit(uri, DEFAULT_SPANS_LIMIT)?;
+        let spans: Vec<Value> = telemetry::recent_spans(limit)
+            .iter()
+            .map(|span| span.to_json())
+            .collect();
+        ret_json(&json!({ "data": spans }))
+    }
 
-        log::debug!("Model name: {}", model_name);
-        if change {
-            config.write().set_model(&model_name)?;
+    fn list_traces(&self, uri: &http::Uri) -> Result<AppResponse> {
+        let limit = parse_limit(uri, DEFAULT_TRACES_LIMIT)?;
+        let mut traces: IndexMap<String, Vec<Span>> = IndexMap::new();
+        for span in telemetry::recent_spans(usize::MAX) {
+            if !traces.contains_key(&span.trace_id) && traces.len() >= limit {
+                continue;
+            }
+            traces.entry(span.trace_id.clone()).or_default().push(span);
         }
+        let traces: Vec<Value> = traces
+            .into_values()
+            .map(|mut spans| {
+                spans.sort_by_key(|span| span.start_time);
+                summarize_trace(&spans)
+            })
+            .collect();
+        ret_json(&json!({ "data": traces }))
+    }
 
-        let mut client = init_client(&config, None)?;
-        if max_tokens.is_some() {
-            client.model_mut().set_max_tokens(max_tokens, true);
+    fn get_trace(&self, trace_id: &str) -> Result<AppResponse> {
+        let spans = telemetry::trace_spans(trace_id);
+        if spans.is_empty() {
+            bail!("Trace '{trace_id}' not found");
         }
-        let abort = create_abort_signal();
-        let http_client = client.build_client()?;
+        let mut data = summarize_trace(&spans);
+        data["spans"] = spans.iter().map(|span| span.to_json()).collect();
+        ret_json(&data)
+    }
 
-        let completion_id = generate_completion_id();
-        let created = Utc::now().timestamp();
+    fn dashboard(&self, path: &str) -> Result<AppResponse> {
+        let (content_type, body) = match path {
+            "/ui" | "/ui/" | "/ui/index.html" => ("text/html; charset=utf-8", DASHBOARD_HTML),
+            "/ui/app.js" => ("application/javascript; charset=utf-8", DASHBOARD_JS),
+            "/ui/style.css" => ("text/css; charset=utf-8", DASHBOARD_CSS),
+            _ => bail!("The requested endpoint was not found."),
+        };
+        let res = Response::builder()
+            .header("Content-Type", content_type)
+            .body(Full::new(Bytes::from(body)).boxed())?;
+        Ok(res)
+    }
 
-        let data: ChatCompletionsData = ChatCompletionsData {
-            messages,
-            temperature,
-            top_p,
-            functions: None,
-            stream,
+    async fn admin(
+        &self,
+        method: &Method,
+        path: &str,
+        req: hyper::Request<Incoming>,
+        status: &mut StatusCode,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = if req_body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
         };
+        let path = urlencoding::decode(path)?;
 
-        if stream {
-            let (tx, mut rx) = unbounded_channel();
-            tokio::spawn(async move {
-                let mut is_first = true;
-                let (tx2, rx2) = unbounded_channel();
-                let mut handler = SseHandler::new(tx2, abort);
-                async fn map_event(
-                    mut rx: UnboundedReceiver<SseEvent>,
-                    tx: &UnboundedSender<ResEvent>,
-                    is_first: &mut bool,
-                ) {
-                    while let Some(reply_event) = rx.recv().await {
-                        if *is_first {
-                            let _ = tx.send(ResEvent::First(None));
-                            *is_first = false;
+        let _guard = self.admin_lock.lock();
+        let mut document = self.state().document.clone();
+        let data = match path.as_ref() {
+            "/admin/clients" if method == Method::GET => {
+                return ret_json(&json!({ "data": admin::list_clients(&document) }));
+            }
+            "/admin/clients" if method == Method::POST => {
+                let name = admin::client_name(&req_body).to_string();
+                admin::add_client(&mut document, req_body)?;
+                self.apply_document(document.clone())?;
+                admin::find_client(&document, &name)
+                    .map(|i| admin::client_summary(&document["clients"][i]))
+            }
+            "/admin/models" if method == Method::GET => {
+                return ret_json(&json!({ "data": admin::list_models(&document) }));
+            }
+            "/admin/models" if method == Method::POST => {
+                let client = req_body["client"].as_str().unwrap_or_default().to_string();
+                let Some(index) = admin::find_client(&document, &client) else {
+                    *status = StatusCode::NOT_FOUND;
+                    bail!("Client '{client}' not found");
+                };
+                let id = format!("{client}:{}", req_body["name"].as_str().unwrap_or_default());
+                admin::add_model(&mut document, index, req_body)?;
+                self.apply_document(document.clone())?;
+                find_model_summary(&document, &id)
+            }
+            "/admin/clients" | "/admin/models" => {
+                *status = StatusCode::METHOD_NOT_ALLOWED;
+                bail!("Method {method} is not allowed on {path}");
+            }
+            _ => {
+                if let Some(name) = path.strip_prefix("/admin/clients/") {
+                    let Some(index) = admin::find_client(&document, name) else {
+                        *status = StatusCode::NOT_FOUND;
+                        bail!("Client '{name}' not found");
+                    };
+                    match *method {
+                        Method::GET => Some(admin::client_summary(&document["clients"][index])),
+                        Method::PATCH => {
+                            admin::update_client(&mut document, index, &req_body)?;
+                            let summary = admin::client_summary(&document["clients"][index]);
+                            self.apply_document(document)?;
+                            Some(summary)
+                        }
+                        Method::DELETE => {
+                            admin::remove_client(&mut document, index)?;
+                            self.apply_document(document)?;
+                            Some(json!({ "name": name, "deleted": true }))
                         }
-                        match reply_event {
-                            SseEvent::Text(text) => {
-                                let _ = tx.send(ResEvent::Text(text));
-                            }
-                            SseEvent::Done => {
-                                let _ = tx.send(ResEvent::Done);
-                            }
+                        _ => {
+                            *status = StatusCode::METHOD_NOT_ALLOWED;
+                            bail!("Method {method} is not allowed on {path}");
                         }
                     }
-                }
-                tokio::select! {
-                    _ = map_event(rx2, &tx, &mut is_first) => {}
-                    ret = client.chat_completions_streaming_inner(&http_client, &mut handler, data) => {
-                        if let Err(err) = ret {
-                            send_first_event(&tx, Some(format!("{err:?}")), &mut is_first)
+                } else if let Some(id) = path.strip_prefix("/admin/models/") {
+                    let Some((index, _)) = admin::find_model(&document, id) else {
+                        *status = StatusCode::NOT_FOUND;
+                        bail!("Model '{id}' not found");
+                    };
+                    let (_, model_name) = id.split_once(':').unwrap_or_default();
+                    match *method {
+                        Method::GET => find_model_summary(&document, id),
+                        Method::PATCH => {
+                            admin::update_model(&mut document, index, model_name, &req_body)?;
+                            self.apply_document(document.clone())?;
+                            let id = match req_body["name"].as_str() {
+                                Some(new_name) => format!(
+                                    "{}:{new_name}",
+                                    admin::client_name(&document["clients"][index])
+                                ),
+                                None => id.to_string(),
+                            };
+                            find_model_summary(&document, &id)
+                        }
+                        Method::DELETE => {
+                            admin::remove_model(&mut document, index, model_name)?;
+                            self.apply_document(document)?;
+                            Some(json!({ "id": id, "deleted": true }))
+                        }
+                        _ => {
+                            *status = StatusCode::METHOD_NOT_ALLOWED;
+                            bail!("Method {method} is not allowed on {path}");
                         }
-                        let _ = tx.send(ResEvent::Done);
                     }
+                } else {
+                    *status = StatusCode::NOT_FOUND;
+                    bail!("The requested endpoint was not found.");
                 }
-            });
+            }
+        };
+        ret_json(&data.unwrap_or_default())
+    }
 
-            let first_event = rx.recv().await;
-
-            if let Some(ResEvent::First(Some(err))) = first_event {
-                bail!("{err}");
-            }
-
-            let shared: Arc<(String, String, i64)> = Arc::new((completion_id, model_name, created));
-            let stream = UnboundedReceiverStream::new(rx);
-            let stream = stream.filter_map(move |res_event| {
-                let shared = shared.clone();
-                async move {
-                    let (completion_id, model, created) = shared.as_ref();
-                    match res_event {
-                        ResEvent::Text(text) => Some(Ok(create_frame(
-                            completion_id,
-                            model,
-                            *created,
-                            &text,
-                            false,
-                        ))),
-                        ResEvent::Done => {
-                            Some(Ok(create_frame(completion_id, model, *created, "", true)))
-                        }
-                        _ => None,
+    async fn sessions(
+        &self,
+        method: &Method,
+        path: &str,
+        req: hyper::Request<Incoming>,
+        status: &mut StatusCode,
+    ) -> Result<AppResponse> {
+        let started = Instant::now();
+        let context = call_context(&req);
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = if req_body.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
+        };
+        let path = urlencoding::decode(path)?;
+
+        if path == "/v1/sessions" {
+            return match *method {
+                Method::GET => {
+                    let data: Vec<Value> = Session::list(&self.sessions_dir)
+                        .iter()
+                        .filter_map(|name| {
+                            let session = self.load_session(name).ok()?;
+                            Some(session_summary(&session, false))
+                        })
+                        .collect();
+                    ret_json(&json!({ "data": data }))
+                }
+                Method::POST => {
+                    let req_body: SessionReqBody = serde_json::from_value(req_body)
+                        .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                    let name = match &req_body.id {
+                        Some(name) => name.clone(),
+                        None => format!("sess_{}", hex_encode(&rand::random::<[u8; 8]>())),
+                    };
+                    validate_session_name(&name)?;
+                    let _guard = self.session_locks.lock(&name).await;
+                    if self.session_file(&name).exists() {
+                        *status = StatusCode::CONFLICT;
+                        bail!("Session '{name}' already exists");
                     }
+                    let config = Config {
+                        model: self.resolve_model(req_body.model.as_deref())?,
+                        temperature: req_body.temperature,
+                        top_p: req_body.top_p,
+                        ..Default::default()
+                    };
+                    let mut session = Session::new(&config, &name);
+                    session.set_compress_threshold(req_body.compress_threshold);
+                    session.set_function_matcher(req_body.function_matcher.as_deref());
+                    session.extend_messages(req_body.messages);
+                    session.save(&self.sessions_dir)?;
+                    ret_json(&session_summary(&session, true))
                 }
-            });
-            let res = Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "text/event-stream")
-                .header("Cache-Control", "no-cache")
-                .header("Connection", "keep-alive")
-                .body(BodyExt::boxed(StreamBody::new(stream)))?;
-            Ok(res)
-        } else {
-            let output = client.chat_completions_inner(&http_client, data).await?;
-            let res = Response::builder()
-                .header("Content-Type", "application/json")
-                .body(
-                    Full::new(ret_non_stream(
-                        &completion_id,
-                        &model_name,
-                        created,
-                        &output,
-                    ))
-                    .boxed(),
+                _ => {
+                    *status = StatusCode::METHOD_NOT_ALLOWED;
+                    bail!("Method {method} is not allowed on {path}");
+                }
+            };
+        }
+
+        let rest = path.strip_prefix("/v1/sessions/").unwrap_or_default();
+        let (name, is_messages) = match rest.split_once('/') {
+            None => (rest, false),
+            Some((name, "messages")) => (name, true),
+            Some(_) => {
+                *status = StatusCode::NOT_FOUND;
+                bail!("The requested endpoint was not found.");
+            }
+        };
+        validate_session_name(name)?;
+        let guard = self.session_locks.lock(name).await;
+        if !self.session_file(name).exists() {
+            *status = StatusCode::NOT_FOUND;
+            bail!("Session '{name}' not found");
+        }
+        let mut session = self.load_session(name)?;
+        match *method {
+            Method::POST if is_messages => {
+                let SessionMessageReqBody {
+                    content,
+                    max_tokens,
+                    stream,
+                    overflow_strategy,
+                } = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                let sessions_dir = self.sessions_dir.clone();
+                let threshold = self.state().summarize.threshold(&session.model);
+                let mut summarized = 0;
+                if session.need_compress(threshold) {
+                    let model = session.model.clone();
+                    if let Some((range, summary)) =
+                        self.summarize(session.messages(), &model, &context).await
+                    {
+                        summarized = range.len();
+                        session.compress_range(range, summary);
+                        session.save(&sessions_dir)?;
+                    }
+                }
+                let (mut client, model_name) = self.create_call_client(
+                    session.model_id().to_string(),
+                    max_tokens,
+                    &context.agent,
                 )?;
-            Ok(res)
+                let functions = match session.function_matcher() {
+                    Some(matcher) => Some(self.select_functions(matcher)?),
+                    None => None,
+                };
+                let tools = tool_declarations(functions.as_deref());
+                client.model_mut().set_tools(&tools);
+                let mut messages = session.build_messages_with(content.clone());
+                let ladder = escalation::ladder(&self.state().escalations, session.model_id());
+                let requested_model = model_name.clone();
+                let (client, model_name) = match &ladder {
+                    Some(ladder) => {
+                        self.escalate(client, model_name, ladder, &messages, &tools, max_tokens)?
+                    }
+                    None => (client, model_name),
+                };
+                if model_name != requested_model {
+                    METRICS.inc_fallbacks(&requested_model, &model_name, &context.agent);
+                }
+                if functions.is_some() {
+                    check_function_calling(client.model())?;
+                }
+                let final_model = model_name.clone();
+                let trimmed = self
+                    .fit_context(&mut messages, client.model(), overflow_strategy, &context)
+                    .await?;
+                let data = ChatCompletionsData {
+                    messages,
+                    temperature: session.temperature(),
+                    top_p: session.top_p(),
+                    functions,
+                    stream,
+                };
+                // The lock is held until the reply is saved, so that turns do not interleave.
+                let on_reply: OnReply = Box::new(move |output| {
+                    let _guard = guard;
+                    session.add_turn(content, &output.text);
+                    session.save(&sessions_dir)
+                });
+                let mut res = if data.functions.is_some() {
+                    self.complete_with_tools(
+                        client,
+                        model_name,
+                        data,
+                        None,
+                        &context,
+                        Some(on_reply),
+                    )
+                    .await?
+                } else {
+                    let mut observer = CallObserver::new(
+                        client.as_ref(),
+                        &context.agent,
+                        context.parent_span.as_ref(),
+                        started,
+                        &data,
+                    );
+                    observer
+                        .span
+                        .set_attribute("agent_panel.session", name.to_string());
+                    complete(client, model_name, data, observer, Some(on_reply)).await?
+                };
+                if summarized > 0 {
+                    res.headers_mut()
+                        .insert(SUMMARIZED_HEADER, summarized.into());
+                }
+                set_trimmed_header(&mut res, trimmed)?;
+                if ladder.is_some() {
+                    res.headers_mut().insert(MODEL_HEADER, final_model.parse()?);
+                }
+                Ok(res)
+            }
+            Method::GET if !is_messages => ret_json(&session_summary(&session, true)),
+            Method::PATCH if !is_messages => {
+                let req_body: SessionReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                if req_body.id.is_some() || !req_body.messages.is_empty() {
+                    bail!("Only 'model', 'temperature', 'top_p', 'compress_threshold' and 'function_matcher' can be updated");
+                }
+                if let Some(model) = &req_body.model {
+                    session.set_model(&self.resolve_model(Some(model))?);
+                }
+                if req_body.temperature.is_some() {
+                    session.set_temperature(req_body.temperature);
+                }
+                if req_body.top_p.is_some() {
+                    session.set_top_p(req_body.top_p);
+                }
+                if req_body.compress_threshold.is_some() {
+                    session.set_compress_threshold(req_body.compress_threshold);
+                }
+                if req_body.function_matcher.is_some() {
+                    session.set_function_matcher(req_body.function_matcher.as_deref());
+                }
+                session.save(&self.sessions_dir)?;
+                ret_json(&session_summary(&session, true))
+            }
+            Method::DELETE if !is_messages => {
+                remove_file(self.session_file(name))
+                    .with_context(|| format!("Failed to delete session '{name}'"))?;
+                ret_json(&json!({ "id": name, "deleted": true }))
+            }
+            _ => {
+                *status = StatusCode::METHOD_NOT_ALLOWED;
+                bail!("Method {method} is not allowed on {path}");
+            }
         }
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct ChatCompletionReqBody {
-    model: String,
-    messages: Vec<Message>,
-    temperature: Option<f64>,
-    top_p: Option<f64>,
-    max_tokens: Option<isize>,
-    #[serde(default)]
-    stream: bool,
-}
+    async fn knowledge_bases(
+        &self,
+        method: &Method,
+        path: &str,
+        req: hyper::Request<Incoming>,
+        status: &mut StatusCode,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = if req_body.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
+        };
 
-#[derive(Debug)]
-enum ResEvent {
-    First(Option<String>),
-    Text(String),
-    Done,
-}
+        if path == "/v1/knowledge_bases" {
+            return match *method {
+                Method::GET => {
+                    let data: Vec<Value> = Rag::list()?
+                        .iter()
+                        .filter_map(|name| Some(rag_summary(&*Rag::load(name).ok()?, false)))
+                        .collect();
+                    ret_json(&json!({ "data": data }))
+                }
+                Method::POST => {
+                    let req_body: RagReqBody = serde_json::from_value(req_body)
+                        .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                    let name = req_body
+                        .name
+                        .ok_or_else(|| anyhow!("Missing 'name' of the knowledge base"))?;
+                    rag::validate_name(&name)?;
+                    let _guard = self.rag_locks.lock(&name).await;
+                    if Rag::exists(&name)? {
+                        *status = StatusCode::CONFLICT;
+                        bail!("Knowledge base '{name}' already exists");
+                    }
+                    let config = self.state().rag.clone();
+                    let embedding_model = req_body
+                        .embedding_model
+                        .or_else(|| config.embedding_model.clone())
+                        .ok_or_else(|| {
+                            anyhow!("Missing 'embedding_model', and no 'rag.embedding_model' is configured")
+                        })?;
+                    let client = self.create_embedding_client(&embedding_model)?;
+                    let mut rag = Rag::new(
+                        &name,
+                        client.model(),
+                        &config,
+                        req_body.chunk_size,
+                        req_body.chunk_overlap,
+                        req_body.top_k,
+                    )?;
+                    let documents = read_documents(req_body.documents, config).await?;
+                    if !documents.is_empty() {
+                        rag.add_documents(documents, client.as_ref()).await?;
+                    }
+                    ret_json(&rag_summary(&*rag.save()?, true))
+                }
+                _ => {
+                    *status = StatusCode::METHOD_NOT_ALLOWED;
+                    bail!("Method {method} is not allowed on {path}");
+                }
+            };
+        }
 
-fn send_first_event(tx: &UnboundedSender<ResEvent>, data: Option<String>, is_first: &mut bool) {
-    if *is_first {
-        let _ = tx.send(ResEvent::First(data));
-        *is_first = false;
+        let rest = path
+            .strip_prefix("/v1/knowledge_bases/")
+            .unwrap_or_default();
+        let mut segments = rest.splitn(3, '/');
+        let name = segments.next().unwrap_or_default();
+        let action = segments.next();
+        let document = segments.next().map(urlencoding::decode).transpose()?;
+        rag::validate_name(name)?;
+        if !Rag::exists(name)? {
+            *status = StatusCode::NOT_FOUND;
+            bail!("Knowledge base '{name}' not found");
+        }
+        match (method.clone(), action, document) {
+            (Method::GET, None, None) => ret_json(&rag_summary(&*Rag::load(name)?, true)),
+            (Method::DELETE, None, None) => {
+                let _guard = self.rag_locks.lock(name).await;
+                Rag::delete(name)?;
+                ret_json(&json!({ "name": name, "deleted": true }))
+            }
+            (Method::POST, Some("documents"), None) => {
+                let req_body: RagDocumentsReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                let _guard = self.rag_locks.lock(name).await;
+                let mut rag = Rag::load_file(name)?;
+                let documents =
+                    read_documents(req_body.documents, self.state().rag.clone()).await?;
+                if documents.is_empty() {
+                    bail!("No document to add");
+                }
+                let client = self.create_embedding_client(&rag.embedding_model)?;
+                let added = rag.add_documents(documents, client.as_ref()).await?;
+                let rag = rag.save()?;
+                let added: Vec<Value> = added
+                    .into_iter()
+                    .map(|(name, chunks)| json!({ "name": name, "chunks": chunks }))
+                    .collect();
+                let mut data = rag_summary(&rag, true);
+                data["added"] = added.into();
+                ret_json(&data)
+            }
+            (Method::DELETE, Some("documents"), Some(document)) => {
+                let _guard = self.rag_locks.lock(name).await;
+                let mut rag = Rag::load_file(name)?;
+                if !rag.documents.contains_key(document.as_ref()) {
+                    *status = StatusCode::NOT_FOUND;
+                }
+                rag.remove_document(&document)?;
+                ret_json(&rag_summary(&*rag.save()?, true))
+            }
+            (Method::POST, Some("search"), None) => {
+                let req_body: RagSearchReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                let rag = Rag::load(name)?;
+                let top_k = req_body.top_k.unwrap_or(rag.top_k);
+                let data = self
+                    .search_rag(
+                        &rag,
+                        &req_body.query,
+                        top_k,
+                        req_body.vector_weight,
+                        req_body.rerank_model,
+                    )
+                    .await?;
+                ret_json(&json!({ "data": data }))
+            }
+            (_, None | Some("documents" | "search"), _) => {
+                *status = StatusCode::METHOD_NOT_ALLOWED;
+                bail!("Method {method} is not allowed on {path}");
+            }
+            _ => {
+                *status = StatusCode::NOT_FOUND;
+                bail!("The requested endpoint was not found.");
+            }
+        }
     }
-}
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to install CTRL+C signal handler")
-}
+    async fn vector_stores(
+        &self,
+        method: &Method,
+        path: &str,
+        req: hyper::Request<Incoming>,
+        status: &mut StatusCode,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = if req_body.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
+        };
 
-fn generate_completion_id() -> String {
-    let random_id = chrono::Utc::now().nanosecond();
-    format!("chatcmpl-{}", random_id)
-}
+        if path == "/v1/vector_stores" {
+            return match *method {
+                Method::GET => {
+                    let data: Vec<Value> = VectorStore::list()?
+                        .iter()
+                        .filter_map(|name| {
+                            Some(vector_store_summary(&*VectorStore::load(name).ok()?))
+                        })
+                        .collect();
+                    ret_json(&json!({ "data": data }))
+                }
+                Method::POST => {
+                    let req_body: VectorStoreReqBody = serde_json::from_value(req_body)
+                        .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                    vector_store::validate_name(&req_body.name)?;
+                    let _guard = self.vector_store_locks.lock(&req_body.name).await;
+                    if VectorStore::exists(&req_body.name)? {
+                        *status = StatusCode::CONFLICT;
+                        bail!("Vector store '{}' already exists", req_body.name);
+                    }
+                    let client = self.create_embedding_client(&req_body.embedding_model)?;
+                    let store = VectorStore::new(&req_body.name, &client.model().id())?;
+                    ret_json(&vector_store_summary(&*store.save()?))
+                }
+                _ => {
+                    *status = StatusCode::METHOD_NOT_ALLOWED;
+                    bail!("Method {method} is not allowed on {path}");
+                }
+            };
+        }
 
-fn set_cors_header(res: &mut AppResponse) {
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        hyper::header::HeaderValue::from_static("*"),
-    );
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
-        hyper::header::HeaderValue::from_static("GET,POST,PUT,PATCH,DELETE"),
-    );
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
-        hyper::header::HeaderValue::from_static("Content-Type,Authorization"),
-    );
-}
+        let rest = path.strip_prefix("/v1/vector_stores/").unwrap_or_default();
+        let mut segments = rest.splitn(3, '/');
+        let name = segments.next().unwrap_or_default();
+        let action = segments.next();
+        let id = segments.next().map(urlencoding::decode).transpose()?;
+        vector_store::validate_name(name)?;
+        if !VectorStore::exists(name)? {
+            *status = StatusCode::NOT_FOUND;
+            bail!("Vector store '{name}' not found");
+        }
+        match (method.clone(), action, id.as_deref()) {
+            (Method::GET, None, None) => {
+                ret_json(&vector_store_summary(&*VectorStore::load(name)?))
+            }
+            (Method::DELETE, None, None) => {
+                let _guard = self.vector_store_locks.lock(name).await;
+                VectorStore::delete(name)?;
+                ret_json(&json!({ "name": name, "deleted": true }))
+            }
+            (Method::POST, Some("documents"), None) => {
+                let req_body: VectorDocumentsReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                if req_body.documents.is_empty() {
+                    bail!("No document to upsert");
+                }
+                let _guard = self.vector_store_locks.lock(name).await;
+                let mut store = VectorStore::load_file(name)?;
+                let client = self.create_embedding_client(&store.embedding_model)?;
+                let ids = store.upsert(req_body.documents, client.as_ref()).await?;
+                let mut data = vector_store_summary(&*store.save()?);
+                data["ids"] = ids.into();
+                ret_json(&data)
+            }
+            (Method::POST, Some("documents"), Some("delete")) => {
+                let req_body: VectorDeleteReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                if req_body.ids.is_empty() && req_body.filter.is_none() {
+                    bail!("Missing 'ids' or 'filter' of the documents to delete");
+                }
+                let _guard = self.vector_store_locks.lock(name).await;
+                let mut store = VectorStore::load_file(name)?;
+                let deleted = store.remove(&req_body.ids, req_body.filter.as_ref())?;
+                let mut data = vector_store_summary(&*store.save()?);
+                data["deleted"] = deleted.into();
+                ret_json(&data)
+            }
+            (Method::GET, Some("documents"), Some(id)) => {
+                let store = VectorStore::load(name)?;
+                let Some(document) = store.documents.get(id) else {
+                    *status = StatusCode::NOT_FOUND;
+                    bail!("Document '{id}' not found in vector store '{name}'");
+                };
+                ret_json(&json!({
+                    "id": id,
+                    "text": document.text,
+                    "metadata": document.metadata,
+                    "vector": document.vector,
+                    "updated_at": document.updated_at,
+                }))
+            }
+            (Method::DELETE, Some("documents"), Some(id)) => {
+                let _guard = self.vector_store_locks.lock(name).await;
+                let mut store = VectorStore::load_file(name)?;
+                if store.remove(&[id.to_string()], None)? == 0 {
+                    *status = StatusCode::NOT_FOUND;
+                    bail!("Document '{id}' not found in vector store '{name}'");
+                }
+                ret_json(&vector_store_summary(&*store.save()?))
+            }
+            (Method::POST, Some("query"), None) => {
+                let req_body: VectorQueryReqBody = serde_json::from_value(req_body)
+                    .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+                let store = VectorStore::load(name)?;
+                let vector = match (req_body.vector, req_body.text) {
+                    (Some(vector), None) => vector,
+                    (None, Some(text)) => {
+                        let client = self.create_embedding_client(&store.embedding_model)?;
+                        let data = EmbeddingsData::new(vec![text], true);
+                        let embeddings = client.embeddings(data).await?;
+                        embeddings
+                            .into_iter()
+                            .next()
+                            .ok_or_else(|| anyhow!("Invalid embeddings response"))?
+                    }
+                    _ => bail!("Expected either a 'text' or a 'vector' to query"),
+                };
+                let top_k = req_body.top_k.unwrap_or(DEFAULT_VECTOR_QUERY_TOP_K).max(1);
+                let data: Vec<Value> = store
+                    .query(&vector, top_k, req_body.filter.as_ref())?
+                    .into_iter()
+                    .filter_map(|(id, score)| {
+                        let document = store.documents.get(&id)?;
+                        let mut data = json!({
+                            "id": id,
+                            "score": score,
+                            "text": document.text,
+                            "metadata": document.metadata,
+                        });
+                        if req_body.include_vectors {
+                            data["vector"] = document.vector.clone().into();
+                        }
+                        Some(data)
+                    })
+                    .collect();
+                ret_json(&json!({ "data": data }))
+            }
+            (_, None | Some("documents" | "query"), _) => {
+                *status = StatusCode::METHOD_NOT_ALLOWED;
+                bail!("Method {method} is not allowed on {path}");
+            }
+            _ => {
+                *status = StatusCode::NOT_FOUND;
+                bail!("The requested endpoint was not found.");
+            }
+        }
+    }
 
-fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool) -> Frame<Bytes> {
-    let (delta, finish_reason) = if done {
-        (json!({}), "stop".into())
-    } else {
-        let delta = if content.is_empty() {
-            json!({ "role": "assistant", "content": content })
+    /// The chunks of the knowledge base best matching the query, by hybrid search and then
+    /// by the rerank model if any, the weight and model defaulting to the `rag` config.
+    async fn search_rag(
+        &self,
+        rag: &Rag,
+        query: &str,
+        top_k: usize,
+        vector_weight: Option<f32>,
+        rerank_model: Option<String>,
+    ) -> Result<Vec<RagResult>> {
+        let config = self.state().rag.clone();
+        let vector_weight = config.vector_weight(vector_weight)?;
+        let embedding = if vector_weight > 0.0 {
+            let client = self.create_embedding_client(&rag.embedding_model)?;
+            let data = EmbeddingsData::new(vec![query.to_string()], true);
+            let embeddings = client.embeddings(data).await?;
+            let embedding = embeddings
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("Invalid embeddings response"))?;
+            Some(embedding)
         } else {
-            json!({ "content": content })
+            None
         };
-        (delta, Value::Null)
-    };
-    let value = json!({
-        "id": id,
-        "object": "chat.completion.chunk",
+        let Some(rerank_model) = rerank_model.or(config.rerank_model) else {
+            return Ok(rag.search(query, embedding.as_deref(), top_k, vector_weight));
+        };
+        let candidates = rag.search(
+            query,
+            embedding.as_deref(),
+            top_k * RERANK_CANDIDATES_FACTOR,
+            vector_weight,
+        );
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+        let documents = candidates.iter().map(|v| v.text.clone()).collect();
+        let data = RerankData::new(query.to_string(), documents, Some(top_k));
+        let results = self.rerank(&rerank_model, data).await?;
+        Ok(results
+            .into_iter()
+            .filter_map(|v| {
+                let mut result = candidates.get(v.index)?.clone();
+                result.score = v.relevance_score as f32;
+                Some(result)
+            })
+            .collect())
+    }
+
+    /// Answer the last user message from the knowledge base, injecting its most relevant
+    /// chunks through the RAG template.
+    async fn augment(&self, name: &str, messages: &mut [Message]) -> Result<()> {
+        let rag = Rag::load(name)?;
+        let message = messages
+            .iter_mut()
+            .rev()
+            .find(|v| v.role.is_user() && !matches!(v.content, MessageContent::ToolResults(_)))
+            .ok_or_else(|| anyhow!("No user message to answer from knowledge base '{name}'"))?;
+        let results = self
+            .search_rag(&rag, &message.content.to_text(), rag.top_k, None, None)
+            .await?;
+        augment_message(message, &results);
+        Ok(())
+    }
+
+    fn session_file(&self, name: &str) -> PathBuf {
+        self.sessions_dir.join(format!("{name}.yaml"))
+    }
+
+    fn load_session(&self, name: &str) -> Result<Session> {
+        let mut session = Session::load(name, &self.session_file(name))?;
+        // Keep the session usable for reading even when its model is no longer served.
+        if let Ok(model) = self.resolve_model(Some(session.model_id())) {
+            session.set_model(&model);
+        }
+        Ok(session)
+    }
+
+    /// The served chat model with this id, the default model when none is given.
+    fn resolve_model(&self, model: Option<&str>) -> Result<Model> {
+        let state = self.state();
+        let mut config = Config {
+            clients: state.clients.to_vec(),
+            model: state.model.clone(),
+            ..Default::default()
+        };
+        if let Some(model) = model.filter(|v| *v != DEFAULT_MODEL_NAME) {
+            config.set_model(model)?;
+        }
+        Ok(config.model)
+    }
+
+    /// Validate the edited config document and serve it, writing it back to the config
+    /// file when the admin API is set to persist its changes.
+    fn apply_document(&self, document: Value) -> Result<()> {
+        let config = Config::from_document(&document)?;
+        if config.admin.persist {
+            Config::save_document(&document)?;
+        }
+        self.swap(config, document);
+        Ok(())
+    }
+
+    async fn replay(&self, span_id: &str, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let started = Instant::now();
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: ReplayReqBody = if req_body.is_empty() {
+            ReplayReqBody::default()
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
+        };
+        let original =
+            telemetry::find_span(span_id).ok_or_else(|| anyhow!("Span '{span_id}' not found"))?;
+        let recorded = RecordedCall::from_span(&original)?;
+
+        let model = req_body.model.unwrap_or_else(|| recorded.model.clone());
+        let max_tokens = req_body.max_tokens.or(recorded.max_tokens);
+        let (mut client, model_name) =
+            self.create_call_client(model, max_tokens, &recorded.agent)?;
+        client
+            .model_mut()
+            .set_tools(&tool_declarations(recorded.functions.as_deref()));
+        let http_client = client.build_client()?;
+        let data = ChatCompletionsData {
+            messages: recorded.messages.clone(),
+            temperature: req_body.temperature.or(recorded.temperature),
+            top_p: req_body.top_p.or(recorded.top_p),
+            functions: recorded.functions.clone(),
+            stream: false,
+        };
+
+        let mut observer =
+            CallObserver::new(client.as_ref(), &recorded.agent, None, started, &data);
+        observer.span.add_link(SpanContext {
+            trace_id: original.trace_id.clone(),
+            span_id: original.span_id.clone(),
+        });
+        observer
+            .span
+            .set_attribute("agent_panel.replay_of", original.span_id.clone());
+        let trace_id = observer.span.trace_id.clone();
+        let replay_span_id = observer.span.span_id.clone();
+        let output = match client.chat_completions_inner(&http_client, data).await {
+            Ok(output) => output,
+            Err(err) => {
+                observer.failure(&err);
+                return Err(err);
+            }
+        };
+        let usage = observer.success(None, &output);
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let original_text = recorded.output["content"].as_str().unwrap_or_default();
+        let original_input_tokens = recorded.input_tokens.unwrap_or_default();
+        let original_output_tokens = recorded.output_tokens.unwrap_or_default();
+        let data = json!({
+            "original": {
+                "trace_id": original.trace_id,
+                "span_id": original.span_id,
+                "model": recorded.model,
+                "output": recorded.output,
+                "usage": {
+                    "prompt_tokens": recorded.input_tokens,
+                    "completion_tokens": recorded.output_tokens,
+                    "cost": recorded.cost,
+                },
+                "latency_ms": original.duration_ms(),
+            },
+            "replay": {
+                "trace_id": trace_id,
+                "span_id": replay_span_id,
+                "model": model_name,
+                "output": {
+                    "role": MessageRole::Assistant,
+                    "content": output.text,
+                    "tool_calls": output.tool_calls,
+                },
+                "usage": usage.to_json(),
+                "latency_ms": latency_ms,
+            },
+            "diff": {
+                "same_output": original_text == output.text
+                    && recorded.output["tool_calls"] == json!(output.tool_calls),
+                "content": diff_lines(original_text, &output.text),
+                "prompt_tokens": usage.input_tokens as i64 - original_input_tokens as i64,
+                "completion_tokens": usage.output_tokens as i64 - original_output_tokens as i64,
+                "cost": usage.cost.zip(recorded.cost).map(|(a, b)| a - b),
+                "latency_ms": latency_ms - original.duration_ms(),
+            },
+        });
+        ret_json(&data)
+    }
+
+    /// Create the client of an LLM call, a failure counting as an errored request.
+    fn create_call_client(
+        &self,
+        model: String,
+        max_tokens: Option<isize>,
+        agent: &str,
+    ) -> Result<(Box<dyn Client>, String)> {
+        self.create_client(model.clone(), max_tokens)
+            .inspect_err(|_| {
+                let labels = METRICS.call_labels(UNKNOWN_LABEL, UNKNOWN_LABEL, agent);
+                METRICS.inc_requests(&labels, "error");
+            })
+    }
+
+    fn create_client(
+        &self,
+        model: String,
+        max_tokens: Option<isize>,
+    ) -> Result<(Box<dyn Client>, String)> {
+        let state = self.state();
+        let config = Config {
+            clients: state.clients.to_vec(),
+            model: state.model.clone(),
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+
+        let (model_name, change) = if model == DEFAULT_MODEL_NAME {
+            (state.model.id(), true)
+        } else if state.model.id() == model {
+            (model, false)
+        } else {
+            (model, true)
+        };
+
+        log::debug!("Model name: {}", model_name);
+        if change {
+            config.write().set_model(&model_name)?;
+        }
+
+        let mut client = init_client(&config, None)?;
+        if max_tokens.is_some() {
+            client.model_mut().set_max_tokens(max_tokens, true);
+        }
+        Ok((client, model_name))
+    }
+
+    /// A client of the served embedding model with this id.
+    fn create_embedding_client(&self, model: &str) -> Result<Box<dyn Client>> {
+        let state = self.state();
+        let config = Config {
+            clients: state.clients.to_vec(),
+            model: state.model.clone(),
+            ..Default::default()
+        };
+        let model = Model::find(&list_embedding_models(&config), model)
+            .ok_or_else(|| anyhow!("No embedding model '{model}'"))?;
+        init_client(&Arc::new(RwLock::new(config)), Some(model))
+    }
+
+    /// Rank the documents by relevance to the query with a rerank model, or by the cosine
+    /// similarity of their embeddings with an embedding model.
+    async fn rerank(&self, model: &str, data: RerankData) -> Result<RerankOutput> {
+        let state = self.state();
+        let config = Config {
+            clients: state.clients.to_vec(),
+            model: state.model.clone(),
+            ..Default::default()
+        };
+        let rerank_models = list_rerank_models(&config);
+        let embedding_models = list_embedding_models(&config);
+        // An embedding model is only picked by its exact id, the lookup falling back to any
+        // model of the platform otherwise.
+        let is_embedding_model = !rerank_models.iter().any(|v| v.id() == model)
+            && embedding_models.iter().any(|v| v.id() == model);
+        if !is_embedding_model {
+            if let Some(model) = Model::find(&rerank_models, model) {
+                let client = init_client(&Arc::new(RwLock::new(config)), Some(model))?;
+                return client.rerank(data).await;
+            }
+        }
+        let model = Model::find(&embedding_models, model)
+            .ok_or_else(|| anyhow!("No rerank or embedding model '{model}'"))?;
+        let client = init_client(&Arc::new(RwLock::new(config)), Some(model))?;
+        let RerankData {
+            query,
+            documents,
+            top_n,
+        } = data;
+        let query = client
+            .embeddings(EmbeddingsData::new(vec![query], true))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Invalid embeddings response"))?;
+        let batch_size = client.model().max_concurrent_chunks().max(1);
+        let mut output = Vec::with_capacity(documents.len());
+        for batch in documents.chunks(batch_size) {
+            let embeddings = client
+                .embeddings(EmbeddingsData::new(batch.to_vec(), false))
+                .await?;
+            if embeddings.len() != batch.len() {
+                bail!("Invalid embeddings response");
+            }
+            let offset = output.len();
+            output.extend(
+                embeddings
+                    .iter()
+                    .enumerate()
+                    .map(|(i, embedding)| RerankResult {
+                        index: offset + i,
+                        relevance_score: cosine_similarity(&query, embedding),
+                    }),
+            );
+        }
+        output.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+        if let Some(top_n) = top_n {
+            output.truncate(top_n);
+        }
+        Ok(output)
+    }
+
+    async fn rerank_documents(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: RerankReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let RerankReqBody {
+            model,
+            query,
+            documents,
+            top_n,
+            return_documents,
+        } = req_body;
+        let data = RerankData::new(query, documents.clone(), top_n);
+        let results: Vec<Value> = self
+            .rerank(&model, data)
+            .await?
+            .into_iter()
+            .map(|v| {
+                let mut result = json!({
+                    "index": v.index,
+                    "relevance_score": v.relevance_score,
+                });
+                if return_documents {
+                    result["document"] = json!({ "text": documents.get(v.index) });
+                }
+                result
+            })
+            .collect();
+        ret_json(&json!({ "model": model, "results": results }))
+    }
+
+    /// Count the tokens of a chat completion request as its model would be charged, without
+    /// calling it.
+    async fn count_tokens(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: TokenizeReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let TokenizeReqBody {
+            model,
+            messages,
+            tools,
+        } = req_body;
+
+        let ladder = escalation::ladder(&self.state().escalations, &model);
+        let model = ladder.as_ref().map(|v| v[0].clone()).unwrap_or(model);
+        let (mut client, model_name) = self.create_client(model, None)?;
+        client.model_mut().set_tools(&tools);
+        let (client, model_name) = match &ladder {
+            Some(ladder) => self.escalate(client, model_name, ladder, &messages, &tools, None)?,
+            None => (client, model_name),
+        };
+        let model = client.model();
+        let breakdown: Vec<Value> = messages
+            .iter()
+            .zip(model.messages_overheads(&messages))
+            .map(|(message, overhead)| {
+                json!({
+                    "role": message.role,
+                    "tokens": model.message_tokens(message),
+                    "overhead": overhead,
+                })
+            })
+            .collect();
+        ret_json(&json!({
+            "model": model_name,
+            "tokenizer": tokenizer::tokenizer_name(model),
+            "input_tokens": model.input_tokens(&messages),
+            "max_input_tokens": model.max_input_tokens(),
+            "excess_tokens": model.excess_input_tokens(&messages),
+            "messages": breakdown,
+            "tools_tokens": model.tools_tokens(),
+            "per_message_tokens": PER_MESSAGES_TOKENS,
+            "basis_tokens": BASIS_TOKENS,
+        }))
+    }
+
+    async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let started = Instant::now();
+        let context = call_context(&req);
+        let summarize = req
+            .headers()
+            .get(SUMMARIZE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v == "true");
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: ChatCompletionReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+
+        let ChatCompletionReqBody {
+            model,
+            messages,
+            temperature,
+            top_p,
+            max_tokens,
+            stream,
+            function_matcher,
+            max_steps,
+            rag,
+            overflow_strategy,
+        } = req_body;
+
+        log::debug!(
+            "Chat completion request: model={model}, messages={messages:?}, temperature={temperature:?}, top_p={top_p:?}, max_tokens={max_tokens:?}, stream={stream}"
+        );
+        let ladder = escalation::ladder(&self.state().escalations, &model);
+        let model = ladder.as_ref().map(|v| v[0].clone()).unwrap_or(model);
+        let (mut client, model_name) =
+            self.create_call_client(model, max_tokens, &context.agent)?;
+        let functions = match &function_matcher {
+            Some(matcher) => Some(self.select_functions(matcher)?),
+            None => None,
+        };
+        let tools = tool_declarations(functions.as_deref());
+        client.model_mut().set_tools(&tools);
+        let mut messages = messages;
+        let mut summarized = 0;
+        if summarize {
+            let threshold = self.state().summarize.threshold(client.model());
+            if threshold >= MIN_COMPRESS_THRESHOLD
+                && client.model().total_tokens(&messages) > threshold
+            {
+                if let Some((range, summary)) =
+                    self.summarize(&messages, client.model(), &context).await
+                {
+                    summarized = range.len();
+                    messages.splice(range, [summary]);
+                }
+            }
+        }
+        if let Some(rag) = &rag {
+            self.augment(rag, &mut messages).await?;
+        }
+        let requested_model = model_name.clone();
+        let (client, model_name) = match &ladder {
+            Some(ladder) => {
+                self.escalate(client, model_name, ladder, &messages, &tools, max_tokens)?
+            }
+            None => (client, model_name),
+        };
+        if model_name != requested_model {
+            METRICS.inc_fallbacks(&requested_model, &model_name, &context.agent);
+        }
+        if functions.is_some() {
+            check_function_calling(client.model())?;
+        }
+        let final_model = model_name.clone();
+        let trimmed = self
+            .fit_context(&mut messages, client.model(), overflow_strategy, &context)
+            .await?;
+        let data: ChatCompletionsData = ChatCompletionsData {
+            messages,
+            temperature,
+            top_p,
+            functions,
+            stream,
+        };
+
+        let mut res = if data.functions.is_some() {
+            self.complete_with_tools(client, model_name, data, max_steps, &context, None)
+                .await?
+        } else {
+            let observer = CallObserver::new(
+                client.as_ref(),
+                &context.agent,
+                context.parent_span.as_ref(),
+                started,
+                &data,
+            );
+            complete(client, model_name, data, observer, None).await?
+        };
+        if summarized > 0 {
+            res.headers_mut()
+                .insert(SUMMARIZED_HEADER, summarized.into());
+        }
+        set_trimmed_header(&mut res, trimmed)?;
+        if ladder.is_some() {
+            res.headers_mut().insert(MODEL_HEADER, final_model.parse()?);
+        }
+        Ok(res)
+    }
+
+    /// Climb the escalation ladder from the current model to the first one whose context
+    /// window fits the messages, the last one being used when none does.
+    fn escalate(
+        &self,
+        client: Box<dyn Client>,
+        model_name: String,
+        ladder: &[String],
+        messages: &[Message],
+        tools: &[Value],
+        max_tokens: Option<isize>,
+    ) -> Result<(Box<dyn Client>, String)> {
+        if client.model().excess_input_tokens(messages) == 0 {
+            return Ok((client, model_name));
+        }
+        let start = ladder
+            .iter()
+            .position(|v| *v == model_name)
+            .map(|i| i + 1)
+            .unwrap_or_default();
+        let mut escalated = None;
+        for model in &ladder[start..] {
+            let (mut client, model_name) = self.create_client(model.clone(), max_tokens)?;
+            client.model_mut().set_tools(tools);
+            let fits = client.model().excess_input_tokens(messages) == 0;
+            escalated = Some((client, model_name));
+            if fits {
+                break;
+            }
+        }
+        Ok(match escalated {
+            Some((client, escalated_name)) => {
+                info!(
+                    "Escalated from '{model_name}' to '{escalated_name}' to fit the context window"
+                );
+                (client, escalated_name)
+            }
+            None => (client, model_name),
+        })
+    }
+
+    /// Fit the messages in the context window of the model with the overflow strategy of the
+    /// request, else of the model, else of the config, returning what was trimmed.
+    async fn fit_context(
+        &self,
+        messages: &mut Vec<Message>,
+        model: &Model,
+        strategy: Option<OverflowStrategy>,
+        context: &CallContext,
+    ) -> Result<Option<Trimmed>> {
+        if model.excess_input_tokens(messages) == 0 {
+            return Ok(None);
+        }
+        let strategy = strategy
+            .or(model.overflow_strategy())
+            .unwrap_or(self.state().overflow_strategy);
+        let tokens = model.input_tokens(messages);
+        let count = match strategy {
+            OverflowStrategy::Reject => None,
+            OverflowStrategy::DropOldest => drop_oldest(messages, model),
+            OverflowStrategy::MiddleOut => middle_out(messages, model),
+            OverflowStrategy::Summarize => match self.summarize(messages, model, context).await {
+                Some((range, summary)) => {
+                    let count = range.len();
+                    messages.splice(range, [summary]);
+                    (model.excess_input_tokens(messages) == 0).then_some(count)
+                }
+                None => None,
+            },
+        };
+        let Some(count) = count else {
+            let max_input_tokens = model.max_input_tokens().unwrap_or_default();
+            match strategy {
+                OverflowStrategy::Reject => bail!(
+                    "Exceed max_input_tokens limit, about {tokens} tokens for {max_input_tokens}"
+                ),
+                _ => bail!(
+                    "Exceed max_input_tokens limit, about {tokens} tokens for {max_input_tokens} that '{}' could not fit",
+                    strategy.as_str()
+                ),
+            }
+        };
+        let trimmed = Trimmed {
+            strategy,
+            messages: count,
+            tokens: tokens.saturating_sub(model.input_tokens(messages)),
+        };
+        info!(
+            "Trimmed the conversation to fit the context window, {}",
+            trimmed.header_value()
+        );
+        Ok(Some(trimmed))
+    }
+
+    /// The gateway-managed functions offered to the model.
+    fn select_functions(&self, matcher: &str) -> Result<Vec<FunctionDeclaration>> {
+        let state = self.state();
+        if !state.function_calling {
+            bail!("Function calling is disabled, set 'function_calling: true' to enable it");
+        }
+        state
+            .function
+            .select(matcher)
+            .ok_or_else(|| anyhow!("No function matches '{matcher}'"))
+    }
+
+    /// Let the model call the gateway-managed functions, feeding their results back until it
+    /// answers or `max_steps` model calls were made, and answer with the step trace.
+    async fn complete_with_tools(
+        &self,
+        client: Box<dyn Client>,
+        model_name: String,
+        mut data: ChatCompletionsData,
+        max_steps: Option<usize>,
+        context: &CallContext,
+        on_reply: Option<OnReply>,
+    ) -> Result<AppResponse> {
+        if data.stream {
+            bail!("Streaming is not supported when the gateway runs the functions");
+        }
+        let state = self.state();
+        let max_steps = max_steps
+            .unwrap_or(usize::MAX)
+            .min(state.tools.max_steps())
+            .max(1);
+        let http_client = client.build_client()?;
+        // The model may only call the functions it was offered.
+        let offered: IndexSet<String> = data
+            .functions
+            .iter()
+            .flatten()
+            .map(|v| v.name.clone())
+            .collect();
+        let mut steps = vec![];
+        let mut usage: Option<CallUsage> = None;
+        let mut parent_span = context.parent_span.clone();
+        let mut traceparent = None;
+        let mut output = ChatCompletionsOutput::default();
+        for step in 1..=max_steps {
+            let observer = CallObserver::new(
+                client.as_ref(),
+                &context.agent,
+                parent_span.as_ref(),
+                Instant::now(),
+                &data,
+            );
+            // The later steps and the function runs are children of the first model call.
+            if traceparent.is_none() {
+                traceparent = Some(observer.span.traceparent());
+                parent_span = Some(observer.span.context());
+            }
+            output = match client
+                .chat_completions_inner(&http_client, data.clone())
+                .await
+            {
+                Ok(output) => output,
+                Err(err) => {
+                    observer.failure(&err);
+                    return Err(err);
+                }
+            };
+            let step_usage = observer.success(None, &output);
+            usage = Some(match usage {
+                Some(usage) => usage.add(step_usage),
+                None => step_usage,
+            });
+            if output.tool_calls.is_empty() || step == max_steps {
+                steps.push(json!({
+                    "step": step,
+                    "text": output.text,
+                    "tool_calls": output.tool_calls,
+                    "usage": step_usage.to_json(),
+                }));
+                break;
+            }
+            let results = join_all(output.tool_calls.iter().map(|call| {
+                execute_tool(
+                    &state.function,
+                    call,
+                    &offered,
+                    &state.tools,
+                    parent_span.as_ref(),
+                )
+            }))
+            .await;
+            steps.push(json!({
+                "step": step,
+                "text": output.text,
+                "tool_calls": results.iter().map(|(result, duration_ms)| json!({
+                    "id": result.call.id,
+                    "name": result.call.name,
+                    "arguments": result.call.arguments,
+                    "output": result.output,
+                    "duration_ms": duration_ms,
+                })).collect::<Vec<_>>(),
+                "usage": step_usage.to_json(),
+            }));
+            let results = results.into_iter().map(|(result, _)| result).collect();
+            data.messages.push(Message::new(
+                MessageRole::Assistant,
+                MessageContent::ToolResults((results, output.text.clone())),
+            ));
+        }
+        let usage = usage.unwrap_or_default();
+        if let Some(on_reply) = on_reply {
+            on_reply(&output)?;
+        }
+        let mut body = ret_non_stream(
+            &generate_completion_id(),
+            &model_name,
+            Utc::now().timestamp(),
+            &output,
+            &usage,
+        );
+        if !output.tool_calls.is_empty() {
+            // The model still wants to call functions after the last step.
+            body["choices"][0]["finish_reason"] = "tool_calls".into();
+            body["choices"][0]["message"]["tool_calls"] = output
+                .tool_calls
+                .iter()
+                .map(|call| {
+                    json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": { "name": call.name, "arguments": call.arguments },
+                    })
+                })
+                .collect();
+        }
+        body["steps"] = steps.into();
+        let mut builder = Response::builder().header("Content-Type", "application/json");
+        if let Some(traceparent) = traceparent {
+            builder = builder.header(TRACEPARENT_HEADER, traceparent);
+        }
+        if let Some(cost) = usage.cost {
+            builder = builder.header(COST_HEADER, format_cost(cost));
+        }
+        Ok(builder.body(Full::new(Bytes::from(body.to_string())).boxed())?)
+    }
+
+    /// Summarize the older turns of the conversation with the summarizer model, a failure
+    /// leaving the conversation as is.
+    async fn summarize(
+        &self,
+        messages: &[Message],
+        model: &Model,
+        context: &CallContext,
+    ) -> Option<(Range<usize>, Message)> {
+        let config = self.state().summarize.clone();
+        let range = summary_range(messages, config.keep_turns())?;
+        let summarizer = config.model.unwrap_or_else(|| model.id());
+        let ret = async {
+            let (client, _) = self.create_client(summarizer, None)?;
+            let http_client = client.build_client()?;
+            let data = ChatCompletionsData {
+                messages: summary_request(&messages[range.clone()]),
+                temperature: None,
+                top_p: None,
+                functions: None,
+                stream: false,
+            };
+            let mut observer = CallObserver::new(
+                client.as_ref(),
+                &context.agent,
+                context.parent_span.as_ref(),
+                Instant::now(),
+                &data,
+            );
+            observer
+                .span
+                .set_attribute("agent_panel.operation", "summarize");
+            match client.chat_completions_inner(&http_client, data).await {
+                Ok(output) => {
+                    observer.success(None, &output);
+                    Ok(output.text)
+                }
+                Err(err) => {
+                    observer.failure(&err);
+                    Err(err)
+                }
+            }
+        }
+        .await;
+        match ret {
+            Ok(summary) => {
+                info!("Summarized {} message(s) of the conversation", range.len());
+                Some((range, summary_message(&summary)))
+            }
+            Err(err) => {
+                warn!("Failed to summarize the conversation, {err:#}");
+                None
+            }
+        }
+    }
+}
+
+/// Called with the reply of a successful chat completion, before the response ends.
+type OnReply = Box<dyn FnOnce(&ChatCompletionsOutput) -> Result<()> + Send>;
+
+/// Run the chat completion and answer it in the OpenAI format, streamed or not.
+async fn complete(
+    client: Box<dyn Client>,
+    model_name: String,
+    data: ChatCompletionsData,
+    observer: CallObserver,
+    on_reply: Option<OnReply>,
+) -> Result<AppResponse> {
+    let abort = create_abort_signal();
+    let http_client = client.build_client()?;
+
+    let completion_id = generate_completion_id();
+    let created = Utc::now().timestamp();
+    let traceparent = observer.span.traceparent();
+
+    if data.stream {
+        let (tx, mut rx) = unbounded_channel();
+        tokio::spawn(async move {
+            METRICS.stream_started(&observer.labels);
+            let mut is_first = true;
+            let mut first_token = None;
+            let (tx2, rx2) = unbounded_channel();
+            let mut handler = SseHandler::new(tx2, abort);
+            async fn map_event(
+                mut rx: UnboundedReceiver<SseEvent>,
+                tx: &UnboundedSender<ResEvent>,
+                is_first: &mut bool,
+                first_token: &mut Option<Instant>,
+            ) {
+                while let Some(reply_event) = rx.recv().await {
+                    if *is_first {
+                        let _ = tx.send(ResEvent::First(None));
+                        *is_first = false;
+                    }
+                    match reply_event {
+                        SseEvent::Text(text) => {
+                            first_token.get_or_insert_with(Instant::now);
+                            let _ = tx.send(ResEvent::Text(text));
+                        }
+                        SseEvent::Done => {
+                            let _ = tx.send(ResEvent::Done(None));
+                        }
+                    }
+                }
+            }
+            let call = async move {
+                let ret = client
+                    .chat_completions_streaming_inner(&http_client, &mut handler, data)
+                    .await;
+                // Dropping the handler closes the channel so that `map_event` can finish.
+                (ret, handler.take())
+            };
+            let (_, (ret, output)) =
+                tokio::join!(map_event(rx2, &tx, &mut is_first, &mut first_token), call);
+            let usage = match ret {
+                Ok(()) => {
+                    // A reply without any text, e.g. only tool calls, still opens the stream.
+                    send_first_event(&tx, None, &mut is_first);
+                    if let Some(on_reply) = on_reply {
+                        if let Err(err) = on_reply(&output) {
+                            warn!("Failed to handle the streamed reply, {err:#}");
+                        }
+                    }
+                    METRICS.stream_finished(&observer.labels);
+                    Some(observer.success(first_token, &output).to_json())
+                }
+                Err(err) => {
+                    METRICS.stream_finished(&observer.labels);
+                    observer.failure(&err);
+                    send_first_event(&tx, Some(format!("{err:?}")), &mut is_first);
+                    None
+                }
+            };
+            let _ = tx.send(ResEvent::Done(usage));
+        });
+
+        let first_event = rx.recv().await;
+
+        if let Some(ResEvent::First(Some(err))) = first_event {
+            bail!("{err}");
+        }
+
+        let shared: Arc<(String, String, i64)> = Arc::new((completion_id, model_name, created));
+        let stream = UnboundedReceiverStream::new(rx);
+        let stream = stream.filter_map(move |res_event| {
+            let shared = shared.clone();
+            async move {
+                let (completion_id, model, created) = shared.as_ref();
+                match res_event {
+                    ResEvent::Text(text) => Some(Ok(create_frame(
+                        completion_id,
+                        model,
+                        *created,
+                        &text,
+                        false,
+                        None,
+                    ))),
+                    ResEvent::Done(usage) => Some(Ok(create_frame(
+                        completion_id,
+                        model,
+                        *created,
+                        "",
+                        true,
+                        usage.as_ref(),
+                    ))),
+                    _ => None,
+                }
+            }
+        });
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .header("Connection", "keep-alive")
+            .header(TRACEPARENT_HEADER, traceparent)
+            .body(BodyExt::boxed(StreamBody::new(stream)))?;
+        Ok(res)
+    } else {
+        let output = match client.chat_completions_inner(&http_client, data).await {
+            Ok(output) => output,
+            Err(err) => {
+                observer.failure(&err);
+                return Err(err);
+            }
+        };
+        let usage = observer.success(None, &output);
+        if let Some(on_reply) = on_reply {
+            on_reply(&output)?;
+        }
+        let mut builder = Response::builder()
+            .header("Content-Type", "application/json")
+            .header(TRACEPARENT_HEADER, traceparent);
+        if let Some(cost) = usage.cost {
+            builder = builder.header(COST_HEADER, format_cost(cost));
+        }
+        let res = builder.body(
+            Full::new(Bytes::from(
+                ret_non_stream(&completion_id, &model_name, created, &output, &usage).to_string(),
+            ))
+            .boxed(),
+        )?;
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionReqBody {
+    model: String,
+    messages: Vec<Message>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<isize>,
+    #[serde(default)]
+    stream: bool,
+    /// Let the gateway run the functions matching this regex for the model
+    function_matcher: Option<String>,
+    max_steps: Option<usize>,
+    /// Answer from the chunks of this knowledge base
+    rag: Option<String>,
+    /// Overrides the overflow strategy of the model and of the config
+    overflow_strategy: Option<OverflowStrategy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionReqBody {
+    id: Option<String>,
+    model: Option<String>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    compress_threshold: Option<usize>,
+    function_matcher: Option<String>,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RagReqBody {
+    name: Option<String>,
+    embedding_model: Option<String>,
+    chunk_size: Option<usize>,
+    chunk_overlap: Option<usize>,
+    top_k: Option<usize>,
+    #[serde(default)]
+    documents: Vec<RagDocumentReqBody>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RagDocumentsReqBody {
+    documents: Vec<RagDocumentReqBody>,
+}
+
+/// A file on the gateway host, or an uploaded document with its text or base64 bytes
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RagDocumentReqBody {
+    path: Option<String>,
+    name: Option<String>,
+    content: Option<String>,
+    data: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RagSearchReqBody {
+    query: String,
+    top_k: Option<usize>,
+    vector_weight: Option<f32>,
+    rerank_model: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RerankReqBody {
+    model: String,
+    query: String,
+    documents: Vec<String>,
+    top_n: Option<usize>,
+    #[serde(default)]
+    return_documents: bool,
+}
+
+/// A chat completion request, its other fields being ignored
+#[derive(Debug, Deserialize)]
+struct TokenizeReqBody {
+    model: String,
+    messages: Vec<Message>,
+    #[serde(default)]
+    tools: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorStoreReqBody {
+    name: String,
+    embedding_model: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorDocumentsReqBody {
+    documents: Vec<VectorDocumentInput>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorDeleteReqBody {
+    #[serde(default)]
+    ids: Vec<String>,
+    filter: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorQueryReqBody {
+    text: Option<String>,
+    vector: Option<Vec<f32>>,
+    top_k: Option<usize>,
+    filter: Option<Value>,
+    #[serde(default)]
+    include_vectors: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionMessageReqBody {
+    content: MessageContent,
+    max_tokens: Option<isize>,
+    #[serde(default)]
+    stream: bool,
+    overflow_strategy: Option<OverflowStrategy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReplayReqBody {
+    model: Option<String>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<isize>,
+}
+
+/// A chat completion rebuilt from its stored span.
+#[derive(Debug)]
+struct RecordedCall {
+    model: String,
+    agent: String,
+    messages: Vec<Message>,
+    functions: Option<Vec<FunctionDeclaration>>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<isize>,
+    output: Value,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+    cost: Option<f64>,
+}
+
+impl RecordedCall {
+    fn from_span(span: &Span) -> Result<Self> {
+        let attrs = &span.attributes;
+        let get_str = |key: &str| attrs.get(key).and_then(|v| v.as_str());
+        let (Some(client), Some(model)) = (
+            get_str("agent_panel.client"),
+            get_str("gen_ai.request.model"),
+        ) else {
+            bail!("Span '{}' is not a chat completion", span.span_id);
+        };
+        let prompt = span
+            .events
+            .iter()
+            .find(|event| event.name == "gen_ai.content.prompt")
+            .ok_or_else(|| {
+                anyhow!(
+                    "Span '{}' has no recorded prompt, see 'telemetry.store_content'",
+                    span.span_id
+                )
+            })?;
+        let messages = prompt.attributes["gen_ai.prompt"]
+            .as_str()
+            .and_then(|v| serde_json::from_str(v).ok())
+            .ok_or_else(|| anyhow!("Span '{}' has an invalid recorded prompt", span.span_id))?;
+        let functions = prompt
+            .attributes
+            .get("gen_ai.request.functions")
+            .and_then(|v| v.as_str())
+            .and_then(|v| serde_json::from_str(v).ok());
+        let output = span
+            .events
+            .iter()
+            .find(|event| event.name == "gen_ai.content.completion")
+            .and_then(|event| event.attributes["gen_ai.completion"].as_str())
+            .and_then(|v| serde_json::from_str::<Value>(v).ok())
+            .map(|v| v[0].clone())
+            .unwrap_or_default();
+        Ok(Self {
+            model: format!("{client}:{model}"),
+            agent: get_str("agent_panel.agent")
+                .unwrap_or(UNKNOWN_AGENT)
+                .to_string(),
+            messages,
+            functions,
+            temperature: attrs
+                .get("gen_ai.request.temperature")
+                .and_then(|v| v.as_f64()),
+            top_p: attrs.get("gen_ai.request.top_p").and_then(|v| v.as_f64()),
+            max_tokens: attrs
+                .get("gen_ai.request.max_tokens")
+                .and_then(|v| v.as_i64())
+                .map(|v| v as isize),
+            output,
+            input_tokens: attrs
+                .get("gen_ai.usage.input_tokens")
+                .and_then(|v| v.as_u64()),
+            output_tokens: attrs
+                .get("gen_ai.usage.output_tokens")
+                .and_then(|v| v.as_u64()),
+            cost: attrs.get("agent_panel.usage.cost").and_then(|v| v.as_f64()),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum ResEvent {
+    First(Option<String>),
+    Text(String),
+    Done(Option<Value>),
+}
+
+/// Run a function called by the model, traced as a child span of the model call.
+async fn execute_tool(
+    function: &Function,
+    call: &ToolCall,
+    offered: &IndexSet<String>,
+    config: &ToolsConfig,
+    parent: Option<&SpanContext>,
+) -> (ToolCallResult, f64) {
+    let started = Instant::now();
+    let mut span = Span::new(&format!("execute_tool {}", call.name), parent);
+    span.set_attribute("gen_ai.operation.name", "execute_tool");
+    span.set_attribute("gen_ai.tool.name", call.name.clone());
+    span.set_attribute("gen_ai.tool.call.id", call.id.clone());
+    let output = if offered.contains(&call.name) {
+        function.execute(call, config).await
+    } else {
+        json!({ "error": format!("Function '{}' is not available", call.name) })
+    };
+    let error = output["error"].as_str().map(|v| v.to_string());
+    if error.is_some() {
+        span.set_attribute("error.type", "tool_error");
+    }
+    span.end(error);
+    telemetry::export(span);
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    (ToolCallResult::new(call.clone(), output), duration_ms)
+}
+
+/// Who is calling and where the call belongs in the caller's trace.
+struct CallContext {
+    agent: String,
+    parent_span: Option<SpanContext>,
+}
+
+/// The calling agent and the parent span of a request, read from its headers.
+fn call_context(req: &hyper::Request<Incoming>) -> CallContext {
+    let agent = req
+        .headers()
+        .get(AGENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(UNKNOWN_AGENT)
+        .to_string();
+    let parent_span = req
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_traceparent);
+    CallContext { agent, parent_span }
+}
+
+/// The metric labels of a call, a model name not in the config of its client, e.g. one
+/// passed through to the API, counting as unknown.
+fn call_labels(client: &dyn Client, agent: &str) -> CallLabels {
+    let model = client.model();
+    let configured = list_models(&client.global_config().read())
+        .iter()
+        .any(|v| v.id() == model.id());
+    let name = if configured {
+        model.name()
+    } else {
+        UNKNOWN_LABEL
+    };
+    METRICS.call_labels(model.client_name(), name, agent)
+}
+
+/// The functions offered to the model as the tool declarations sent along the messages.
+fn tool_declarations(functions: Option<&[FunctionDeclaration]>) -> Vec<Value> {
+    functions
+        .unwrap_or_default()
+        .iter()
+        .map(|v| json!(v))
+        .collect()
+}
+
+fn check_function_calling(model: &Model) -> Result<()> {
+    if !model.supports_function_calling() {
+        bail!("Model '{}' does not support function calling", model.id());
+    }
+    Ok(())
+}
+
+fn set_trimmed_header(res: &mut AppResponse, trimmed: Option<Trimmed>) -> Result<()> {
+    if let Some(trimmed) = trimmed {
+        res.headers_mut()
+            .insert(TRIMMED_HEADER, trimmed.header_value().parse()?);
+    }
+    Ok(())
+}
+
+fn vector_store_summary(store: &VectorStore) -> Value {
+    json!({
+        "name": store.name,
+        "embedding_model": store.embedding_model,
+        "dimensions": store.dimensions,
+        "created_at": store.created_at,
+        "documents_count": store.documents.len(),
+    })
+}
+
+fn rag_summary(rag: &Rag, with_documents: bool) -> Value {
+    let mut data = json!({
+        "name": rag.name,
+        "embedding_model": rag.embedding_model,
+        "chunk_size": rag.chunk_size,
+        "chunk_overlap": rag.chunk_overlap,
+        "top_k": rag.top_k,
+        "created_at": rag.created_at,
+        "documents_count": rag.documents.len(),
+        "chunks_count": rag.chunks_count(),
+    });
+    if with_documents {
+        data["documents"] = rag
+            .documents
+            .iter()
+            .map(|(name, document)| {
+                json!({
+                    "name": name,
+                    "chunks": document.chunks.len(),
+                    "added_at": document.added_at,
+                })
+            })
+            .collect::<Vec<_>>()
+            .into();
+    }
+    data
+}
+
+/// Read the documents sent to the knowledge base API as (name, text) pairs, off the async
+/// runtime since extracting the text of PDFs is slow.
+async fn read_documents(
+    documents: Vec<RagDocumentReqBody>,
+    config: RagConfig,
+) -> Result<Vec<(String, String)>> {
+    tokio::task::spawn_blocking(move || {
+        let mut output = vec![];
+        for document in documents {
+            match document {
+                RagDocumentReqBody {
+                    path: Some(path),
+                    name: None,
+                    content: None,
+                    data: None,
+                } => {
+                    let path = config.check_path(&path)?;
+                    output.extend(rag::read_documents(&[path])?);
+                }
+                RagDocumentReqBody {
+                    path: None,
+                    name: Some(name),
+                    content: Some(content),
+                    data: None,
+                } => output.push((name, content)),
+                RagDocumentReqBody {
+                    path: None,
+                    name: Some(name),
+                    content: None,
+                    data: Some(data),
+                } => {
+                    let bytes = base64_decode(&data)
+                        .map_err(|err| anyhow!("Invalid base64 data of '{name}', {err}"))?;
+                    let text = rag::load_bytes(&name, &bytes)?;
+                    output.push((name, text));
+                }
+                _ => bail!("A document needs either a 'path', or a 'name' with its 'content' or base64 'data'"),
+            }
+        }
+        Ok(output)
+    })
+    .await?
+}
+
+fn session_summary(session: &Session, with_messages: bool) -> Value {
+    let mut data = json!(session);
+    data["id"] = session.name().into();
+    data["tokens"] = session.tokens().into();
+    if let Some(map) = data.as_object_mut() {
+        map.remove("data_urls");
+        map.remove("compressed_messages");
+        if !with_messages {
+            map.remove("messages");
+            map.insert("messages_count".into(), session.messages().len().into());
+        }
+    }
+    data
+}
+
+fn send_first_event(tx: &UnboundedSender<ResEvent>, data: Option<String>, is_first: &mut bool) {
+    if *is_first {
+        let _ = tx.send(ResEvent::First(data));
+        *is_first = false;
+    }
+}
+
+/// Records the metrics and the span of a single LLM call.
+struct CallObserver {
+    labels: CallLabels,
+    model: Model,
+    started: Instant,
+    estimated_input_tokens: u64,
+    span: Span,
+}
+
+impl CallObserver {
+    fn new(
+        client: &dyn Client,
+        agent: &str,
+        parent: Option<&SpanContext>,
+        started: Instant,
+        data: &ChatCompletionsData,
+    ) -> Self {
+        let model = client.model().clone();
+        let labels = call_labels(client, agent);
+        let mut span = Span::new(&format!("chat {}", model.name()), parent);
+        span.set_attribute(
+            "gen_ai.system",
+            gen_ai_system(client.kind(), model.client_name()),
+        );
+        span.set_attribute("gen_ai.operation.name", "chat");
+        span.set_attribute("gen_ai.request.model", model.name());
+        span.set_attribute("gen_ai.request.temperature", data.temperature);
+        span.set_attribute("gen_ai.request.top_p", data.top_p);
+        span.set_attribute("gen_ai.request.max_tokens", model.max_tokens_param());
+        span.set_attribute("agent_panel.client", model.client_name());
+        span.set_attribute("agent_panel.agent", agent);
+        span.set_attribute("agent_panel.stream", data.stream);
+        let mut prompt: IndexMap<String, Value> = [(
+            "gen_ai.prompt".to_string(),
+            json!(data.messages).to_string().into(),
+        )]
+        .into();
+        if let Some(functions) = &data.functions {
+            prompt.insert(
+                "gen_ai.request.functions".into(),
+                json!(functions).to_string().into(),
+            );
+        }
+        span.add_event("gen_ai.content.prompt", prompt);
+        let estimated_input_tokens = model.input_tokens(&data.messages) as u64;
+        Self {
+            labels,
+            model,
+            started,
+            estimated_input_tokens,
+            span,
+        }
+    }
+
+    fn success(
+        mut self,
+        first_token: Option<Instant>,
+        output: &ChatCompletionsOutput,
+    ) -> CallUsage {
+        let Self {
+            labels,
+            model,
+            started,
+            ..
+        } = &self;
+        let now = Instant::now();
+        let usage = CallUsage::new(model, self.estimated_input_tokens, output);
+        METRICS.inc_requests(labels, "success");
+        METRICS.observe_latency(labels, (now - *started).as_secs_f64());
+        if let Some(first_token) = first_token {
+            METRICS.observe_time_to_first_token(labels, (first_token - *started).as_secs_f64());
+        }
+        METRICS.add_tokens(labels, usage.input_tokens, usage.output_tokens);
+        if let Some(cost) = usage.cost {
+            METRICS.add_cost(labels, cost);
+        }
+        let generation_secs = (now - first_token.unwrap_or(*started)).as_secs_f64();
+        if usage.output_tokens > 0 && generation_secs > 0.0 {
+            METRICS.observe_tokens_per_second(labels, usage.output_tokens as f64 / generation_secs);
+        }
+
+        let span = &mut self.span;
+        span.set_attribute("gen_ai.response.model", self.model.name());
+        span.set_attribute("gen_ai.response.id", output.id.clone());
+        span.set_attribute("gen_ai.usage.input_tokens", usage.input_tokens);
+        span.set_attribute("gen_ai.usage.output_tokens", usage.output_tokens);
+        span.set_attribute("agent_panel.usage.cost", usage.cost);
+        span.set_attribute("agent_panel.usage.estimated", usage.estimated);
+        let completion = json!([{
+            "role": MessageRole::Assistant,
+            "content": output.text,
+            "tool_calls": output.tool_calls,
+        }])
+        .to_string();
+        span.add_event(
+            "gen_ai.content.completion",
+            [("gen_ai.completion".to_string(), completion.into())].into(),
+        );
+        span.end(None);
+        telemetry::export(self.span);
+        usage
+    }
+
+    fn failure(mut self, err: &anyhow::Error) {
+        METRICS.inc_requests(&self.labels, "error");
+        METRICS.observe_latency(&self.labels, self.started.elapsed().as_secs_f64());
+        self.span.set_attribute("error.type", "gateway_error");
+        self.span.end(Some(err.to_string()));
+        telemetry::export(self.span);
+    }
+}
+
+/// Reload the config when the config file changes or on SIGHUP. An invalid config is
+/// reported and the current one is kept.
+fn watch_config(server: Arc<Server>) {
+    let (tx, mut rx) = unbounded_channel();
+
+    #[cfg(unix)]
+    {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                warn!("Failed to install SIGHUP handler");
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                let _ = tx.send(());
+            }
+        });
+    }
+
+    if let Ok(path) = Config::config_file() {
+        tokio::spawn(async move {
+            let modified = || path.metadata().and_then(|v| v.modified()).ok();
+            let mut last_modified = modified();
+            loop {
+                tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+                let current = modified();
+                if current != last_modified {
+                    last_modified = current;
+                    info!("Config file {} changed, reloading", path.display());
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            if let Err(err) = server.reload() {
+                error!("Failed to reload config, keeping the current one: {err:#}");
+            }
+        }
+    });
+}
+
+/// Probe the clients periodically, and again right after every config reload.
+fn watch_health(server: Arc<Server>) {
+    tokio::spawn(async move {
+        loop {
+            let state = server.state();
+            let interval = state.health.probe_interval();
+            if interval.is_some() {
+                health::probe_clients(&state.clients, &state.health).await;
+            }
+            drop(state);
+            match interval {
+                Some(interval) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(interval) => {}
+                        _ = server.probe_notify.notified() => {}
+                    }
+                }
+                None => server.probe_notify.notified().await,
+            }
+        }
+    });
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to install CTRL+C signal handler")
+}
+
+fn generate_completion_id() -> String {
+    let random_id = chrono::Utc::now().nanosecond();
+    format!("chatcmpl-{}", random_id)
+}
+
+fn set_cors_header(res: &mut AppResponse) {
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        hyper::header::HeaderValue::from_static("*"),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
+        hyper::header::HeaderValue::from_static("GET,POST,PUT,PATCH,DELETE"),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
+        hyper::header::HeaderValue::from_static("Content-Type,Authorization"),
+    );
+}
+
+fn create_frame(
+    id: &str,
+    model: &str,
+    created: i64,
+    content: &str,
+    done: bool,
+    usage: Option<&Value>,
+) -> Frame<Bytes> {
+    let (delta, finish_reason) = if done {
+        (json!({}), "stop".into())
+    } else {
+        let delta = if content.is_empty() {
+            json!({ "role": "assistant", "content": content })
+        } else {
+            json!({ "content": content })
+        };
+        (delta, Value::Null)
+    };
+    let mut value = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
         "created": created,
         "model": model,
         "choices": [
@@ -397,6 +2734,9 @@ fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool)
             },
         ],
     });
+    if let Some(usage) = usage {
+        value["usage"] = usage.clone();
+    }
     let output = if done {
         format!("data: {value}\n\ndata: [DONE]\n\n")
     } else {
@@ -405,12 +2745,15 @@ fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool)
     Frame::data(Bytes::from(output))
 }
 
-fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsOutput) -> Bytes {
+fn ret_non_stream(
+    id: &str,
+    model: &str,
+    created: i64,
+    output: &ChatCompletionsOutput,
+    usage: &CallUsage,
+) -> Value {
     let id = output.id.as_deref().unwrap_or(id);
-    let input_tokens = output.input_tokens.unwrap_or_default();
-    let output_tokens = output.output_tokens.unwrap_or_default();
-    let total_tokens = input_tokens + output_tokens;
-    let res_body = json!({
+    json!({
         "id": id,
         "object": "chat.completion",
         "created": created,
@@ -426,13 +2769,162 @@ fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsO
                 "finish_reason": "stop",
             },
         ],
-        "usage": {
-            "prompt_tokens": input_tokens,
-            "completion_tokens": output_tokens,
-            "total_tokens": total_tokens,
-        },
-    });
-    Bytes::from(res_body.to_string())
+        "usage": usage.to_json(),
+    })
+}
+
+fn format_cost(cost: f64) -> String {
+    format!("{cost:.8}")
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Aggregate the tokens, cost and timing of the spans of a trace.
+fn summarize_trace(spans: &[Span]) -> Value {
+    let root = spans
+        .iter()
+        .find(|span| {
+            span.parent_span_id
+                .as_ref()
+                .map(|id| spans.iter().all(|v| &v.span_id != id))
+                .unwrap_or(true)
+        })
+        .or(spans.first());
+    let start_time = spans.iter().map(|v| v.start_time).min().unwrap_or_default();
+    let end_time = spans
+        .iter()
+        .map(|v| v.end_time.unwrap_or(v.start_time))
+        .max()
+        .unwrap_or_default();
+    let mut agents = IndexSet::new();
+    let mut models = IndexSet::new();
+    let (mut input_tokens, mut output_tokens, mut cost) = (0, 0, 0.0);
+    for span in spans {
+        let attrs = &span.attributes;
+        if let Some(agent) = attrs.get("agent_panel.agent").and_then(|v| v.as_str()) {
+            agents.insert(agent.to_string());
+        }
+        if let (Some(client), Some(model)) = (
+            attrs.get("agent_panel.client").and_then(|v| v.as_str()),
+            attrs.get("gen_ai.request.model").and_then(|v| v.as_str()),
+        ) {
+            models.insert(format!("{client}:{model}"));
+        }
+        let get_u64 = |key: &str| attrs.get(key).and_then(|v| v.as_u64()).unwrap_or_default();
+        input_tokens += get_u64("gen_ai.usage.input_tokens");
+        output_tokens += get_u64("gen_ai.usage.output_tokens");
+        cost += attrs
+            .get("agent_panel.usage.cost")
+            .and_then(|v| v.as_f64())
+            .unwrap_or_default();
+    }
+    json!({
+        "trace_id": root.map(|v| v.trace_id.clone()),
+        "name": root.map(|v| v.name.clone()),
+        "start_time": (start_time / 1_000_000) as u64,
+        "duration_ms": end_time.saturating_sub(start_time) as f64 / 1_000_000.0,
+        "span_count": spans.len(),
+        "agents": agents,
+        "models": models,
+        "input_tokens": input_tokens,
+        "output_tokens": output_tokens,
+        "cost": cost,
+        "error": spans.iter().any(|v| v.error.is_some()),
+    })
+}
+
+/// A line-based diff of two texts, computed from their longest common subsequence.
+///
+/// The lines differing between the common prefix and suffix are reported as deleted then
+/// inserted when their table would exceed `MAX_DIFF_CELLS`, its size being quadratic.
+fn diff_lines(old: &str, new: &str) -> Vec<Value> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let equal = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|v| json!({ "op": "equal", "text": v }))
+            .collect::<Vec<_>>()
+    };
+    let mut output = equal(&old[..prefix]);
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let (n, m) = (old_middle.len(), new_middle.len());
+    if n.saturating_mul(m) > MAX_DIFF_CELLS {
+        output.extend(
+            old_middle
+                .iter()
+                .map(|v| json!({ "op": "delete", "text": v })),
+        );
+        output.extend(
+            new_middle
+                .iter()
+                .map(|v| json!({ "op": "insert", "text": v })),
+        );
+    } else {
+        let (old, new) = (old_middle, new_middle);
+        let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if old[i] == new[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old[i] == new[j] {
+                output.push(json!({ "op": "equal", "text": old[i] }));
+                i += 1;
+                j += 1;
+            } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+                output.push(json!({ "op": "insert", "text": new[j] }));
+                j += 1;
+            } else {
+                output.push(json!({ "op": "delete", "text": old[i] }));
+                i += 1;
+            }
+        }
+    }
+    output.extend(equal(&old[old.len() - suffix..]));
+    output
+}
+
+fn find_model_summary(document: &Value, id: &str) -> Option<Value> {
+    admin::list_models(document)
+        .into_iter()
+        .find(|v| v["id"].as_str() == Some(id))
+}
+
+fn parse_limit(uri: &http::Uri, default: usize) -> Result<usize> {
+    let query = uri.query().unwrap_or_default();
+    for (key, value) in query.split('&').filter_map(|v| v.split_once('=')) {
+        if key == "limit" {
+            return value
+                .parse()
+                .map_err(|_| anyhow!("Invalid limit '{value}'"));
+        }
+    }
+    Ok(default)
+}
+
+fn ret_json(data: &Value) -> Result<AppResponse> {
+    let res = Response::builder()
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+    Ok(res)
 }
 
 fn ret_err<T: std::fmt::Display>(err: T) -> AppResponse {
@@ -447,3 +2939,493 @@ fn ret_err<T: std::fmt::Display>(err: T) -> AppResponse {
         .body(Full::new(Bytes::from(data.to_string())).boxed())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serve the config document on a random port, returning the server and its base URL.
+    async fn start_server(
+        document: Value,
+        function: Function,
+    ) -> (Arc<Server>, String, oneshot::Sender<()>) {
+        let sessions_dir = std::env::temp_dir().join("agent-panel-sessions-unused");
+        start_server_with_sessions(document, function, sessions_dir).await
+    }
+
+    /// Like `start_server`, saving the sessions in the given directory.
+    async fn start_server_with_sessions(
+        document: Value,
+        function: Function,
+        sessions_dir: PathBuf,
+    ) -> (Arc<Server>, String, oneshot::Sender<()>) {
+        let mut config: Config = serde_json::from_value(document.clone()).unwrap();
+        config.set_model(&config.model_id.clone()).unwrap();
+        config.function = function;
+        let state = ServerState::new(&config, document);
+        let server = Arc::new(Server {
+            config: Arc::new(RwLock::new(config)),
+            state: RwLock::new(Arc::new(state)),
+            admin_lock: Mutex::new(()),
+            probe_notify: Notify::new(),
+            session_locks: Default::default(),
+            rag_locks: Default::default(),
+            vector_store_locks: Default::default(),
+            sessions_dir,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let shutdown = server.clone().run(listener).await.unwrap();
+        (server, url, shutdown)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_complete_with_tools() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let functions_dir =
+            std::env::temp_dir().join(format!("agent-panel-tools-{}", std::process::id()));
+        std::fs::create_dir_all(functions_dir.join("bin")).unwrap();
+        let script = functions_dir.join("bin").join("get_weather");
+        std::fs::write(&script, "#!/bin/sh\nprintf '{\"args\": %s}' \"$1\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(
+            functions_dir.join("functions.json"),
+            json!([{
+                "name": "get_weather",
+                "description": "Get the weather",
+                "parameters": { "type": "object", "properties": {} },
+            }])
+            .to_string(),
+        )
+        .unwrap();
+        let document = json!({
+            "model": "mock:weather",
+            "function_calling": true,
+            "clients": [{
+                "type": "mock",
+                "models": [{ "name": "weather", "supports_function_calling": true }],
+                "responses": {
+                    "weather": {
+                        "type": "tool_calls",
+                        "tool_calls": [{ "name": "get_weather", "arguments": { "city": "Paris" } }],
+                    },
+                },
+            }],
+        });
+        let function = Function::init(&functions_dir).unwrap();
+        let (_, url, shutdown) = start_server(document, function).await;
+        let client = reqwest::Client::new();
+        let chat = |max_steps: Option<usize>| {
+            let builder = client
+                .post(format!("{url}/v1/chat/completions"))
+                .json(&json!({
+                    "model": "mock:weather",
+                    "messages": [{ "role": "user", "content": "Weather in Paris?" }],
+                    "function_matcher": "get_weather",
+                    "max_steps": max_steps,
+                }));
+            async move { builder.send().await.unwrap().json::<Value>().await.unwrap() }
+        };
+
+        // The function runs with the model's arguments, then the model answers with its output
+        let data = chat(None).await;
+        let steps = data["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0]["tool_calls"][0]["name"], "get_weather");
+        assert_eq!(
+            steps[0]["tool_calls"][0]["output"],
+            json!({ "args": { "city": "Paris" } })
+        );
+        assert_eq!(
+            data["choices"][0]["message"]["content"],
+            r#"{"args":{"city":"Paris"}}"#
+        );
+        assert_eq!(data["choices"][0]["finish_reason"], "stop");
+
+        // The loop stops after max_steps model calls, returning the pending calls
+        let data = chat(Some(1)).await;
+        assert_eq!(data["steps"].as_array().unwrap().len(), 1);
+        assert_eq!(data["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(
+            data["choices"][0]["message"]["tool_calls"][0]["function"]["name"],
+            "get_weather"
+        );
+
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&functions_dir);
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens() {
+        let document = json!({
+            "model": "openai:gpt-4o",
+            "clients": [{ "type": "openai", "api_key": "sk-test" }],
+        });
+        let (_, url, shutdown) = start_server(document, Function::default()).await;
+        let client = reqwest::Client::new();
+        let count = |messages: Value| {
+            let builder = client.post(format!("{url}/v1/tokenize")).json(&json!({
+                "model": "openai:gpt-4o",
+                "messages": messages,
+                "tools": [{
+                    "type": "function",
+                    "function": { "name": "get_weather", "parameters": { "type": "object" } },
+                }],
+            }));
+            async move { builder.send().await.unwrap().json::<Value>().await.unwrap() }
+        };
+
+        let data = count(json!([
+            { "role": "system", "content": "You are a helpful assistant." },
+            { "role": "user", "content": "Hello world" },
+            { "role": "assistant", "content": "Hi there" },
+        ]))
+        .await;
+        assert_eq!(data["tokenizer"], "o200k_base");
+        let messages = data["messages"].as_array().unwrap();
+        let overheads: Vec<u64> = messages
+            .iter()
+            .map(|v| v["overhead"].as_u64().unwrap())
+            .collect();
+        // A last assistant message is continued by the model, without a message overhead
+        let per_message = data["per_message_tokens"].as_u64().unwrap();
+        assert_eq!(overheads, [per_message, per_message, 0]);
+        assert_eq!(messages[1]["tokens"], 2);
+        let breakdown: u64 = messages
+            .iter()
+            .map(|v| v["tokens"].as_u64().unwrap() + v["overhead"].as_u64().unwrap())
+            .sum();
+        let tools_tokens = data["tools_tokens"].as_u64().unwrap();
+        assert!(tools_tokens > 0);
+        assert_eq!(
+            data["input_tokens"].as_u64().unwrap(),
+            breakdown + tools_tokens + data["basis_tokens"].as_u64().unwrap()
+        );
+
+        let data = count(json!([{ "role": "user", "content": "Hello world" }])).await;
+        assert_eq!(data["messages"][0]["overhead"].as_u64(), Some(per_message));
+
+        let _ = shutdown.send(());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_usage() {
+        let document = json!({
+            "model": "mock:echo",
+            "clients": [{
+                "type": "mock",
+                "models": [{ "name": "echo" }, { "name": "greeter" }],
+                "responses": {
+                    "greeter": {
+                        "type": "text",
+                        "text": "Hello there",
+                        "usage": { "input_tokens": 12, "output_tokens": 9 },
+                    },
+                },
+            }],
+        });
+        let (_, url, shutdown) = start_server(document, Function::default()).await;
+        let client = reqwest::Client::new();
+        let stream_usage = |model: &str| {
+            let builder = client
+                .post(format!("{url}/v1/chat/completions"))
+                .json(&json!({
+                    "model": model,
+                    "messages": [{ "role": "user", "content": "Hi" }],
+                    "stream": true,
+                }));
+            async move {
+                let text = builder.send().await.unwrap().text().await.unwrap();
+                text.lines()
+                    .filter_map(|v| v.strip_prefix("data: "))
+                    .filter_map(|v| serde_json::from_str::<Value>(v).ok())
+                    .find_map(|v| v.get("usage").cloned())
+                    .unwrap()
+            }
+        };
+
+        // The usage reported at the end of the stream replaces the estimate
+        let usage = stream_usage("mock:greeter").await;
+        assert_eq!(usage["prompt_tokens"], 12);
+        assert_eq!(usage["completion_tokens"], 9);
+        assert_eq!(usage["estimated"], false);
+
+        let usage = stream_usage("mock:echo").await;
+        assert_eq!(usage["completion_tokens"], 1);
+        assert_eq!(usage["estimated"], true);
+
+        let _ = shutdown.send(());
+    }
+
+    #[tokio::test]
+    async fn test_metric_labels() {
+        let document = json!({
+            "model": "mock:echo",
+            "clients": [{ "type": "mock", "models": [{ "name": "echo" }] }],
+        });
+        let (_, url, shutdown) = start_server(document, Function::default()).await;
+        let client = reqwest::Client::new();
+        let nonce = format!("labels{}", std::process::id());
+        for (model, agent) in [
+            (format!("mock:{nonce}"), format!("{nonce}-agent")),
+            (format!("{nonce}:echo"), format!("{nonce} agent")),
+            ("mock:echo".to_string(), format!("{nonce}\"agent")),
+        ] {
+            client
+                .post(format!("{url}/v1/chat/completions"))
+                .header(AGENT_HEADER, agent)
+                .json(&json!({
+                    "model": model,
+                    "messages": [{ "role": "user", "content": "Hi" }],
+                }))
+                .send()
+                .await
+                .unwrap();
+        }
+        let metrics = client
+            .get(format!("{url}/metrics"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        // Only configured models and valid agent names become label values
+        assert!(metrics.contains(&format!(
+            r#"agent_panel_requests_total{{client="mock",model="unknown",agent="{nonce}-agent",status="success"}}"#
+        )));
+        assert!(metrics.contains(
+            r#"agent_panel_requests_total{client="unknown",model="unknown",agent="unknown",status="error"}"#
+        ));
+        assert!(metrics.contains(
+            r#"agent_panel_requests_total{client="mock",model="echo",agent="unknown",status="success"}"#
+        ));
+        assert!(metrics
+            .lines()
+            .filter(|v| v.contains(&nonce))
+            .all(|v| v.contains(&format!(r#"agent="{nonce}-agent""#))));
+        assert!(metrics.contains("agent_panel_retries_total 0"));
+        assert!(metrics.contains("agent_panel_cache_hits_total 0"));
+
+        let _ = shutdown.send(());
+    }
+
+    #[tokio::test]
+    async fn test_escalation_fallbacks() {
+        let agent = format!("escalation{}", std::process::id());
+        let document = json!({
+            "model": "mock:small",
+            "clients": [{
+                "type": "mock",
+                "models": [
+                    { "name": "small", "max_input_tokens": 20 },
+                    { "name": "large", "max_input_tokens": 10000 },
+                ],
+            }],
+            "escalations": { "chat": ["mock:small", "mock:large"] },
+        });
+        let (_, url, shutdown) = start_server(document, Function::default()).await;
+        let client = reqwest::Client::new();
+        let chat = |content: String| {
+            let builder = client
+                .post(format!("{url}/v1/chat/completions"))
+                .header(AGENT_HEADER, &agent)
+                .json(&json!({
+                    "model": "chat",
+                    "messages": [{ "role": "user", "content": content }],
+                }));
+            async move { builder.send().await.unwrap().json::<Value>().await.unwrap() }
+        };
+        let fallbacks = || async {
+            let metrics = reqwest::get(format!("{url}/metrics"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            let series = format!(
+                r#"agent_panel_fallbacks_total{{requested_model="mock:small",model="mock:large",agent="{agent}"}} "#
+            );
+            metrics
+                .lines()
+                .find_map(|v| v.strip_prefix(&series))
+                .map(|v| v.parse::<f64>().unwrap())
+        };
+
+        // A prompt fitting the first model is no fallback
+        let data = chat("Hi".into()).await;
+        assert_eq!(data["model"], "mock:small");
+        assert_eq!(fallbacks().await, None);
+
+        let data = chat("word ".repeat(100)).await;
+        assert_eq!(data["model"], "mock:large");
+        assert_eq!(fallbacks().await, Some(1.0));
+
+        let _ = shutdown.send(());
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let sessions_dir =
+            std::env::temp_dir().join(format!("agent-panel-sessions-{}", std::process::id()));
+        let document = json!({
+            "model": "mock:echo",
+            "clients": [{ "type": "mock", "models": [{ "name": "echo" }] }],
+        });
+        let (server, url, shutdown) =
+            start_server_with_sessions(document.clone(), Function::default(), sessions_dir.clone())
+                .await;
+        let client = reqwest::Client::new();
+        let send = |method: Method, path: &str, body: Option<Value>| {
+            let mut builder = client.request(method, format!("{url}{path}"));
+            if let Some(body) = body {
+                builder = builder.json(&body);
+            }
+            async move {
+                let res = builder.send().await.unwrap();
+                (res.status().as_u16(), res.json::<Value>().await.unwrap())
+            }
+        };
+
+        let (status, data) = send(Method::POST, "/v1/sessions", Some(json!({ "id": "s1" }))).await;
+        assert_eq!((status, data["id"].as_str()), (200, Some("s1")));
+        assert_eq!(data["model"], "mock:echo");
+        let (status, _) = send(Method::POST, "/v1/sessions", Some(json!({ "id": "s1" }))).await;
+        assert_eq!(status, 409);
+        let (_, data) = send(Method::GET, "/v1/sessions", None).await;
+        assert_eq!(data["data"][0]["id"], "s1");
+        assert_eq!(data["data"][0]["messages_count"], 0);
+
+        let (status, data) = send(
+            Method::POST,
+            "/v1/sessions/s1/messages",
+            Some(json!({ "content": "Hello" })),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(data["choices"][0]["message"]["content"], "Hello");
+        let (_, data) = send(
+            Method::PATCH,
+            "/v1/sessions/s1",
+            Some(json!({ "temperature": 0.5 })),
+        )
+        .await;
+        assert_eq!(data["temperature"], 0.5);
+        assert!(sessions_dir.join("s1.yaml").exists());
+        assert_eq!(server.session_locks.len(), 0);
+        let _ = shutdown.send(());
+
+        // A new server reads the session back from its file
+        let (_, url, shutdown) =
+            start_server_with_sessions(document, Function::default(), sessions_dir.clone()).await;
+        let send = |method: Method, path: &str| {
+            let builder = client.request(method, format!("{url}{path}"));
+            async move {
+                let res = builder.send().await.unwrap();
+                (res.status().as_u16(), res.json::<Value>().await.unwrap())
+            }
+        };
+        let (_, data) = send(Method::GET, "/v1/sessions/s1").await;
+        assert_eq!(data["temperature"], 0.5);
+        let messages = data["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            (&messages[0]["content"], &messages[1]["role"]),
+            (&json!("Hello"), &json!("assistant"))
+        );
+        let (_, data) = send(Method::DELETE, "/v1/sessions/s1").await;
+        assert_eq!(data["deleted"], true);
+        let (status, _) = send(Method::GET, "/v1/sessions/s1").await;
+        assert_eq!(status, 404);
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&sessions_dir);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let ops = |diff: Vec<Value>| {
+            diff.iter()
+                .map(|v| {
+                    format!(
+                        "{} {}",
+                        v["op"].as_str().unwrap(),
+                        v["text"].as_str().unwrap()
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ops(diff_lines("a\nb\nc\nd", "a\nx\nc\nd\ne")),
+            ["equal a", "insert x", "delete b", "equal c", "equal d", "insert e"]
+        );
+        assert_eq!(ops(diff_lines("", "a")), ["insert a"]);
+
+        // Past the cap, the changed lines are no longer matched
+        let old: Vec<String> = (0..1001).map(|i| i.to_string()).collect();
+        let new: Vec<String> = (0..1001).rev().map(|i| i.to_string()).collect();
+        let diff = diff_lines(&old.join("\n"), &new.join("\n"));
+        assert_eq!(diff.len(), 2002);
+        assert!(diff[..1001].iter().all(|v| v["op"] == "delete"));
+        assert!(diff[1001..].iter().all(|v| v["op"] == "insert"));
+        let diff = diff_lines(
+            &format!("x\n{}\ny", old.join("\n")),
+            &format!("x\n{}\ny", new.join("\n")),
+        );
+        assert_eq!(diff.len(), 2004);
+        assert_eq!(diff[0]["op"], "equal");
+        assert_eq!(diff[2003]["op"], "equal");
+    }
+
+    #[test]
+    fn test_recorded_call_from_span() {
+        let mut span = Span::new("chat openai:gpt-4o", None);
+        span.set_attribute("agent_panel.client", "openai");
+        span.set_attribute("gen_ai.request.model", "gpt-4o");
+        span.set_attribute("agent_panel.agent", "support");
+        span.set_attribute("gen_ai.request.temperature", 0.5);
+        span.set_attribute("gen_ai.request.max_tokens", 256);
+        span.set_attribute("gen_ai.usage.input_tokens", 12);
+        span.set_attribute("gen_ai.usage.output_tokens", 3);
+        assert!(RecordedCall::from_span(&span).is_err());
+
+        let messages = vec![Message::new(
+            MessageRole::User,
+            MessageContent::Text("Hi".into()),
+        )];
+        span.add_event(
+            "gen_ai.content.prompt",
+            [(
+                "gen_ai.prompt".to_string(),
+                json!(messages).to_string().into(),
+            )]
+            .into(),
+        );
+        span.add_event(
+            "gen_ai.content.completion",
+            [(
+                "gen_ai.completion".to_string(),
+                json!([{ "role": "assistant", "content": "Hello" }])
+                    .to_string()
+                    .into(),
+            )]
+            .into(),
+        );
+        let call = RecordedCall::from_span(&span).unwrap();
+        assert_eq!(call.model, "openai:gpt-4o");
+        assert_eq!(call.agent, "support");
+        assert_eq!(json!(call.messages), json!(messages));
+        assert!(call.functions.is_none());
+        assert_eq!((call.temperature, call.top_p), (Some(0.5), None));
+        assert_eq!(call.max_tokens, Some(256));
+        assert_eq!(call.output["content"], "Hello");
+        assert_eq!((call.input_tokens, call.output_tokens), (Some(12), Some(3)));
+        assert_eq!(call.cost, None);
+
+        let mut span = Span::new("embeddings", None);
+        span.set_attribute("gen_ai.request.model", "text-embedding-3-small");
+        assert!(RecordedCall::from_span(&span).is_err());
+    }
+}
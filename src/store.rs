@@ -0,0 +1,152 @@
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+
+/// Named values saved as bincode files in a directory, such as the knowledge bases or the
+/// vector stores, and kept in memory once loaded.
+pub struct Store<T> {
+    /// What a value is called in messages, e.g. "knowledge base"
+    noun: &'static str,
+    dir: fn() -> Result<PathBuf>,
+    cache: RwLock<HashMap<String, Arc<T>>>,
+}
+
+impl<T: Serialize + DeserializeOwned> Store<T> {
+    pub fn new(noun: &'static str, dir: fn() -> Result<PathBuf>) -> Self {
+        Self {
+            noun,
+            dir,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn validate_name(&self, name: &str) -> Result<()> {
+        validate_name(self.noun, name)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        let Ok(entries) = fs::read_dir((self.dir)()?) else {
+            return Ok(vec![]);
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "bin" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> Result<bool> {
+        Ok(self.file(name)?.exists())
+    }
+
+    /// The value, loaded from disk on first use.
+    pub fn load(&self, name: &str) -> Result<Arc<T>> {
+        if let Some(value) = self.cache.read().get(name) {
+            return Ok(value.clone());
+        }
+        let value = Arc::new(self.load_file(name)?);
+        self.cache.write().insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+
+    /// The stored value, uncached, to be changed and saved again.
+    pub fn load_file(&self, name: &str) -> Result<T> {
+        if !self.exists(name)? {
+            bail!("{} '{name}' not found", capitalize(self.noun));
+        }
+        let path = self.file(name)?;
+        let ctx = || format!("Failed to load {} at {}", self.noun, path.display());
+        let content = fs::read(&path).with_context(ctx)?;
+        bincode::deserialize(&content).with_context(ctx)
+    }
+
+    /// Write the value to disk, then serve it.
+    pub fn save(&self, name: &str, value: T) -> Result<Arc<T>> {
+        let path = self.file(name)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let content = bincode::serialize(&value)?;
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to save {} to {}", self.noun, path.display()))?;
+        let value = Arc::new(value);
+        self.cache.write().insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        if !self.exists(name)? {
+            bail!("{} '{name}' not found", capitalize(self.noun));
+        }
+        let path = self.file(name)?;
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to delete {} at {}", self.noun, path.display()))?;
+        self.cache.write().remove(name);
+        Ok(())
+    }
+
+    fn file(&self, name: &str) -> Result<PathBuf> {
+        self.validate_name(name)?;
+        Ok((self.dir)()?.join(format!("{name}.bin")))
+    }
+}
+
+/// Names end up in file paths, so they are restricted to a safe charset.
+pub fn validate_name(noun: &str, name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid {noun} name '{name}', only letters, digits, '-' and '_' are allowed");
+    }
+    Ok(())
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir() -> Result<PathBuf> {
+        Ok(std::env::temp_dir().join(format!("agent-panel-store-{}", std::process::id())))
+    }
+
+    #[test]
+    fn test_store() {
+        let store: Store<Vec<u32>> = Store::new("test value", test_dir);
+        assert!(store.list().unwrap().is_empty());
+        store.save("b", vec![1, 2]).unwrap();
+        store.save("a", vec![3]).unwrap();
+        assert_eq!(store.list().unwrap(), ["a", "b"]);
+        assert!(store.exists("a").unwrap());
+        assert_eq!(*store.load("b").unwrap(), [1, 2]);
+        assert_eq!(store.load_file("a").unwrap(), [3]);
+
+        store.delete("a").unwrap();
+        assert!(!store.exists("a").unwrap());
+        let err = store.load("a").unwrap_err();
+        assert_eq!(err.to_string(), "Test value 'a' not found");
+        let err = store.exists("../a").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid test value name '../a'"));
+        fs::remove_dir_all(test_dir().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use crate::client::{Message, MessageContent, MessageRole, Model};
+use crate::config::{SUMMARIZE_PROMPT, SUMMARY_PROMPT};
+
+use serde::Deserialize;
+use std::ops::Range;
+
+const DEFAULT_THRESHOLD: f64 = 0.75;
+const DEFAULT_KEEP_TURNS: usize = 2;
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct SummarizeConfig {
+    /// The model writing the summaries, defaults to the model of the conversation
+    pub model: Option<String>,
+    /// Share of the model's `max_input_tokens` above which older turns are summarized
+    pub threshold: Option<f64>,
+    /// Most recent user turns kept verbatim
+    pub keep_turns: Option<usize>,
+}
+
+impl SummarizeConfig {
+    /// The token count above which a conversation with the model gets summarized, 0 for
+    /// models without a known context size.
+    pub fn threshold(&self, model: &Model) -> usize {
+        let ratio = self.threshold.unwrap_or(DEFAULT_THRESHOLD);
+        model
+            .max_input_tokens()
+            .map(|v| (v as f64 * ratio) as usize)
+            .unwrap_or_default()
+    }
+
+    pub fn keep_turns(&self) -> usize {
+        self.keep_turns.unwrap_or(DEFAULT_KEEP_TURNS).max(1)
+    }
+}
+
+/// The messages to summarize: the ones between the leading system prompts and the most
+/// recent turns, `None` when there is nothing old enough.
+pub fn summary_range(messages: &[Message], keep_turns: usize) -> Option<Range<usize>> {
+    let start = messages
+        .iter()
+        .take_while(|v| v.role.is_system() && !is_summary(v))
+        .count();
+    let end = messages
+        .iter()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, v)| v.role.is_user() && !matches!(v.content, MessageContent::ToolResults(_)))
+        .map(|(i, _)| i)
+        .rev()
+        .nth(keep_turns.max(1) - 1)?;
+    (end > start).then_some(start..end)
+}
+
+/// The request asking a model to summarize the messages.
+pub fn summary_request(messages: &[Message]) -> Vec<Message> {
+    let transcript = messages
+        .iter()
+        .map(|message| {
+            let role = match message.role {
+                MessageRole::System => "system",
+                MessageRole::Assistant => "assistant",
+                MessageRole::User => "user",
+            };
+            let content = match &message.content {
+                MessageContent::ToolResults(_) => {
+                    serde_json::to_string(&message.content).unwrap_or_default()
+                }
+                content => content.to_text(),
+            };
+            format!("{role}: {content}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    vec![Message::new(
+        MessageRole::User,
+        MessageContent::Text(format!("{transcript}\n\n{SUMMARIZE_PROMPT}")),
+    )]
+}
+
+pub fn summary_message(summary: &str) -> Message {
+    Message::new(
+        MessageRole::System,
+        MessageContent::Text(format!("{SUMMARY_PROMPT}{summary}")),
+    )
+}
+
+fn is_summary(message: &Message) -> bool {
+    matches!(&message.content, MessageContent::Text(text) if text.starts_with(SUMMARY_PROMPT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: MessageRole, text: &str) -> Message {
+        Message::new(role, MessageContent::Text(text.to_string()))
+    }
+
+    #[test]
+    fn test_summary_range() {
+        let mut messages = vec![message(MessageRole::System, "Be brief")];
+        for i in 0..3 {
+            messages.push(message(MessageRole::User, &format!("question {i}")));
+            messages.push(message(MessageRole::Assistant, &format!("answer {i}")));
+        }
+        assert_eq!(summary_range(&messages, 2), Some(1..3));
+        assert_eq!(summary_range(&messages, 1), Some(1..5));
+        assert_eq!(summary_range(&messages, 3), None);
+
+        // Earlier summaries are summarized again instead of piling up.
+        messages.splice(1..3, [summary_message("questions about 0")]);
+        assert_eq!(summary_range(&messages, 1), Some(1..4));
+    }
+}
@@ -0,0 +1,520 @@
+use anyhow::Result;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+const DEFAULT_SERVICE_NAME: &str = "agent-panel";
+const TRACES_PATH: &str = "/v1/traces";
+const MAX_BATCH_SIZE: usize = 64;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_STORED_SPANS: usize = 1000;
+const CONTENT_EVENT_PREFIX: &str = "gen_ai.content.";
+
+// OTLP span kind and status codes
+const SPAN_KIND_CLIENT: u8 = 3;
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+lazy_static! {
+    static ref EXPORTER: RwLock<Option<Exporter>> = RwLock::new(None);
+    static ref STORE: RwLock<SpanStore> =
+        RwLock::new(SpanStore::new(DEFAULT_MAX_STORED_SPANS, false));
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// The OTLP/HTTP collector, e.g. http://localhost:4318
+    pub otlp_endpoint: Option<String>,
+    pub otlp_headers: IndexMap<String, String>,
+    pub service_name: Option<String>,
+    /// Attach prompts and completions to the exported spans
+    pub capture_content: bool,
+    /// Keep prompts and completions in the in-memory store, for the dashboard and replays
+    pub store_content: bool,
+    /// The number of recent spans kept in memory for the trace query API
+    pub max_stored_spans: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Exporter {
+    sender: UnboundedSender<Span>,
+    capture_content: bool,
+}
+
+/// Start the background task exporting spans to the configured collector.
+pub fn init(config: &TelemetryConfig) -> Result<()> {
+    *STORE.write() = SpanStore::new(
+        config.max_stored_spans.unwrap_or(DEFAULT_MAX_STORED_SPANS),
+        config.store_content,
+    );
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) if !endpoint.is_empty() => endpoint.trim_end_matches('/'),
+        _ => return Ok(()),
+    };
+    let url = if endpoint.ends_with(TRACES_PATH) {
+        endpoint.to_string()
+    } else {
+        format!("{endpoint}{TRACES_PATH}")
+    };
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SERVICE_NAME.into());
+    let (sender, receiver) = unbounded_channel();
+    *EXPORTER.write() = Some(Exporter {
+        sender,
+        capture_content: config.capture_content,
+    });
+    tokio::spawn(run_exporter(
+        url.clone(),
+        config.otlp_headers.clone(),
+        service_name,
+        receiver,
+    ));
+    info!("Exporting OTLP spans to {url}");
+    Ok(())
+}
+
+/// Store a finished span and queue it for export.
+///
+/// Prompts and completions are only exported when `capture_content` is enabled, and only
+/// stored when `store_content` is.
+pub fn export(span: Span) {
+    if let Some(exporter) = EXPORTER.read().as_ref() {
+        let mut span = span.clone();
+        if !exporter.capture_content {
+            span.strip_content();
+        }
+        let _ = exporter.sender.send(span);
+    }
+    STORE.write().push(span);
+}
+
+pub fn find_span(span_id: &str) -> Option<Span> {
+    STORE
+        .read()
+        .spans
+        .iter()
+        .find(|span| span.span_id == span_id)
+        .cloned()
+}
+
+/// The most recent stored spans, newest first.
+pub fn recent_spans(limit: usize) -> Vec<Span> {
+    STORE
+        .read()
+        .spans
+        .iter()
+        .rev()
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+/// All stored spans of a trace, in start order.
+pub fn trace_spans(trace_id: &str) -> Vec<Span> {
+    let mut spans: Vec<Span> = STORE
+        .read()
+        .spans
+        .iter()
+        .filter(|span| span.trace_id == trace_id)
+        .cloned()
+        .collect();
+    spans.sort_by_key(|span| span.start_time);
+    spans
+}
+
+#[derive(Debug)]
+struct SpanStore {
+    spans: VecDeque<Span>,
+    capacity: usize,
+    store_content: bool,
+}
+
+impl SpanStore {
+    fn new(capacity: usize, store_content: bool) -> Self {
+        Self {
+            spans: VecDeque::new(),
+            capacity,
+            store_content,
+        }
+    }
+
+    fn push(&mut self, mut span: Span) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.store_content {
+            span.strip_content();
+        }
+        while self.spans.len() >= self.capacity {
+            self.spans.pop_front();
+        }
+        self.spans.push_back(span);
+    }
+}
+
+/// The remote parent of a span, propagated with the W3C `traceparent` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+pub fn parse_traceparent(value: &str) -> Option<SpanContext> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, span_id, flags] = parts.as_slice() else {
+        return None;
+    };
+    let is_hex = |v: &str, len: usize| {
+        v.len() == len && v.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    };
+    let is_zero = |v: &str| v.chars().all(|c| c == '0');
+    if !is_hex(version, 2)
+        || *version == "ff"
+        || !is_hex(trace_id, 32)
+        || is_zero(trace_id)
+        || !is_hex(span_id, 16)
+        || is_zero(span_id)
+        || !is_hex(flags, 2)
+    {
+        return None;
+    }
+    Some(SpanContext {
+        trace_id: trace_id.to_string(),
+        span_id: span_id.to_string(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub start_time: u128,
+    pub end_time: Option<u128>,
+    pub attributes: IndexMap<String, Value>,
+    pub events: Vec<SpanEvent>,
+    pub links: Vec<SpanContext>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpanEvent {
+    pub name: String,
+    pub time: u128,
+    pub attributes: IndexMap<String, Value>,
+}
+
+impl Span {
+    pub fn new(name: &str, parent: Option<&SpanContext>) -> Self {
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) => (parent.trace_id.clone(), Some(parent.span_id.clone())),
+            None => (format!("{:032x}", random_id::<u128>()), None),
+        };
+        Self {
+            trace_id,
+            span_id: format!("{:016x}", random_id::<u64>()),
+            parent_span_id,
+            name: name.to_string(),
+            start_time: now_nanos(),
+            end_time: None,
+            attributes: IndexMap::new(),
+            events: vec![],
+            links: vec![],
+            error: None,
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+
+    /// The context for the children of this span.
+    pub fn context(&self) -> SpanContext {
+        SpanContext {
+            trace_id: self.trace_id.clone(),
+            span_id: self.span_id.clone(),
+        }
+    }
+
+    pub fn set_attribute<T: Into<Value>>(&mut self, key: &str, value: T) {
+        let value = value.into();
+        if !value.is_null() {
+            self.attributes.insert(key.to_string(), value);
+        }
+    }
+
+    pub fn add_event(&mut self, name: &str, attributes: IndexMap<String, Value>) {
+        self.events.push(SpanEvent {
+            name: name.to_string(),
+            time: now_nanos(),
+            attributes,
+        })
+    }
+
+    pub fn add_link(&mut self, context: SpanContext) {
+        self.links.push(context);
+    }
+
+    /// Drop the events holding prompts and completions.
+    fn strip_content(&mut self) {
+        self.events
+            .retain(|event| !event.name.starts_with(CONTENT_EVENT_PREFIX));
+    }
+
+    pub fn end(&mut self, error: Option<String>) {
+        self.end_time = Some(now_nanos());
+        self.error = error;
+    }
+
+    pub fn duration_ms(&self) -> f64 {
+        let end_time = self.end_time.unwrap_or_else(now_nanos);
+        end_time.saturating_sub(self.start_time) as f64 / 1_000_000.0
+    }
+
+    /// The representation used by the trace query API.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "trace_id": self.trace_id,
+            "span_id": self.span_id,
+            "parent_span_id": self.parent_span_id,
+            "name": self.name,
+            "start_time": (self.start_time / 1_000_000) as u64,
+            "duration_ms": self.duration_ms(),
+            "attributes": self.attributes,
+            "events": self.events.iter().map(|event| json!({
+                "name": event.name,
+                "time": (event.time / 1_000_000) as u64,
+                "attributes": event.attributes,
+            })).collect::<Vec<_>>(),
+            "links": self.links.iter().map(|link| json!({
+                "trace_id": link.trace_id,
+                "span_id": link.span_id,
+            })).collect::<Vec<_>>(),
+            "error": self.error,
+        })
+    }
+
+    fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": SPAN_KIND_CLIENT,
+            "startTimeUnixNano": self.start_time.to_string(),
+            "endTimeUnixNano": self.end_time.unwrap_or_else(now_nanos).to_string(),
+            "attributes": to_otlp_attributes(&self.attributes),
+            "events": self.events.iter().map(|event| json!({
+                "name": event.name,
+                "timeUnixNano": event.time.to_string(),
+                "attributes": to_otlp_attributes(&event.attributes),
+            })).collect::<Vec<_>>(),
+            "links": self.links.iter().map(|link| json!({
+                "traceId": link.trace_id,
+                "spanId": link.span_id,
+            })).collect::<Vec<_>>(),
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = parent_span_id.clone().into();
+        }
+        span["status"] = match &self.error {
+            Some(message) => json!({ "code": STATUS_CODE_ERROR, "message": message }),
+            None => json!({ "code": STATUS_CODE_OK }),
+        };
+        span
+    }
+}
+
+/// Map a client type to the `gen_ai.system` value of the GenAI semantic conventions.
+pub fn gen_ai_system(client_type: &str, client_name: &str) -> String {
+    match client_type {
+        "claude" => "anthropic",
+        "azure-openai" => "az.ai.openai",
+        "bedrock" => "aws.bedrock",
+        "vertexai" | "vertexai-claude" => "vertex_ai",
+        "openai-compatible" => client_name,
+        _ => client_type,
+    }
+    .to_string()
+}
+
+async fn run_exporter(
+    url: String,
+    headers: IndexMap<String, String>,
+    service_name: String,
+    mut receiver: UnboundedReceiver<Span>,
+) {
+    let client = reqwest::Client::new();
+    let mut batch = vec![];
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            span = receiver.recv() => {
+                match span {
+                    Some(span) => {
+                        batch.push(span);
+                        if batch.len() >= MAX_BATCH_SIZE {
+                            flush(&client, &url, &headers, &service_name, &mut batch).await;
+                        }
+                    }
+                    None => {
+                        flush(&client, &url, &headers, &service_name, &mut batch).await;
+                        break;
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                flush(&client, &url, &headers, &service_name, &mut batch).await;
+            }
+        }
+    }
+}
+
+async fn flush(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &IndexMap<String, String>,
+    service_name: &str,
+    batch: &mut Vec<Span>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let spans: Vec<Value> = batch.drain(..).map(|span| span.to_otlp()).collect();
+    let body = json!({
+        "resourceSpans": [
+            {
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": service_name } },
+                    ],
+                },
+                "scopeSpans": [
+                    {
+                        "scope": {
+                            "name": env!("CARGO_CRATE_NAME"),
+                            "version": env!("CARGO_PKG_VERSION"),
+                        },
+                        "spans": spans,
+                    },
+                ],
+            },
+        ],
+    });
+    let mut builder = client.post(url).json(&body);
+    for (key, value) in headers {
+        builder = builder.header(key, value);
+    }
+    match builder.send().await {
+        Ok(res) if !res.status().is_success() => {
+            warn!("Failed to export spans to {url}, status: {}", res.status())
+        }
+        Ok(_) => {}
+        Err(err) => warn!("Failed to export spans to {url}, {err}"),
+    }
+}
+
+fn to_otlp_attributes(attributes: &IndexMap<String, Value>) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Bool(v) => json!({ "boolValue": v }),
+                Value::Number(v) if v.is_f64() => json!({ "doubleValue": v }),
+                Value::Number(v) => json!({ "intValue": v.to_string() }),
+                Value::String(v) => json!({ "stringValue": v }),
+                _ => json!({ "stringValue": value.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn random_id<T>() -> T
+where
+    T: Default + PartialEq,
+    rand::distributions::Standard: rand::distributions::Distribution<T>,
+{
+    loop {
+        let id = rand::random::<T>();
+        if id != T::default() {
+            return id;
+        }
+    }
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_nanos())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let context =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id, "00f067aa0ba902b7");
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none()
+        );
+    }
+
+    #[test]
+    fn test_span_store() {
+        let mut store = SpanStore::new(2, false);
+        for name in ["a", "b", "c"] {
+            let mut span = Span::new(name, None);
+            span.add_event(
+                "gen_ai.content.prompt",
+                [("gen_ai.prompt".to_string(), "secret".into())].into(),
+            );
+            store.push(span);
+        }
+        let names: Vec<&str> = store.spans.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["b", "c"]);
+        assert!(store.spans.iter().all(|v| v.events.is_empty()));
+
+        let mut store = SpanStore::new(2, true);
+        let mut span = Span::new("a", None);
+        span.add_event("gen_ai.content.prompt", IndexMap::new());
+        store.push(span);
+        assert_eq!(store.spans[0].events.len(), 1);
+    }
+
+    #[test]
+    fn test_span_parent() {
+        let parent = SpanContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+        };
+        let span = Span::new("chat", Some(&parent));
+        assert_eq!(span.trace_id, parent.trace_id);
+        assert_eq!(span.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(span.span_id.len(), 16);
+        let otlp = span.to_otlp();
+        assert_eq!(otlp["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(otlp["kind"], SPAN_KIND_CLIENT);
+    }
+}
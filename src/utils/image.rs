@@ -0,0 +1,72 @@
+use super::base64_decode;
+
+/// The width and height of a PNG, JPEG, GIF or WebP image read from its header.
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+    let be32 = |i: usize| Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?));
+    let le24 = |i: usize| {
+        let v = data.get(i..i + 3)?;
+        Some(v[0] as u32 | (v[1] as u32) << 8 | (v[2] as u32) << 16)
+    };
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some((le16(6)?, le16(8)?));
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return match data.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        };
+    }
+    if data.starts_with(b"\xff\xd8") {
+        // Walk the segments up to the start of frame, which holds the dimensions
+        let mut i = 2;
+        while *data.get(i)? == 0xff {
+            let marker = *data.get(i + 1)?;
+            if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    None
+}
+
+/// The dimensions of an image given as a base64 data URL, `None` for remote URLs.
+pub fn data_url_image_dimensions(url: &str) -> Option<(u32, u32)> {
+    let (_, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    image_dimensions(&base64_decode(data).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend(640u32.to_be_bytes());
+        png.extend(480u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((640, 480)));
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend(20u16.to_le_bytes());
+        gif.extend(10u16.to_le_bytes());
+        assert_eq!(image_dimensions(&gif), Some((20, 10)));
+
+        // SOI, an APP0 segment of 4 bytes, then a baseline start of frame
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xc0\x00\x11\x08\x02\x00\x03\x00";
+        assert_eq!(image_dimensions(jpeg), Some((768, 512)));
+
+        assert_eq!(image_dimensions(b"not an image"), None);
+        assert_eq!(data_url_image_dimensions("https://example.com/a.png"), None);
+    }
+}
@@ -1,11 +1,13 @@
 mod abort_signal;
 mod clipboard;
 mod crypto;
+mod image;
 mod prompt_input;
 
 pub use self::abort_signal::*;
 pub use self::clipboard::set_text;
 pub use self::crypto::*;
+pub use self::image::*;
 pub use self::prompt_input::*;
 
 use fancy_regex::Regex;
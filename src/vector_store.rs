@@ -0,0 +1,395 @@
+use crate::client::{Client, EmbeddingsData};
+use crate::config::Config;
+use crate::rag::{cosine_similarity, VectorIndex};
+use crate::store::Store;
+use crate::utils::{hex_encode, now};
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    cmp::Ordering,
+    sync::{Arc, OnceLock},
+};
+
+const MAX_ID_LEN: usize = 256;
+/// Filtered queries matching at most this many documents are answered exactly, the HNSW
+/// graph being unreliable when most of its points are filtered out.
+const EXACT_SEARCH_LIMIT: usize = 1000;
+
+lazy_static! {
+    static ref STORES: Store<VectorStore> = Store::new("vector store", Config::vector_stores_dir);
+}
+
+/// A collection of documents with metadata, searched by the similarity of their vectors.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VectorStore {
+    pub name: String,
+    pub embedding_model: String,
+    /// The length of the vectors, set by the first document
+    pub dimensions: Option<usize>,
+    pub created_at: String,
+    pub documents: IndexMap<String, VectorDocument>,
+    /// Built on the first query, `None` when there is no document
+    #[serde(skip)]
+    index: OnceLock<Option<VectorIndex>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorDocument {
+    pub text: Option<String>,
+    #[serde(with = "json_string")]
+    pub metadata: Map<String, Value>,
+    pub vector: Vec<f32>,
+    pub updated_at: String,
+}
+
+/// A document to upsert, embedded from its text unless its vector is given.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VectorDocumentInput {
+    pub id: Option<String>,
+    pub text: Option<String>,
+    pub vector: Option<Vec<f32>>,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+}
+
+impl VectorStore {
+    pub fn new(name: &str, embedding_model: &str) -> Result<Self> {
+        validate_name(name)?;
+        Ok(Self {
+            name: name.to_string(),
+            embedding_model: embedding_model.to_string(),
+            dimensions: None,
+            created_at: now(),
+            documents: Default::default(),
+            index: OnceLock::new(),
+        })
+    }
+
+    pub fn list() -> Result<Vec<String>> {
+        STORES.list()
+    }
+
+    pub fn exists(name: &str) -> Result<bool> {
+        STORES.exists(name)
+    }
+
+    /// The vector store, loaded from disk on first use.
+    pub fn load(name: &str) -> Result<Arc<Self>> {
+        STORES.load(name)
+    }
+
+    /// The stored vector store, uncached, to be changed and saved again.
+    pub fn load_file(name: &str) -> Result<Self> {
+        STORES.load_file(name)
+    }
+
+    /// Write the vector store to disk, then serve it, its index being built on the next
+    /// query so that successive upserts only pay for a single build.
+    pub fn save(self) -> Result<Arc<Self>> {
+        STORES.save(&self.name.clone(), self)
+    }
+
+    pub fn delete(name: &str) -> Result<()> {
+        STORES.delete(name)
+    }
+
+    /// Insert the documents or replace the ones with the same ids, embedding the texts of
+    /// those without a vector. Returns the ids of the documents.
+    pub async fn upsert(
+        &mut self,
+        documents: Vec<VectorDocumentInput>,
+        client: &dyn Client,
+    ) -> Result<Vec<String>> {
+        let mut documents: Vec<(String, VectorDocumentInput)> = documents
+            .into_iter()
+            .map(|document| {
+                let id = match &document.id {
+                    Some(id) => id.clone(),
+                    None => format!("doc_{}", hex_encode(&rand::random::<[u8; 8]>())),
+                };
+                if id.is_empty() || id.len() > MAX_ID_LEN {
+                    bail!("Invalid document id '{id}'");
+                }
+                if document.text.is_none() && document.vector.is_none() {
+                    bail!("Document '{id}' needs a 'text' or a 'vector'");
+                }
+                Ok((id, document))
+            })
+            .collect::<Result<_>>()?;
+
+        let pending: Vec<usize> = (0..documents.len())
+            .filter(|i| documents[*i].1.vector.is_none())
+            .collect();
+        let batch_size = client.model().max_concurrent_chunks().max(1);
+        for batch in pending.chunks(batch_size) {
+            let texts = batch
+                .iter()
+                .map(|i| documents[*i].1.text.clone().unwrap_or_default())
+                .collect::<Vec<_>>();
+            let data = EmbeddingsData::new(texts, false);
+            let embeddings = client.embeddings(data).await?;
+            if embeddings.len() != batch.len() {
+                bail!("Invalid embeddings response");
+            }
+            for (i, embedding) in batch.iter().zip(embeddings) {
+                documents[*i].1.vector = Some(embedding);
+            }
+        }
+
+        let mut dimensions = self.dimensions;
+        for (id, document) in &documents {
+            let len = document
+                .vector
+                .as_ref()
+                .map(|v| v.len())
+                .unwrap_or_default();
+            match dimensions {
+                _ if len == 0 => bail!("Document '{id}' has an empty vector"),
+                Some(dimensions) if dimensions != len => bail!(
+                    "Document '{id}' has a vector of {len} dimensions instead of {dimensions}"
+                ),
+                _ => dimensions = Some(len),
+            }
+        }
+        self.dimensions = dimensions;
+
+        let mut ids = vec![];
+        for (id, document) in documents {
+            self.documents.insert(
+                id.clone(),
+                VectorDocument {
+                    text: document.text,
+                    metadata: document.metadata,
+                    vector: document.vector.unwrap_or_default(),
+                    updated_at: now(),
+                },
+            );
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+
+    /// Remove the documents with the ids or matching the filter, returning their count.
+    pub fn remove(&mut self, ids: &[String], filter: Option<&Value>) -> Result<usize> {
+        if let Some(filter) = filter {
+            validate_filter(filter)?;
+        }
+        let count = self.documents.len();
+        self.documents.retain(|id, document| {
+            let matched = ids.contains(id)
+                || filter.is_some_and(|filter| matches_filter(filter, &document.metadata));
+            !matched
+        });
+        Ok(count - self.documents.len())
+    }
+
+    /// The ids of the documents closest to the vector that match the filter, with their
+    /// cosine similarity, most similar first.
+    pub fn query(
+        &self,
+        vector: &[f32],
+        top_k: usize,
+        filter: Option<&Value>,
+    ) -> Result<Vec<(String, f32)>> {
+        if let Some(dimensions) = self.dimensions {
+            if vector.len() != dimensions {
+                bail!(
+                    "The query vector has {} dimensions instead of {dimensions}",
+                    vector.len()
+                );
+            }
+        }
+        let Some(index) = self.index.get_or_init(|| self.build_index()) else {
+            return Ok(vec![]);
+        };
+        let results = match filter {
+            None => index.search(vector, top_k, None),
+            Some(filter) => {
+                validate_filter(filter)?;
+                let matched: Vec<bool> = self
+                    .documents
+                    .values()
+                    .map(|v| matches_filter(filter, &v.metadata))
+                    .collect();
+                if matched.iter().filter(|v| **v).count() <= EXACT_SEARCH_LIMIT {
+                    let mut results: Vec<(usize, f32)> = self
+                        .documents
+                        .values()
+                        .enumerate()
+                        .filter(|(i, _)| matched[*i])
+                        .map(|(i, v)| (i, cosine_similarity(vector, &v.vector) as f32))
+                        .collect();
+                    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+                    results.truncate(top_k);
+                    results
+                } else {
+                    index.search(vector, top_k, Some(&|id| matched[id]))
+                }
+            }
+        };
+        Ok(results
+            .into_iter()
+            .filter_map(|(i, score)| {
+                let (id, _) = self.documents.get_index(i)?;
+                Some((id.clone(), score))
+            })
+            .collect())
+    }
+
+    fn build_index(&self) -> Option<VectorIndex> {
+        let vectors: Vec<&Vec<f32>> = self.documents.values().map(|v| &v.vector).collect();
+        VectorIndex::build(&vectors)
+    }
+}
+
+/// Vector store names end up in file paths, so they are restricted to a safe charset.
+pub fn validate_name(name: &str) -> Result<()> {
+    STORES.validate_name(name)
+}
+
+/// Check a metadata filter: an object mapping fields to a value they must equal or to
+/// operators, e.g. `{"lang": "rust", "stars": {"$gte": 10}}`, with `$and` and `$or` taking
+/// lists of filters.
+pub fn validate_filter(filter: &Value) -> Result<()> {
+    let Some(filter) = filter.as_object() else {
+        bail!("Invalid filter {filter}, expected an object");
+    };
+    for (key, value) in filter {
+        match key.as_str() {
+            "$and" | "$or" => match value.as_array() {
+                Some(filters) => filters.iter().try_for_each(validate_filter)?,
+                None => bail!("Invalid filter, '{key}' takes a list of filters"),
+            },
+            key if key.starts_with('$') => bail!("Invalid filter, unknown operator '{key}'"),
+            _ => {
+                let Some(operators) = value.as_object() else {
+                    continue;
+                };
+                for (operator, operand) in operators {
+                    match operator.as_str() {
+                        "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" => {}
+                        "$in" | "$nin" if operand.is_array() => {}
+                        "$exists" if operand.is_boolean() => {}
+                        _ => bail!("Invalid filter, unsupported condition '{operator}: {operand}' on '{key}'"),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn matches_filter(filter: &Value, metadata: &Map<String, Value>) -> bool {
+    let Some(filter) = filter.as_object() else {
+        return false;
+    };
+    filter.iter().all(|(key, value)| match key.as_str() {
+        "$and" => value
+            .as_array()
+            .is_some_and(|v| v.iter().all(|filter| matches_filter(filter, metadata))),
+        "$or" => value
+            .as_array()
+            .is_some_and(|v| v.iter().any(|filter| matches_filter(filter, metadata))),
+        _ => {
+            let field = metadata.get(key);
+            match value.as_object() {
+                Some(operators) => operators
+                    .iter()
+                    .all(|(operator, operand)| matches_condition(field, operator, operand)),
+                None => field.is_some_and(|field| values_equal(field, value)),
+            }
+        }
+    })
+}
+
+fn matches_condition(field: Option<&Value>, operator: &str, operand: &Value) -> bool {
+    let list = || operand.as_array().into_iter().flatten();
+    match (operator, field) {
+        ("$exists", _) => operand.as_bool() == Some(field.is_some()),
+        ("$ne", field) => !field.is_some_and(|field| values_equal(field, operand)),
+        ("$nin", field) => !field.is_some_and(|field| list().any(|v| values_equal(field, v))),
+        (_, None) => false,
+        ("$eq", Some(field)) => values_equal(field, operand),
+        ("$in", Some(field)) => list().any(|v| values_equal(field, v)),
+        ("$gt", Some(field)) => compare(field, operand) == Some(Ordering::Greater),
+        ("$gte", Some(field)) => matches!(
+            compare(field, operand),
+            Some(Ordering::Greater | Ordering::Equal)
+        ),
+        ("$lt", Some(field)) => compare(field, operand) == Some(Ordering::Less),
+        ("$lte", Some(field)) => matches!(
+            compare(field, operand),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        _ => false,
+    }
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Stores the metadata as a JSON string, since bincode cannot deserialize arbitrary JSON.
+mod json_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use serde_json::{Map, Value};
+
+    pub fn serialize<S: Serializer>(
+        value: &Map<String, Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&serde_json::to_string(value).map_err(serde::ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Map<String, Value>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        serde_json::from_str(&value).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_filter() {
+        let metadata = json!({ "lang": "rust", "stars": 42, "tags": "cli" });
+        let metadata = metadata.as_object().unwrap();
+        let check = |filter: Value| {
+            validate_filter(&filter).unwrap();
+            matches_filter(&filter, metadata)
+        };
+        assert!(check(json!({ "lang": "rust", "stars": 42.0 })));
+        assert!(check(json!({ "stars": { "$gte": 10, "$lt": 100 } })));
+        assert!(check(json!({ "lang": { "$in": ["go", "rust"] } })));
+        assert!(check(
+            json!({ "$or": [{ "lang": "go" }, { "tags": "cli" }] })
+        ));
+        assert!(check(
+            json!({ "license": { "$exists": false }, "lang": { "$ne": "go" } })
+        ));
+        assert!(!check(json!({ "stars": { "$gt": "10" } })));
+        assert!(!check(json!({ "license": "mit" })));
+        assert!(validate_filter(&json!({ "stars": { "$regex": "4" } })).is_err());
+        assert!(validate_filter(&json!({ "$or": { "lang": "go" } })).is_err());
+    }
+}